@@ -34,23 +34,11 @@ abstract contract BasePeriodic is IPeriodic {
         require(msg.sender == address(periodicDispatcher()), "Wrong caller");
         if ((self_cycles % self_cyclesPerRetarget) == 0) {
             // It's a retarget
-            uint adjustmentFactor;
-            {
-                uint realTime = block.timestamp - self_lastRetargetTime;
-                uint expectedTime = self_targetSecondsPerCycle * self_cyclesPerRetarget;
-                // Multiply by SCALE for precision
-                adjustmentFactor = (realTime * SCALE) / expectedTime;
-            }
-
-            if (adjustmentFactor > SCALE*2) {
-                adjustmentFactor = SCALE*2; // Max 2x increase
-            } else if (adjustmentFactor < SCALE/2) {
-                adjustmentFactor = SCALE/2; // Min 0.5x decrease
-            }
-            
-            // If realTime < expectedTime, increase difficulty (reduce pay)
-            // If realTime > expectedTime, decrease difficulty (increase pay)
-            self_currentPayPerPeriod = self_currentPayPerPeriod * adjustmentFactor / SCALE;
+            self_currentPayPerPeriod = retarget(
+                self_currentPayPerPeriod,
+                block.timestamp - self_lastRetargetTime,
+                self_targetSecondsPerCycle * self_cyclesPerRetarget
+            );
 
             // Update last retarget time for next calculation
             self_lastRetargetTime = uint64(block.timestamp);
@@ -59,18 +47,112 @@ abstract contract BasePeriodic is IPeriodic {
         self_lastCycleTime = uint64(block.timestamp);
     }
 
+    /// Tuning of the retargetting algorithm, override this to change it. All factors are
+    /// scaled by 1e18. The defaults are the original algorithm: the pay is scaled by
+    /// realTime / expectedTime, clamped to between 0.5x and 2x.
+    /// @return minFactor Smallest adjustment in one retarget
+    /// @return maxFactor Largest adjustment in one retarget
+    /// @return smoothing How much of the adjustment to apply, 1e18 applies all of it. With
+    ///                   cyclesPerRetarget = 1 and a smaller value, this is an exponential
+    ///                   moving average over the cycles.
+    /// @return minPay Floor on currentPayPerPeriod()
+    /// @return maxPay Ceiling on currentPayPerPeriod()
+    function retargetParams() public view virtual returns (
+        uint minFactor,
+        uint maxFactor,
+        uint smoothing,
+        uint minPay,
+        uint maxPay
+    ) {
+        return (SCALE/2, SCALE*2, SCALE, 0, type(uint).max);
+    }
+
+    /// Compute the new pay per period. Pollinators predict payouts using retargetParams(), so
+    /// if you override this with a different algorithm, they will not be able to.
+    /// @param pay The current pay per period
+    /// @param realTime Seconds since the last retarget
+    /// @param expectedTime Seconds the cycles since the last retarget should have taken
+    function retarget(uint pay, uint realTime, uint expectedTime) public view virtual returns (uint) {
+        (uint minFactor, uint maxFactor, uint smoothing, uint minPay, uint maxPay) = retargetParams();
+
+        // If realTime < expectedTime, increase difficulty (reduce pay)
+        // If realTime > expectedTime, decrease difficulty (increase pay)
+        uint adjustmentFactor = (realTime * SCALE) / expectedTime;
+        if (adjustmentFactor > maxFactor) {
+            adjustmentFactor = maxFactor;
+        } else if (adjustmentFactor < minFactor) {
+            adjustmentFactor = minFactor;
+        }
+        uint target = pay * adjustmentFactor / SCALE;
+        if (smoothing < SCALE) {
+            target = (pay * (SCALE - smoothing) + target * smoothing) / SCALE;
+        }
+
+        if (target > maxPay) {
+            target = maxPay;
+        } else if (target < minPay) {
+            target = minPay;
+        }
+        return target;
+    }
+
     function nectarAvailable() public view returns (uint) {
         uint secs = block.timestamp - self_lastCycleTime;
         uint payPerSecond = self_currentPayPerPeriod / self_targetSecondsPerCycle;
         return secs * payPerSecond;
     }
 
+    /// The amount of nectar paid for a cycle which takes exactly the target time.
+    /// Nectar accrues at currentPayPerPeriod() / targetSecondsPerCycle() per second since
+    /// lastCycleTime(), this only changes on a retarget.
+    function currentPayPerPeriod() public view returns (uint) {
+        return self_currentPayPerPeriod;
+    }
+
+    function lastCycleTime() public view returns (uint64) {
+        return self_lastCycleTime;
+    }
+
+    function lastRetargetTime() public view returns (uint64) {
+        return self_lastRetargetTime;
+    }
+
+    /// Number of cycles so far, starting at 1. A retarget happens in postPeriodic() when this
+    /// is a multiple of cyclesPerRetarget().
+    function cycles() public view returns (uint64) {
+        return self_cycles;
+    }
+
+    function cyclesPerRetarget() public view returns (uint64) {
+        return self_cyclesPerRetarget;
+    }
+
+    function targetSecondsPerCycle() public view returns (uint64) {
+        return self_targetSecondsPerCycle;
+    }
+
+    /// The ERC20 token which nectar is paid in, address(0) for the base coin. Override this
+    /// to pay in a token. The dispatcher reads it once, when the contract is first funded or
+    /// cycled, and keeps using that token whatever this returns later. Nectar in a
+    /// token is funded with PeriodicDispatcher.addTokenNectar(), and periodic() may raise it
+    /// by transferring the token to the dispatcher. Pollinators only cycle contracts whose
+    /// token they know how to value.
+    function nectarToken() public view virtual returns (address) {
+        return address(0);
+    }
+
     function nectarShortfall() public view returns (uint) {
         uint na = nectarAvailable();
         uint bal = IPeriodicDispatcher(periodicDispatcher()).balanceOf(address(this));
         return (bal < na) ? (na - bal) : 0;
     }
 
+    /// Withdraw unused nectar from the dispatcher. Contracts which want to allow this should
+    /// call it from a function which checks that the caller is authorized, e.g. the owner.
+    function withdrawNectar(address payable to, uint amount) internal {
+        IPeriodicDispatcher(periodicDispatcher()).withdrawNectar(to, amount);
+    }
+
     function periodicDispatcher() virtual public view returns (address payable);
 
     function periodic() virtual external;
@@ -7,7 +7,9 @@ import "./generate.sol";
 library PayAfter {
     function msgSender() internal view returns (address) {
         if (msg.sender == Generate.PAYAFTER_DISPATCHER) {
-            return IPayAfterDispatcher(Generate.PAYAFTER_DISPATCHER).getSigner();
+            // No signer when the dispatcher is acting for itself, such as taking a fee in a token
+            address signer = IPayAfterDispatcher(Generate.PAYAFTER_DISPATCHER).getSigner();
+            return (signer == address(0)) ? msg.sender : signer;
         } else {
             return address(msg.sender);
         }
@@ -3,8 +3,10 @@ pragma solidity ^0.8.28;
 
 import "@openzeppelin/contracts/utils/cryptography/ECDSA.sol";
 import "@openzeppelin/contracts/utils/cryptography/MessageHashUtils.sol";
+import "@openzeppelin/contracts/utils/cryptography/SignatureChecker.sol";
 
 import "./interface/IPayAfterDispatcher.sol";
+import "./PayAfterFeeCollector.sol";
 
 contract PayAfterDispatcher is IPayAfterDispatcher {
     // PayAfter Data Format (each cell is 1 byte):
@@ -28,9 +30,61 @@ contract PayAfterDispatcher is IPayAfterDispatcher {
     //    +              Payload (variable)               +
     // XX |                                               |
     //    +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
+    //
+    // That is format version 1. In version 2 the timestamp is replaced by a versioned header:
+    //    +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
+    // 64 |  |  csum  |00|fv|       timestamp       |flags|
+    //    +--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+--+
+    // 80 |flags|    Options (selected by flags)          |
+    //    +--+--+                                         +
+    // XX |          Fee Entries (variable length)        |
+    //   ~~~                                             ~~~
+    // A version 1 timestamp never starts with a zero byte (that would be before July 1970), so
+    // a zero byte marks the versioned header, followed by the format version (fv), a 64 bit
+    // timestamp and 32 bits of flags. Each flag may add a fixed size field to the options.
+    //
+    // For an ERC-1271 signer (a smart contract wallet) the Signature field holds the address of
+    // the wallet in bytes 0-19, the length of its signature in bytes 20-21 (big endian) and v in
+    // byte 64. The wallet's signature is appended after the payload.
     uint32 constant SIG_START = 0;
     uint32 constant SIG_LEN = 65;
 
+    // The last byte of the signature (v) also carries the signature version:
+    // v = 27 + (version << 1) + recovery id
+    uint32 constant SIG_V = SIG_START + 64;
+    /// personal_sign over keccak256(abi.encode(keccak256(data), chainid))
+    uint8 constant SIG_VERSION_PERSONAL = 0;
+    /// EIP-712 typed data, see payAfterStructHash()
+    uint8 constant SIG_VERSION_EIP712 = 1;
+    /// EIP-712 typed data which also names the wallet, see signedHash(), validated by the
+    /// signer contract's isValidSignature()
+    uint8 constant SIG_VERSION_ERC1271 = 2;
+
+    bytes32 constant EIP712_DOMAIN_TYPEHASH = keccak256(
+        "EIP712Domain(string name,string version,uint256 chainId,address verifyingContract)");
+    bytes32 constant PAYAFTER_TYPEHASH = keccak256(
+        "PayAfter(uint64 creationTime,Fee[] fees,Call[] calls)"
+        "Call(address target,bytes data)"
+        "Fee(uint256 amount,uint64 afterSeconds)");
+    bytes32 constant PAYAFTER_V2_TYPEHASH = keccak256(
+        "PayAfterV2(uint64 creationTime,uint32 flags,bytes options,Fee[] fees,CallV2[] calls)"
+        "CallV2(address target,uint8 flags,uint256 value,bytes data)"
+        "Fee(uint256 amount,uint64 afterSeconds)");
+    bytes32 constant WALLET_PAYAFTER_TYPEHASH = keccak256(
+        "WalletPayAfter(address wallet,PayAfter payAfter)"
+        "Call(address target,bytes data)"
+        "Fee(uint256 amount,uint64 afterSeconds)"
+        "PayAfter(uint64 creationTime,Fee[] fees,Call[] calls)");
+    bytes32 constant WALLET_PAYAFTER_V2_TYPEHASH = keccak256(
+        "WalletPayAfterV2(address wallet,PayAfterV2 payAfter)"
+        "CallV2(address target,uint8 flags,uint256 value,bytes data)"
+        "Fee(uint256 amount,uint64 afterSeconds)"
+        "PayAfterV2(uint64 creationTime,uint32 flags,bytes options,Fee[] fees,CallV2[] calls)");
+    bytes32 constant FEE_TYPEHASH = keccak256("Fee(uint256 amount,uint64 afterSeconds)");
+    bytes32 constant CALL_TYPEHASH = keccak256("Call(address target,bytes data)");
+    bytes32 constant CALL_V2_TYPEHASH =
+        keccak256("CallV2(address target,uint8 flags,uint256 value,bytes data)");
+
     // The checksum is just the first 3 bytes of the address.
     // This protects against data corruption which causes the
     // sig check to recover the wrong address.
@@ -42,6 +96,41 @@ contract PayAfterDispatcher is IPayAfterDispatcher {
     uint32 constant TS_LEN = 4;
     uint32 constant FEE_START = TS_START + TS_LEN;
 
+    uint8 constant FORMAT_V1 = 1;
+    uint8 constant FORMAT_V2 = 2;
+    uint32 constant FV_START = TS_START + 1;
+    uint32 constant V2_TS_START = FV_START + 1;
+    uint32 constant V2_TS_LEN = 8;
+    uint32 constant FLAGS_START = V2_TS_START + V2_TS_LEN;
+    uint32 constant FLAGS_LEN = 4;
+    uint32 constant OPTIONS_START = FLAGS_START + FLAGS_LEN;
+    /// Each flag selects an option of a fixed size, the options follow the flags in order of
+    /// the flag bits.
+    /// The dispatch must start with at least this much gas left, so that a pollinator cannot
+    /// starve the calls of gas. The option is a 32 bit amount of gas.
+    uint32 constant FLAG_MIN_GAS = 1;
+    uint32 constant MIN_GAS_LEN = 4;
+    /// The transaction may only run after the same signer's transaction with this data hash
+    /// has run (and was not killed). The option is the 32 byte data hash.
+    /// Only the execution entry records that the required transaction ran, and anyone may
+    /// delete it once that transaction has expired, so the dependent transaction must run
+    /// before then. Sign it to expire no later than the transaction it requires.
+    uint32 constant FLAG_REQUIRES = 2;
+    uint32 constant REQUIRES_LEN = 32;
+    /// Only one pollinator may dispatch the transaction until some seconds after its creation
+    /// time, then anyone may. The option is the 20 byte pollinator address followed by the
+    /// 32 bit number of seconds.
+    uint32 constant FLAG_EXCLUSIVE = 4;
+    uint32 constant EXCLUSIVE_LEN = 24;
+    /// The fees are amounts of this ERC20 token, which the feeCollector transfers from the
+    /// signer to the pollinator (the signer must approve it), instead of the base coin. The
+    /// option is the 20 byte token address.
+    uint32 constant FLAG_FEE_TOKEN = 8;
+    uint32 constant FEE_TOKEN_LEN = 20;
+    /// Flags which this dispatcher understands, a transaction with any other flag is refused
+    /// because the signer expects something of it which it cannot do.
+    uint32 constant KNOWN_FLAGS = FLAG_MIN_GAS | FLAG_REQUIRES | FLAG_EXCLUSIVE | FLAG_FEE_TOKEN;
+
     /// Private key is `echo 'estimateGas' | sha256sum`
     /// This allows the signer to estimate gas before setting up the fee and signing.
     /// Pollinator implementations MUST refuse any transaction signed with this key.
@@ -56,20 +145,33 @@ contract PayAfterDispatcher is IPayAfterDispatcher {
         /// The sender of the transaction
         address signer;
         uint96 fee;
+        /// The token which the fee is paid in, 0 for the base coin
+        address feeToken;
     }
 
     // STATE //
     State private self_state;
     /// This is a hashmap of executions that have either been completed, or have
     /// been killed. The key is derived by taking the address concatnated with the
-    /// signature hash, i.e.
-    /// keccak256(address || MessageHashUtils.toEthSignedMessageHash(keccak256(message)))
+    /// signature hash (the hash which was signed, see signedHash()), i.e.
+    /// keccak256(address || signatureHash)
     /// This way, if someone claims the right to kill an execution, we hash their address
     /// with the provided hash before storing. Assuming keccak256() collisions to be "impossible"
     /// this gives every user their own namespace in the same mapping.
     mapping(bytes32 => uint) private self_executionBlacklist;
+    /// During dispatchBatch(), the fees owed to the pollinator by the transactions which have
+    /// already run. These coins are held by the dispatcher but do not belong to the signer of
+    /// the transaction currently running.
+    uint private self_batchReserved;
     // END STATE //
 
+    /// Signers approve this contract to take fees which are paid in a token
+    address public immutable override feeCollector;
+
+    constructor() {
+        feeCollector = address(new PayAfterFeeCollector());
+    }
+
     uint constant MINUTE_SEC = 60;
     uint constant HOUR_SEC = MINUTE_SEC * 60;
     uint constant DAY_SEC = HOUR_SEC * 24;
@@ -150,6 +252,86 @@ contract PayAfterDispatcher is IPayAfterDispatcher {
         uint32 feePacked1;
         /// The memory offset where data begins (after all of the fee entries).
         uint32 dataOffset;
+        /// FORMAT_V1 or FORMAT_V2
+        uint8 format;
+        /// Always 0 in format version 1
+        uint32 flags;
+        /// The offset of the first fee entry, the options end here.
+        uint32 feeStart;
+        /// Gas which must be left when the dispatch starts, 0 without FLAG_MIN_GAS
+        uint32 minGas;
+        /// Data hash of the transaction which must have run first, 0 without FLAG_REQUIRES
+        bytes32 requires;
+        /// The only pollinator which may dispatch before exclusiveUntil, see FLAG_EXCLUSIVE
+        address exclusive;
+        /// 0 without FLAG_EXCLUSIVE
+        uint64 exclusiveUntil;
+        /// The ERC20 token which the fees are in, 0 (the base coin) without FLAG_FEE_TOKEN
+        address feeToken;
+    }
+
+    function optionLen(uint32 flag) private pure returns (uint32) {
+        if (flag == FLAG_MIN_GAS) {
+            return MIN_GAS_LEN;
+        } else if (flag == FLAG_REQUIRES) {
+            return REQUIRES_LEN;
+        } else if (flag == FLAG_EXCLUSIVE) {
+            return EXCLUSIVE_LEN;
+        } else if (flag == FLAG_FEE_TOKEN) {
+            return FEE_TOKEN_LEN;
+        }
+        return 0;
+    }
+
+    /// Where the option for `flag` starts, or with a flag above all of `flags`, where the
+    /// options end.
+    function optionStart(uint32 flags, uint64 flag) private pure returns (uint32 start) {
+        start = OPTIONS_START;
+        for (uint64 f = 1; f < flag && f <= flags; f <<= 1) {
+            if (flags & f != 0) {
+                start += optionLen(uint32(f));
+            }
+        }
+    }
+
+    /// Parse the part of the header between the checksum and the fee entries.
+    function parseHeader(
+        bytes calldata signedMultiCall,
+        ParseFeeRet memory ret
+    ) private pure {
+        require(signedMultiCall.length >= FEE_START, "parseHeader() Buffer overflow");
+        if (signedMultiCall[TS_START] != 0) {
+            ret.format = FORMAT_V1;
+            ret.creationTime = uint64(uint32(bytes4(signedMultiCall[TS_START : TS_START+TS_LEN])));
+            ret.feeStart = FEE_START;
+            return;
+        }
+        ret.format = uint8(signedMultiCall[FV_START]);
+        require(ret.format == FORMAT_V2, "Unsupported format version");
+        require(signedMultiCall.length >= OPTIONS_START, "parseHeader() Buffer overflow");
+        ret.creationTime = uint64(bytes8(signedMultiCall[V2_TS_START : V2_TS_START+V2_TS_LEN]));
+        ret.flags = uint32(bytes4(signedMultiCall[FLAGS_START : FLAGS_START+FLAGS_LEN]));
+        require(ret.flags & ~KNOWN_FLAGS == 0, "Unsupported flags");
+        ret.feeStart = optionStart(ret.flags, uint64(1) << 32);
+        require(signedMultiCall.length >= ret.feeStart, "parseHeader() Buffer overflow");
+        if (ret.flags & FLAG_MIN_GAS != 0) {
+            uint32 start = optionStart(ret.flags, FLAG_MIN_GAS);
+            ret.minGas = uint32(bytes4(signedMultiCall[start : start+MIN_GAS_LEN]));
+        }
+        if (ret.flags & FLAG_REQUIRES != 0) {
+            uint32 start = optionStart(ret.flags, FLAG_REQUIRES);
+            ret.requires = bytes32(signedMultiCall[start : start+REQUIRES_LEN]);
+        }
+        if (ret.flags & FLAG_EXCLUSIVE != 0) {
+            uint32 start = optionStart(ret.flags, FLAG_EXCLUSIVE);
+            ret.exclusive = address(bytes20(signedMultiCall[start : start+20]));
+            ret.exclusiveUntil = ret.creationTime +
+                uint32(bytes4(signedMultiCall[start+20 : start+EXCLUSIVE_LEN]));
+        }
+        if (ret.flags & FLAG_FEE_TOKEN != 0) {
+            uint32 start = optionStart(ret.flags, FLAG_FEE_TOKEN);
+            ret.feeToken = address(bytes20(signedMultiCall[start : start+FEE_TOKEN_LEN]));
+        }
     }
 
     /// Fee Entry:
@@ -174,14 +356,14 @@ contract PayAfterDispatcher is IPayAfterDispatcher {
         bytes calldata signedMultiCall,
         uint64 currentTime
     ) public pure returns (ParseFeeRet memory ret) {
-        ret.creationTime = uint64(uint32(bytes4(signedMultiCall[TS_START : TS_START+TS_LEN])));
+        parseHeader(signedMultiCall, ret);
         // Start off with the kill fee, if there are no fee entries in the past then the transaction
         // is invalid with future expiration, meaning it will be valid in the future.
         uint32 feeEntry = 0;
         ret.feePacked0 = 0xffffffff;
         ret.feePacked1 = 0xffffffff;
         ret.expiration = type(uint64).max;
-        for (ret.dataOffset = FEE_START; (feeEntry >> 31) == 0; ret.dataOffset += 4) {
+        for (ret.dataOffset = ret.feeStart; (feeEntry >> 31) == 0; ret.dataOffset += 4) {
             require(ret.dataOffset + 4 <= signedMultiCall.length, "parseFee() Buffer overflow");
             feeEntry = uint32(bytes4(signedMultiCall[ret.dataOffset : ret.dataOffset + 4]));
             if (ret.expiration < type(uint64).max) {
@@ -204,6 +386,150 @@ contract PayAfterDispatcher is IPayAfterDispatcher {
         }
     }
 
+    function domainSeparator() public view override returns (bytes32) {
+        return keccak256(abi.encode(
+            EIP712_DOMAIN_TYPEHASH,
+            keccak256("PayAfterDispatcher"),
+            keccak256("1"),
+            block.chainid,
+            address(this)
+        ));
+    }
+
+    function feesStructHash(
+        bytes calldata signedMultiCall,
+        uint32 feeStart,
+        uint32 dataOffset
+    ) private pure returns (bytes32) {
+        bytes32[] memory fees = new bytes32[]((dataOffset - feeStart) / 4);
+        for (uint i = 0; i < fees.length; i++) {
+            uint32 feeEntry = uint32(bytes4(signedMultiCall[feeStart + i*4 : feeStart + i*4 + 4]));
+            uint amount = type(uint).max;
+            if ((feeEntry & ((uint32(1) << PACKED_FEE_WIDTH) - 1)) < PACKED_KILL_FEE) {
+                amount = unpackFee(feeEntry);
+            }
+            fees[i] = keccak256(abi.encode(FEE_TYPEHASH, amount, unpackTime(feeEntry)));
+        }
+        return keccak256(abi.encodePacked(fees));
+    }
+
+    /// Same walk as dispatchMulti(), counting the calls first
+    function callsStructHash(
+        bytes calldata multiCallData,
+        uint8 format
+    ) private pure returns (bytes32) {
+        uint count = 0;
+        for (bytes calldata d = multiCallData; d.length > callHeaderLen(format); count++) {
+            (, , d) = nextCall(d, format);
+        }
+        bytes32[] memory calls = new bytes32[](count);
+        for (uint i = 0; i < count; i++) {
+            (CallEntry memory c, bytes calldata callData, bytes calldata rest) =
+                nextCall(multiCallData, format);
+            if (format == FORMAT_V1) {
+                calls[i] = keccak256(abi.encode(CALL_TYPEHASH, c.target, keccak256(callData)));
+            } else {
+                calls[i] = keccak256(abi.encode(
+                    CALL_V2_TYPEHASH, c.target, c.flags, c.value, keccak256(callData)));
+            }
+            multiCallData = rest;
+        }
+        return keccak256(abi.encodePacked(calls));
+    }
+
+    /// The EIP-712 struct hash of a PayAfter, this is what a wallet shows the user:
+    ///     PayAfter(uint64 creationTime,Fee[] fees,Call[] calls)
+    ///     Fee(uint256 amount,uint64 afterSeconds)
+    ///     Call(address target,bytes data)
+    /// Each fee applies afterSeconds after creationTime, a kill fee has amount type(uint).max.
+    /// Format version 2 uses
+    ///     PayAfterV2(uint64 creationTime,uint32 flags,bytes options,Fee[] fees,CallV2[] calls)
+    ///     CallV2(address target,uint8 flags,uint256 value,bytes data)
+    /// so that a signature for one format can never be read as the other.
+    function payAfterStructHash(
+        bytes calldata signedMultiCall,
+        ParseFeeRet memory pfr
+    ) public pure returns (bytes32) {
+        bytes32 fees = feesStructHash(signedMultiCall, pfr.feeStart, pfr.dataOffset);
+        bytes32 calls = callsStructHash(
+            signedMultiCall[pfr.dataOffset : signedDataEnd(signedMultiCall)], pfr.format);
+        if (pfr.format == FORMAT_V1) {
+            return keccak256(abi.encode(PAYAFTER_TYPEHASH, pfr.creationTime, fees, calls));
+        }
+        return keccak256(abi.encode(
+            PAYAFTER_V2_TYPEHASH,
+            pfr.creationTime,
+            pfr.flags,
+            keccak256(signedMultiCall[OPTIONS_START : pfr.feeStart]),
+            fees,
+            calls
+        ));
+    }
+
+    /// The hash which the signer signed, it identifies the transaction in events and in
+    /// self_executionBlacklist.
+    function signedHash(
+        bytes calldata signedMultiCall,
+        ParseFeeRet memory pfr
+    ) internal view returns (bytes32) {
+        uint8 version = sigVersion(signedMultiCall);
+        if (version == SIG_VERSION_EIP712) {
+            return MessageHashUtils.toTypedDataHash(
+                domainSeparator(), payAfterStructHash(signedMultiCall, pfr));
+        }
+        if (version == SIG_VERSION_ERC1271) {
+            // The wallet is part of what is signed, so that a wallet owner's signature can
+            // neither be replayed as the owner's own PayAfter nor as another wallet's
+            //     WalletPayAfter(address wallet,PayAfter payAfter)
+            //     WalletPayAfterV2(address wallet,PayAfterV2 payAfter)
+            address wallet = address(bytes20(signedMultiCall[SIG_START : SIG_START+20]));
+            bytes32 typeHash = (pfr.format == FORMAT_V1) ?
+                WALLET_PAYAFTER_TYPEHASH : WALLET_PAYAFTER_V2_TYPEHASH;
+            return MessageHashUtils.toTypedDataHash(domainSeparator(), keccak256(abi.encode(
+                typeHash, wallet, payAfterStructHash(signedMultiCall, pfr))));
+        }
+        require(version == SIG_VERSION_PERSONAL, "Invalid signature version");
+        bytes32 dataHash = keccak256(signedMultiCall[SIG_START+SIG_LEN : ]);
+        dataHash = keccak256(abi.encode(dataHash, block.chainid));
+        return MessageHashUtils.toEthSignedMessageHash(dataHash);
+    }
+
+    function sigVersion(bytes calldata signedMultiCall) internal pure returns (uint8) {
+        uint8 v = uint8(signedMultiCall[SIG_V]);
+        require(v >= 27, "Invalid signature version");
+        return (v - 27) >> 1;
+    }
+
+    /// The end of the signed part of the transaction, an ERC-1271 signature follows it
+    function signedDataEnd(bytes calldata signedMultiCall) internal pure returns (uint) {
+        if (sigVersion(signedMultiCall) != SIG_VERSION_ERC1271) {
+            return signedMultiCall.length;
+        }
+        uint sigLen = uint16(bytes2(signedMultiCall[SIG_START+20 : SIG_START+22]));
+        require(signedMultiCall.length >= FEE_START + 4 + sigLen, "Invalid signature length");
+        return signedMultiCall.length - sigLen;
+    }
+
+    function recoverSigner(
+        bytes calldata signedMultiCall,
+        bytes32 dataHash
+    ) internal view returns (address) {
+        if (sigVersion(signedMultiCall) == SIG_VERSION_ERC1271) {
+            address wallet = address(bytes20(signedMultiCall[SIG_START : SIG_START+20]));
+            bytes calldata sig = signedMultiCall[signedDataEnd(signedMultiCall) : ];
+            require(SignatureChecker.isValidERC1271SignatureNow(wallet, dataHash, sig),
+                "Invalid ERC-1271 signature");
+            return wallet;
+        }
+        uint8 v = uint8(signedMultiCall[SIG_V]);
+        return ECDSA.recover(
+            dataHash,
+            27 + ((v - 27) & 1),
+            bytes32(signedMultiCall[SIG_START : SIG_START+32]),
+            bytes32(signedMultiCall[SIG_START+32 : SIG_START+64])
+        );
+    }
+
     function executionHash(bytes32 signatureHash, address sender) public pure override returns (bytes32) {
         return keccak256(abi.encode(sender, signatureHash));
     }
@@ -232,6 +558,10 @@ contract PayAfterDispatcher is IPayAfterDispatcher {
         return self_state.fee;
     }
 
+    function getFeeToken() public view override returns (address) {
+        return self_state.feeToken;
+    }
+
     uint constant SCALE = 1e18;
     function computeRequiredFee(
         ParseFeeRet memory pfr,
@@ -267,39 +597,95 @@ contract PayAfterDispatcher is IPayAfterDispatcher {
         return ret;
     }
 
-    function dispatchOne(address contractAddr, bytes calldata callData) private {
-        (bool success, bytes memory returnData) = contractAddr.call(callData);
-        if (!success) {
-            // Handling failure, `returnData` might contain revert reason if provided by the called function
-            if (returnData.length > 0) {
-                // Try to decode the revert reason
-                assembly {
-                    let revertReasonPtr := add(returnData, 0x20)
-                    let revertReasonLen := mload(returnData)
-                    revert(revertReasonPtr, revertReasonLen)
-                }
-            } else {
-                revert FailedCallNoReason(contractAddr, bytes4(callData[:4]));
-            }
+    /// Call Entry:
+    ///   Format 1: [target: 20] [callData length: 2] [callData: variable]
+    ///   Format 2: [target: 20] [call flags: 1] [callData length: 2]
+    ///             [value: 12, only if CALL_FLAG_VALUE] [callData: variable]
+    /// All numbers are big endian. Trailing data no longer than a call entry header is ignored.
+    ///
+    /// The value is paid out of the coins which the dispatcher holds for this transaction, i.e.
+    /// those which earlier calls have sent to it.
+    uint8 constant CALL_FLAG_VALUE = 1;
+    /// If the call reverts (or its value is not covered), emit PayAfterCallFailed and carry on
+    /// with the next call instead of reverting the whole transaction.
+    uint8 constant CALL_FLAG_ALLOW_FAILURE = 2;
+    uint8 constant KNOWN_CALL_FLAGS = CALL_FLAG_VALUE | CALL_FLAG_ALLOW_FAILURE;
+    uint constant CALL_VALUE_LEN = 12;
+
+    struct CallEntry {
+        address target;
+        /// Always 0 in format version 1
+        uint8 flags;
+        uint value;
+    }
+
+    function callHeaderLen(uint8 format) private pure returns (uint) {
+        return (format == FORMAT_V1) ? 22 : 23;
+    }
+
+    /// Parse the call entry at the start of multiCallData
+    /// @return c The target, flags and value of the call
+    /// @return callData The data to call the target with
+    /// @return rest The remaining call entries
+    function nextCall(bytes calldata multiCallData, uint8 format) private pure returns (
+        CallEntry memory c,
+        bytes calldata callData,
+        bytes calldata rest
+    ) {
+        c.target = address(bytes20(multiCallData[0:20]));
+        uint offset = 20;
+        if (format != FORMAT_V1) {
+            c.flags = uint8(multiCallData[offset]);
+            require(c.flags & ~KNOWN_CALL_FLAGS == 0, "Unsupported call flags");
+            offset += 1;
         }
+        uint length = uint16(bytes2(multiCallData[offset : offset+2]));
+        offset += 2;
+        if ((c.flags & CALL_FLAG_VALUE) != 0) {
+            c.value = uint96(bytes12(multiCallData[offset : offset+CALL_VALUE_LEN]));
+            offset += CALL_VALUE_LEN;
+        }
+        callData = multiCallData[offset : offset+length];
+        rest = multiCallData[offset+length : ];
     }
-    // Format is: [contractAddr: 20] [callData length: 2 (big endian)] [callData: variable]
-    function dispatchMulti(bytes calldata multiCallData) private {
-        while (multiCallData.length > 22) {
-            // Extract the contract address (20 bytes)
-            address contractAddr = address(bytes20(multiCallData[0:20]));
 
-            bytes calldata callData;
-            {
-                // Extract the length of callData (2 bytes)
-                uint length = uint(bytes32(multiCallData[20:22])) >> 240;
-                // Slice the callData based on the length
-                callData = multiCallData[22 : 22 + length];
-                multiCallData = multiCallData[22 + length : ];
+    /// @return success True if the call succeeded
+    /// @return reason The revert data if it did not
+    function dispatchOne(
+        address contractAddr,
+        uint value,
+        bytes calldata callData
+    ) private returns (bool success, bytes memory reason) {
+        if (value > 0) {
+            uint available = address(this).balance - self_batchReserved;
+            if (available < value) {
+                return (false, abi.encodeWithSelector(ValueNotCovered.selector, available, value));
             }
+        }
+        (success, reason) = contractAddr.call{value: value}(callData);
+        if (!success && reason.length == 0) {
+            // A plain transfer of value has no function selector
+            bytes4 selector = (callData.length >= 4) ? bytes4(callData[:4]) : bytes4(0);
+            reason = abi.encodeWithSelector(FailedCallNoReason.selector, contractAddr, selector);
+        }
+    }
 
-            // Call dispatchOne with the extracted address and call data
-            dispatchOne(contractAddr, callData);
+    function dispatchMulti(bytes calldata multiCallData, uint8 format) private {
+        for (uint i = 0; multiCallData.length > callHeaderLen(format); i++) {
+            (CallEntry memory c, bytes calldata callData, bytes calldata rest) =
+                nextCall(multiCallData, format);
+            multiCallData = rest;
+            (bool success, bytes memory reason) = dispatchOne(c.target, c.value, callData);
+            if (success) {
+                continue;
+            }
+            if ((c.flags & CALL_FLAG_ALLOW_FAILURE) == 0) {
+                // Bubble up the revert reason
+                assembly {
+                    revert(add(reason, 0x20), mload(reason))
+                }
+            }
+            emit PayAfterCallFailed(i, reason);
         }
     }
 
@@ -320,25 +706,27 @@ contract PayAfterDispatcher is IPayAfterDispatcher {
 
     function dispatch0(
         bytes calldata signedMultiCall,
-        bytes calldata pollinatorData
-    ) private returns (bytes calldata) {
+        bytes calldata pollinatorData,
+        address pollinator
+    ) private returns (bytes calldata, uint8) {
         // It is required that we have at least one fee entry
         require(signedMultiCall.length >= FEE_START + 4);
         uint64 block_timestamp = uint64(block.timestamp);
-        if (msg.sender == SIMULATE_ADDRESS && pollinatorData.length >= 8) {
+        if (pollinator == SIMULATE_ADDRESS && pollinatorData.length >= 8) {
             block_timestamp = uint64(bytes8(pollinatorData[:8]));
             pollinatorData = pollinatorData[8:];
         }
         ParseFeeRet memory pfr = parseFee(signedMultiCall, block_timestamp);
         require(pfr.feePacked0 < 0xffffffff, "Transaction not yet valid");
         require(pfr.expiration > block_timestamp, "Transaction has expired");
+        require(gasleft() >= pfr.minGas, "Not enough gas");
+        require(block_timestamp >= pfr.exclusiveUntil || pollinator == pfr.exclusive ||
+                pollinator == SIMULATE_ADDRESS, "Exclusive to another pollinator");
 
         address signer;
         {
-            bytes32 dataHash = keccak256(signedMultiCall[SIG_START+SIG_LEN : ]);
-            dataHash = keccak256(abi.encode(dataHash, block.chainid));
-            dataHash = MessageHashUtils.toEthSignedMessageHash(dataHash);
-            signer = ECDSA.recover(dataHash, signedMultiCall[SIG_START : SIG_START+SIG_LEN]);
+            bytes32 dataHash = signedHash(signedMultiCall, pfr);
+            signer = recoverSigner(signedMultiCall, dataHash);
 
             {
                 uint24 c = uint24(bytes3(signedMultiCall[CSUM_START : CSUM_START+CSUM_LEN]));
@@ -347,21 +735,28 @@ contract PayAfterDispatcher is IPayAfterDispatcher {
 
             if (signer == ESTIMATEGAS_ADDRESS) {
                 // In estimateGas mode we use the msg sender so that signing is not required.
-                signer = msg.sender;
+                signer = pollinator;
                 // This is a convenience for the pollinator which will fail execution if he is
                 // estimating gas on a transaction which was (maliciously) signed using the
                 // estimateGas address. This way he knows to discard the transaction and do
                 // not under any circumstances send it.
-                require(msg.sender != SIMULATE_ADDRESS, "Signed with estimateGas");
+                require(pollinator != SIMULATE_ADDRESS, "Signed with estimateGas");
             }
 
             {
                 bytes32 eh = executionHash(dataHash, signer);
-                require(self_executionBlacklist[eh] == 0 || msg.sender == SIMULATE_ADDRESS,
+                require(self_executionBlacklist[eh] == 0 || pollinator == SIMULATE_ADDRESS,
                         "Already executed or killed");
                 self_executionBlacklist[eh] = pfr.expiration;
             }
 
+            if (pfr.flags & FLAG_REQUIRES != 0) {
+                // An executed transaction's entry is its expiration, a killed one's is max. An
+                // expired one's may have been deleted, see FLAG_REQUIRES.
+                uint e = self_executionBlacklist[executionHash(pfr.requires, signer)];
+                require(e != 0 && e != type(uint).max, "Required transaction has not run");
+            }
+
             emit PayAfter(dataHash, signer, pfr.expiration);
             require(self_state.signer == address(0), "Reentrence");
             self_state.signer = signer;
@@ -371,6 +766,7 @@ contract PayAfterDispatcher is IPayAfterDispatcher {
             uint fee = computeRequiredFee(pfr, block_timestamp);
             require(fee <= type(uint96).max, "Fee cannot be represented");
             self_state.fee = uint96(fee);
+            self_state.feeToken = pfr.feeToken;
         }
 
         for (; 32 <= pollinatorData.length; pollinatorData = pollinatorData[32 : ]) {
@@ -382,26 +778,83 @@ contract PayAfterDispatcher is IPayAfterDispatcher {
             delete self_executionBlacklist[evict_eh];
         }
 
-        return signedMultiCall[pfr.dataOffset : ];
+        return (signedMultiCall[pfr.dataOffset : signedDataEnd(signedMultiCall)], pfr.format);
     }
 
-    function dispatch1() private {
-        uint fee = getRequiredFee();
-        if (address(this).balance < fee) {
-            revert FeeNotCovered(address(this).balance, fee);
+    /// Take the fee out of the coins which the transaction has sent to the dispatcher and refund
+    /// the rest to the signer. A fee in a token is transferred from the signer to the pollinator
+    /// by the feeCollector and all of the coins are refunded.
+    /// @return fee The fee in the base coin which is owed to the pollinator
+    function dispatch1(address pollinator) private returns (uint fee) {
+        address signer = self_state.signer;
+        address token = self_state.feeToken;
+        fee = (token == address(0)) ? getRequiredFee() : 0;
+        uint available = address(this).balance - self_batchReserved;
+        if (available < fee) {
+            revert FeeNotCovered(available, fee);
         }
-        payable(msg.sender).transfer(fee);
-        if (address(this).balance > 0) {
-            payable(self_state.signer).transfer(address(this).balance);
+        if (available > fee) {
+            payable(signer).transfer(available - fee);
+        }
+        if (token != address(0)) {
+            // Before self_state is cleared, the collector checks that it takes from the signer
+            payTokenFee(token, signer, pollinator, getRequiredFee());
         }
         delete self_state;
     }
 
+    function payTokenFee(address token, address signer, address pollinator, uint fee) private {
+        // Nobody can send from the SIMULATE_ADDRESS, the tokens only need to go somewhere
+        address to = (pollinator == SIMULATE_ADDRESS) ? address(this) : pollinator;
+        if (!PayAfterFeeCollector(feeCollector).collect(token, signer, to, fee)) {
+            revert FeeTokenNotCovered(token, fee);
+        }
+    }
+
     // Recover address and dispatch
     function dispatch(bytes calldata signedMultiCall, bytes calldata pollinatorData) external override {
-        signedMultiCall = dispatch0(signedMultiCall, pollinatorData);
-        dispatchMulti(signedMultiCall);
-        dispatch1();
+        uint8 format;
+        (signedMultiCall, format) = dispatch0(signedMultiCall, pollinatorData, msg.sender);
+        dispatchMulti(signedMultiCall, format);
+        payable(msg.sender).transfer(dispatch1(msg.sender));
+    }
+
+    /// Used by dispatchBatch() so that a failing transaction reverts only its own changes.
+    function dispatchBatchItem(
+        bytes calldata signedMultiCall,
+        bytes calldata pollinatorData,
+        address pollinator
+    ) external returns (uint fee) {
+        require(msg.sender == address(this), "Only self");
+        uint8 format;
+        (signedMultiCall, format) = dispatch0(signedMultiCall, pollinatorData, pollinator);
+        dispatchMulti(signedMultiCall, format);
+        fee = dispatch1(pollinator);
+        self_batchReserved += fee;
+    }
+
+    /// Run a number of PayAfter transactions one after another. A transaction which fails is
+    /// skipped (emitting PayAfterFailed) and the fees of the others are paid together.
+    /// The pollinatorData is only used for the SIMULATE_ADDRESS timestamp, expired entries
+    /// cannot be removed in a batch.
+    function dispatchBatch(
+        bytes[] calldata signedMultiCalls,
+        bytes calldata pollinatorData
+    ) external override {
+        require(self_state.signer == address(0), "Reentrence");
+        bytes calldata itemData = pollinatorData[:0];
+        if (msg.sender == SIMULATE_ADDRESS && pollinatorData.length >= 8) {
+            itemData = pollinatorData[:8];
+        }
+        for (uint i = 0; i < signedMultiCalls.length; i++) {
+            try this.dispatchBatchItem(signedMultiCalls[i], itemData, msg.sender) {
+            } catch (bytes memory reason) {
+                emit PayAfterFailed(i, reason);
+            }
+        }
+        uint total = self_batchReserved;
+        self_batchReserved = 0;
+        payable(msg.sender).transfer(total);
     }
 
     receive() external payable { }
@@ -0,0 +1,30 @@
+// SPDX-License-Identifier: MIT OR Apache-2
+pragma solidity ^0.8.28;
+
+import "@openzeppelin/contracts/interfaces/IERC20.sol";
+
+import "./interface/IPayAfterDispatcher.sol";
+
+/// Transfers PayAfter fees which are paid in a token, signers approve this contract rather than
+/// the dispatcher. The dispatcher makes every call of every PayAfter transaction, so tokens
+/// approved to it could be taken by anyone's transaction. This contract only takes tokens from
+/// the signer of the transaction being dispatched, and only when the dispatcher asks.
+contract PayAfterFeeCollector {
+    IPayAfterDispatcher public immutable dispatcher;
+
+    constructor() {
+        dispatcher = IPayAfterDispatcher(msg.sender);
+    }
+
+    /// Transfer the fee from the signer, returns false if the token refuses
+    function collect(address token, address from, address to, uint amount) external returns (bool) {
+        require(msg.sender == address(dispatcher), "Only the dispatcher");
+        // The transaction's own calls also come from the dispatcher, they may only spend
+        // what their signer approved
+        require(from == dispatcher.getSigner(), "Only from the signer");
+        (bool ok, bytes memory ret) = token.call(
+            abi.encodeCall(IERC20.transferFrom, (from, to, amount)));
+        // Like SafeERC20, accept tokens which return nothing but not an address without code
+        return ok && (ret.length == 0 ? token.code.length > 0 : abi.decode(ret, (bool)));
+    }
+}
@@ -3,42 +3,228 @@ pragma solidity ^0.8.28;
 
 import "@openzeppelin/contracts/utils/cryptography/ECDSA.sol";
 import "@openzeppelin/contracts/utils/cryptography/MessageHashUtils.sol";
+import "@openzeppelin/contracts/token/ERC20/IERC20.sol";
+import "@openzeppelin/contracts/token/ERC20/utils/SafeERC20.sol";
 
 import "./interface/IPeriodic.sol";
 import "./interface/IPeriodicDispatcher.sol";
 
 contract PeriodicDispatcher is IPeriodicDispatcher {
+    using SafeERC20 for IERC20;
+
+    struct NectarToken {
+        address token;
+        bool fixed;
+    }
+
+    /// Balances are in units of each periodic contract's nectar token
     mapping(address => uint) private self_balances;
+    /// The token of each periodic contract's balance, read from its nectarToken() when the
+    /// contract is first funded or cycled and never again. Otherwise a contract could fund
+    /// itself in a worthless token and then switch, to withdraw coins or tokens which belong
+    /// to other contracts.
+    mapping(address => NectarToken) private self_tokens;
+    /// Most funders listed for each periodic contract, so anyone can make a deposit without
+    /// the list growing beyond what fundersOf() can return
+    uint public constant MAX_FUNDERS = 32;
+
+    /// Deposited by each funder less what was withdrawn back to them,
+    /// periodic contract => funder => amount
+    mapping(address => mapping(address => uint)) private self_deposits;
+    /// The first MAX_FUNDERS funders of each periodic contract which still have a deposit
+    mapping(address => address[]) private self_funders;
+    /// Set while a periodic contract is being cycled. Whatever the dispatcher receives in the
+    /// meantime is credited to that contract, so nectar added through addNectar() or
+    /// addTokenNectar() then would be credited twice.
+    bool private self_dispatching;
+
+    modifier notDispatching() {
+        require(!self_dispatching, "Dispatch in progress");
+        _;
+    }
 
     function balanceOf(address ofWhom) public view override returns (uint) {
         return self_balances[ofWhom];
     }
 
-    function addNectar(address forWhom) public payable {
-        self_balances[forWhom] += msg.value;
+    function depositOf(address periodicContract, address funder) external view override returns (uint) {
+        return self_deposits[periodicContract][funder];
     }
 
-    function dispatch(address periodicContract, uint minNectar) external {
+    function fundersOf(address periodicContract) external view override returns (address[] memory) {
+        return self_funders[periodicContract];
+    }
+
+    /// The token a periodic contract pays nectar in, address(0) for the base coin.
+    /// Contracts which do not implement nectarToken() pay in the base coin. Once the contract
+    /// has been funded or cycled this is the token it was fixed to.
+    function nectarTokenOf(address periodicContract) public view override returns (address) {
+        NectarToken memory nt = self_tokens[periodicContract];
+        if (nt.fixed) {
+            return nt.token;
+        }
+        return _readNectarToken(periodicContract);
+    }
+
+    function _readNectarToken(address periodicContract) internal view returns (address) {
+        (bool ok, bytes memory ret) =
+            periodicContract.staticcall(abi.encodeCall(IPeriodic.nectarToken, ()));
+        if (!ok || ret.length < 32) {
+            return address(0);
+        }
+        return abi.decode(ret, (address));
+    }
+
+    /// The token of the periodic contract's balance, fixing it if this is its first use
+    function _nectarToken(address periodicContract) internal returns (address) {
+        NectarToken memory nt = self_tokens[periodicContract];
+        if (nt.fixed) {
+            return nt.token;
+        }
+        address token = _readNectarToken(periodicContract);
+        self_tokens[periodicContract] = NectarToken(token, true);
+        return token;
+    }
+
+    function _credit(address forWhom, uint amount) internal {
+        self_balances[forWhom] += amount;
+        if (self_deposits[forWhom][msg.sender] == 0 && amount > 0 &&
+            self_funders[forWhom].length < MAX_FUNDERS)
+        {
+            self_funders[forWhom].push(msg.sender);
+        }
+        self_deposits[forWhom][msg.sender] += amount;
+        emit NectarAdded(forWhom, msg.sender, amount);
+    }
+
+    function addNectar(address forWhom) public payable notDispatching {
+        require(_nectarToken(forWhom) == address(0), "Nectar is paid in a token");
+        _credit(forWhom, msg.value);
+    }
+
+    /// Fund a periodic contract which pays nectar in a token, the amount must be approved first.
+    function addTokenNectar(address forWhom, uint amount) external override notDispatching {
+        address token = _nectarToken(forWhom);
+        require(token != address(0), "Nectar is paid in the base coin");
+        // Credit what actually arrived, in case the token takes a fee on transfer
+        uint bal = IERC20(token).balanceOf(address(this));
+        IERC20(token).safeTransferFrom(msg.sender, address(this), amount);
+        _credit(forWhom, IERC20(token).balanceOf(address(this)) - bal);
+    }
+
+    function _held(address token) internal view returns (uint) {
+        if (token == address(0)) {
+            return address(this).balance;
+        }
+        return IERC20(token).balanceOf(address(this));
+    }
+
+    function _pay(address token, address payable to, uint amount) internal {
+        if (token == address(0)) {
+            to.transfer(amount);
+        } else {
+            IERC20(token).safeTransfer(to, amount);
+        }
+    }
+
+    /// Nectar withdrawn to one of its funders is a refund of their deposit
+    function _refund(address periodicContract, address to, uint amount) internal {
+        uint deposit = self_deposits[periodicContract][to];
+        if (deposit == 0) {
+            return;
+        }
+        if (amount < deposit) {
+            self_deposits[periodicContract][to] = deposit - amount;
+            return;
+        }
+        self_deposits[periodicContract][to] = 0;
+        address[] storage funders = self_funders[periodicContract];
+        for (uint i = 0; i < funders.length; i++) {
+            if (funders[i] == to) {
+                funders[i] = funders[funders.length - 1];
+                funders.pop();
+                break;
+            }
+        }
+    }
+
+    /// Withdraw unused nectar, this must be called by the periodic contract itself so that
+    /// the contract decides who is authorized to withdraw, for example its owner.
+    function withdrawNectar(address payable to, uint amount) external override notDispatching {
+        require(self_balances[msg.sender] >= amount, "Not enough balance");
+        self_balances[msg.sender] -= amount;
+        _refund(msg.sender, to, amount);
+        emit NectarWithdrawn(msg.sender, to, amount);
+        _pay(_nectarToken(msg.sender), to, amount);
+    }
+
+    /// Cycle the periodic contract and return the nectar to be paid and the token it is paid
+    /// in, without paying it.
+    function _dispatch(
+        address periodicContract,
+        uint minNectar
+    ) internal notDispatching returns (address, uint) {
         {
             uint _toPay = IPeriodic(periodicContract).nectarAvailable();
             require(minNectar <= _toPay, "Not enough nectar");
             minNectar = _toPay;
         }
 
-        uint bal = address(this).balance;
+        address token = _nectarToken(periodicContract);
+        uint bal = _held(token);
+        self_dispatching = true;
         IPeriodic(periodicContract).periodic();
         IPeriodic(periodicContract).postPeriodic();
-        bal = address(this).balance - bal + self_balances[periodicContract];
+        self_dispatching = false;
+        bal = _held(token) - bal + self_balances[periodicContract];
 
         {
             require(bal >= minNectar, "Not enough balance");
             bal -= minNectar;
-            payable(msg.sender).transfer(minNectar);
             self_balances[periodicContract] = bal;
         }
 
         emit PeriodicPollinated(periodicContract, minNectar);
+        return (token, minNectar);
+    }
+
+    function dispatch(address periodicContract, uint minNectar) external {
+        (address token, uint nectar) = _dispatch(periodicContract, minNectar);
+        _pay(token, payable(msg.sender), nectar);
+    }
+
+    /// Used by dispatchMany() so that a failing entry reverts only its own changes.
+    /// Nectar paid in a token goes straight to the pollinator, nectar in the base coin is
+    /// returned so that it can be paid in one transfer.
+    function dispatchSelf(
+        address periodicContract,
+        uint minNectar,
+        address payable pollinator
+    ) external returns (uint) {
+        require(msg.sender == address(this), "Only self");
+        (address token, uint nectar) = _dispatch(periodicContract, minNectar);
+        if (token == address(0)) {
+            return nectar;
+        }
+        _pay(token, pollinator, nectar);
+        return 0;
+    }
+
+    function dispatchMany(
+        address[] calldata periodicContracts,
+        uint[] calldata minNectar
+    ) external notDispatching {
+        require(periodicContracts.length == minNectar.length, "Length mismatch");
+        uint total = 0;
+        for (uint i = 0; i < periodicContracts.length; i++) {
+            try this.dispatchSelf(periodicContracts[i], minNectar[i], payable(msg.sender)) returns (uint nectar) {
+                total += nectar;
+            } catch (bytes memory reason) {
+                emit PeriodicFailed(periodicContracts[i], reason);
+            }
+        }
+        payable(msg.sender).transfer(total);
     }
 
     receive() external payable { }
-}
\ No newline at end of file
+}
@@ -19,6 +19,7 @@ contract UniswapV2Helper {
     function coverFeeWithLimit(address token, uint maxTokens) public {
         IPayAfterDispatcher dispatcher = IPayAfterDispatcher(self_dispatcher);
         address signer = dispatcher.getSigner();
+        require(dispatcher.getFeeToken() == address(0), "Fee is paid in a token");
         if (maxTokens == 0) {
             maxTokens = IERC20(token).balanceOf(signer);
         }
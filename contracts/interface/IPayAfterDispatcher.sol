@@ -4,14 +4,27 @@ pragma solidity ^0.8.28;
 interface IPayAfterDispatcher {
     event PayAfter(bytes32 dataHash, address signer, uint64 expiration);
     event PayAfterExpired(bytes32 dataHash, address signer);
+    event PayAfterFailed(uint index, bytes reason);
+    /// A call with CALL_FLAG_ALLOW_FAILURE failed, this belongs to the PayAfter event before it
+    event PayAfterCallFailed(uint index, bytes reason);
 
     error FailedCallNoReason(address contractAddr, bytes4 functionId);
     error FeeNotCovered(uint balance, uint needed);
+    error ValueNotCovered(uint balance, uint needed);
+    /// Transferring the fee in a token from the signer failed, usually for lack of allowance
+    error FeeTokenNotCovered(address token, uint needed);
 
     function executionHash(bytes32 signatureHash, address sender) external pure returns (bytes32);
     function getSigner() external view returns (address);
     function killTransaction(bytes32 signatureHash) external;
     function executionBlacklist(bytes32 executionHash) external view returns (uint);
+    /// The fee owed by the transaction being dispatched, in getFeeToken()
     function getRequiredFee() external view returns (uint);
+    /// The ERC20 token which the fee is paid in, 0 for the base coin
+    function getFeeToken() external view returns (address);
+    /// The contract which signers approve to take fees paid in a token
+    function feeCollector() external view returns (address);
+    function domainSeparator() external view returns (bytes32);
     function dispatch(bytes calldata signedMultiCall, bytes calldata deleteReplays) external;
+    function dispatchBatch(bytes[] calldata signedMultiCalls, bytes calldata pollinatorData) external;
 }
\ No newline at end of file
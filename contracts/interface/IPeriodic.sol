@@ -5,4 +5,21 @@ interface IPeriodic {
     function nectarAvailable() external view returns (uint);
     function periodic() external;
     function postPeriodic() external;
-}
\ No newline at end of file
+    function periodicDispatcher() external view returns (address payable);
+    function nectarShortfall() external view returns (uint);
+    function nectarToken() external view returns (address);
+
+    function currentPayPerPeriod() external view returns (uint);
+    function lastCycleTime() external view returns (uint64);
+    function lastRetargetTime() external view returns (uint64);
+    function cycles() external view returns (uint64);
+    function cyclesPerRetarget() external view returns (uint64);
+    function targetSecondsPerCycle() external view returns (uint64);
+    function retargetParams() external view returns (
+        uint minFactor,
+        uint maxFactor,
+        uint smoothing,
+        uint minPay,
+        uint maxPay
+    );
+}
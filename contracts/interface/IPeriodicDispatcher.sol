@@ -3,7 +3,16 @@ pragma solidity ^0.8.28;
 
 interface IPeriodicDispatcher {
     event PeriodicPollinated(address, uint);
+    event PeriodicFailed(address, bytes);
+    event NectarAdded(address periodicContract, address funder, uint amount);
+    event NectarWithdrawn(address periodicContract, address to, uint amount);
     function addNectar(address forWhom) external payable;
+    function addTokenNectar(address forWhom, uint amount) external;
+    function nectarTokenOf(address periodicContract) external view returns (address);
+    function withdrawNectar(address payable to, uint amount) external;
     function balanceOf(address ofWhom) external view returns (uint);
+    function depositOf(address periodicContract, address funder) external view returns (uint);
+    function fundersOf(address periodicContract) external view returns (address[] memory);
     function dispatch(address periodicContract, uint minNectar) external;
-}
\ No newline at end of file
+    function dispatchMany(address[] calldata periodicContracts, uint[] calldata minNectar) external;
+}
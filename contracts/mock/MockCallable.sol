@@ -17,4 +17,15 @@ contract MockCallable is MockPayAfter {
         lastCaller = msgSender();
         total += val;
     }
+
+    function failMe() external pure {
+        revert("Not today");
+    }
+
+    /// Stands in for something which pays out coins to the dispatcher, like WETH.withdraw()
+    function payOut(uint amount) external {
+        payable(msg.sender).transfer(amount);
+    }
+
+    receive() external payable { }
 }
\ No newline at end of file
@@ -0,0 +1,24 @@
+// SPDX-License-Identifier: MIT OR Apache-2
+pragma solidity ^0.8.28;
+
+import "@openzeppelin/contracts/interfaces/IERC1271.sol";
+import "@openzeppelin/contracts/utils/cryptography/ECDSA.sol";
+
+/// A smart contract wallet which accepts signatures made by its owner
+contract MockERC1271Wallet is IERC1271 {
+    address private immutable self_owner;
+
+    constructor(address owner) {
+        self_owner = owner;
+    }
+
+    function isValidSignature(bytes32 hash, bytes memory signature) external view returns (bytes4) {
+        (address recovered, ECDSA.RecoverError err, ) = ECDSA.tryRecover(hash, signature);
+        if (err == ECDSA.RecoverError.NoError && recovered == self_owner) {
+            return IERC1271.isValidSignature.selector;
+        }
+        return 0xffffffff;
+    }
+
+    receive() external payable { }
+}
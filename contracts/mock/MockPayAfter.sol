@@ -12,7 +12,8 @@ contract MockPayAfter {
 
     function msgSender() internal view returns (address) {
         if (msg.sender == self_dispatcher) {
-            return IPayAfterDispatcher(self_dispatcher).getSigner();
+            address signer = IPayAfterDispatcher(self_dispatcher).getSigner();
+            return (signer == address(0)) ? msg.sender : signer;
         } else {
             return address(msg.sender);
         }
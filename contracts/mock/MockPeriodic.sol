@@ -6,12 +6,18 @@ import "../BasePeriodic.sol";
 contract MockPeriodic is BasePeriodic {
     uint public counter;
     address private immutable self_dispatcher;
+    address private immutable self_owner;
     constructor(address dispatcher) BasePeriodic(
         60 * 10,                       // _targetSecondsPerCycle
         100,                           // _cyclesPerRetarget
         1 * 10**18 / uint(2726) / 10   // _initialPayPerPeriod
     ) {
         self_dispatcher = dispatcher;
+        self_owner = msg.sender;
+    }
+    function retire(address payable to, uint amount) external {
+        require(msg.sender == self_owner, "Only owner");
+        withdrawNectar(to, amount);
     }
     function periodic() external override {
         counter++;
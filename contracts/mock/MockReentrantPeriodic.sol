@@ -0,0 +1,41 @@
+// SPDX-License-Identifier: MIT OR Apache-2
+pragma solidity ^0.8.28;
+
+import "@openzeppelin/contracts/interfaces/IERC20.sol";
+
+import "../BasePeriodic.sol";
+
+/// A periodic contract which adds nectar to itself from inside periodic(), hoping to have it
+/// credited twice and then withdraw what belongs to other contracts
+contract MockReentrantPeriodic is BasePeriodic {
+    address private immutable self_dispatcher;
+    address private immutable self_token;
+    constructor(address dispatcher, address token) BasePeriodic(
+        60 * 10,                       // _targetSecondsPerCycle
+        100,                           // _cyclesPerRetarget
+        1000                           // _initialPayPerPeriod
+    ) {
+        self_dispatcher = dispatcher;
+        self_token = token;
+    }
+    function nectarToken() public view override returns (address) {
+        return self_token;
+    }
+    function periodic() external override {
+        IPeriodicDispatcher disp = IPeriodicDispatcher(periodicDispatcher());
+        if (self_token == address(0)) {
+            disp.addNectar{ value: address(this).balance }(address(this));
+        } else {
+            uint amount = IERC20(self_token).balanceOf(address(this));
+            IERC20(self_token).approve(address(disp), amount);
+            disp.addTokenNectar(address(this), amount);
+        }
+    }
+    function retire(address payable to, uint amount) external {
+        withdrawNectar(to, amount);
+    }
+    function periodicDispatcher() override public view returns (address payable) {
+        return payable(self_dispatcher);
+    }
+    receive() external payable { }
+}
@@ -0,0 +1,35 @@
+// SPDX-License-Identifier: MIT OR Apache-2
+pragma solidity ^0.8.28;
+
+import "../BasePeriodic.sol";
+
+/// A periodic contract which pays its nectar in an ERC20
+contract MockTokenPeriodic is BasePeriodic {
+    uint public counter;
+    address private immutable self_dispatcher;
+    address private self_token;
+    constructor(address dispatcher, address token) BasePeriodic(
+        60 * 10,                       // _targetSecondsPerCycle
+        100,                           // _cyclesPerRetarget
+        1 * 10**18                     // _initialPayPerPeriod
+    ) {
+        self_dispatcher = dispatcher;
+        self_token = token;
+    }
+    function nectarToken() public view override returns (address) {
+        return self_token;
+    }
+    /// Misbehave by switching token, the dispatcher must keep using the first one
+    function setNectarToken(address token) external {
+        self_token = token;
+    }
+    function retire(address payable to, uint amount) external {
+        withdrawNectar(to, amount);
+    }
+    function periodic() external override {
+        counter++;
+    }
+    function periodicDispatcher() override public view returns (address payable) {
+        return payable(self_dispatcher);
+    }
+}
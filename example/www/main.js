@@ -191,7 +191,7 @@ const createTransaction = async (to, amt, fees) => {
         feeCheck = fees;
     }
 
-    const gas = await Pollinate.PayAfter.estimateGas(wallet.signer, calls, feeCheck);
+    const gas = await Pollinate.PayAfter.estimateGas(wallet.signer, calls, feeCheck, undefined, 'eip712');
     const feeData = await wallet.provider.getFeeData();
 
     if (!fees) {
@@ -201,7 +201,7 @@ const createTransaction = async (to, amt, fees) => {
         });
     }
 
-    let signed = await Pollinate.PayAfter.signCalls(wallet.signer, calls, fees);
+    let signed = await Pollinate.PayAfter.signCalls(wallet.signer, calls, fees, { mode: 'eip712' });
     console.log(signed);
 
     const response = await fetch(`${POLLINATOR}/api/v1/payafter`, {
@@ -6,20 +6,81 @@ import {
     UNISWAPV2_HELPER_ABI,
 } from './generate';
 
+export type CallOpts = {
+    /// Base coin to send with the call, paid out of what the dispatcher has received earlier in
+    /// the same transaction. Requires format 2.
+    value?: bigint,
+    /// If the call reverts, carry on with the rest of the transaction instead of reverting it.
+    /// The dispatcher emits PayAfterCallFailed. Requires format 2.
+    allowFailure?: boolean,
+};
+
+const CALL_FLAG_VALUE = 1;
+const CALL_FLAG_ALLOW_FAILURE = 2;
+const MAX_CALL_VALUE = (BigInt(1) << BigInt(96)) - BigInt(1);
+
+// A prepared call is always a format 2 call entry, signCalls() re-encodes it for format 1
+type CallEntry = { target: string, flags: number, value: bigint, data: string };
+
+const encodeCall = (c: CallEntry, format: number): string => {
+    const target = c.target.replace(/^0x/, '').toLowerCase();
+    const lengthHex = (c.data.length / 2).toString(16).padStart(4, '0');
+    if (format === 1) {
+        if (c.flags & CALL_FLAG_VALUE) {
+            throw new Error("signCalls(): sending value with a call requires format 2");
+        }
+        if (c.flags & CALL_FLAG_ALLOW_FAILURE) {
+            throw new Error("signCalls(): allowFailure requires format 2");
+        }
+        return target + lengthHex + c.data;
+    }
+    const value = (c.flags & CALL_FLAG_VALUE) ? c.value.toString(16).padStart(24, '0') : '';
+    return target + c.flags.toString(16).padStart(2, '0') + lengthHex + value + c.data;
+};
+
+// Split the calls the same way as PayAfterDispatcher.dispatchMulti()
+const splitCalls = (data: string, format: number): CallEntry[] => {
+    const out = [];
+    const headerLen = (format === 1) ? 22 : 23;
+    while (data.length > headerLen * 2) {
+        const target = '0x' + data.slice(0, 40);
+        let pos = 40;
+        let flags = 0;
+        if (format !== 1) {
+            flags = parseInt(data.slice(pos, pos + 2), 16);
+            pos += 2;
+        }
+        const length = parseInt(data.slice(pos, pos + 4), 16);
+        pos += 4;
+        let value = BigInt(0);
+        if (flags & CALL_FLAG_VALUE) {
+            value = BigInt('0x' + data.slice(pos, pos + 24));
+            pos += 24;
+        }
+        out.push({ target, flags, value, data: data.slice(pos, pos + length * 2) });
+        data = data.slice(pos + length * 2);
+    }
+    return out;
+};
+
+export const prepareRawCall = (target: string, data: string, opts?: CallOpts): string => {
+    const value = opts?.value ?? BigInt(0);
+    if (value < 0 || value > MAX_CALL_VALUE) {
+        throw new Error("prepareCall(): value must be between 0 and 2**96 - 1");
+    }
+    const flags = ((value > 0) ? CALL_FLAG_VALUE : 0) |
+        (opts?.allowFailure ? CALL_FLAG_ALLOW_FAILURE : 0);
+    return '0x' + encodeCall({ target, flags, value, data: data.replace(/^0x/, '') }, 2);
+};
+
 export const prepareCall = async (
     contract: ethers.Contract,
     funcName: string,
-    args: any[]
+    args: any[],
+    opts?: CallOpts
 ): Promise<string> => {
-    const buf: string[] = [await contract.getAddress()];
-    const encodedData = await contract.interface.encodeFunctionData(funcName, args).slice(2);
-    
-    // Convert length to hex string
-    const lengthHex = (encodedData.length / 2).toString(16).padStart(4, '0');
-    
-    buf.push(lengthHex);
-    buf.push(encodedData);
-    return buf.join('');
+    const encodedData = contract.interface.encodeFunctionData(funcName, args);
+    return prepareRawCall(await contract.getAddress(), encodedData, opts);
 };
 
 export type FeeEntry_t = {
@@ -217,10 +278,284 @@ const hexToBytes = (hex: string): Uint8Array => {
     return array;
 };
 
-export const signCalls = async (signer: Signer, calls: string[], fees: FeeEntry_t[]): Promise<string> => {
-    const csum = (await signer.getAddress()).slice(-6);
-    const block = await signer.provider.getBlock('latest');
-    const ts = block.timestamp.toString(16).padStart(8, '0');
+/// 'eip712' shows the user the fees and calls in their wallet, 'personal' asks them to sign
+/// an opaque hash and is only kept for compatibility. 'erc1271' is for a smart contract wallet,
+/// the same typed data is signed and the wallet's isValidSignature() is asked to accept it.
+export type SignMode = 'personal' | 'eip712' | 'erc1271';
+
+export type TypedDataSigner = (
+    domain: ethers.TypedDataDomain,
+    types: Record<string, ethers.TypedDataField[]>,
+    value: Record<string, any>,
+) => Promise<string>;
+
+export type SignOpts = {
+    mode?: SignMode,
+    /// The dispatcher which will verify an 'eip712' signature, defaults to the deployed one
+    dispatcher?: string,
+    /// For 'erc1271', the address of the smart contract wallet which is the signer
+    wallet?: string,
+    /// For 'erc1271', produce a signature which the wallet accepts, defaults to the signer
+    /// signing the typed data directly
+    signTypedData?: TypedDataSigner,
+    /// Binary format version, 2 has a 64 bit timestamp but dispatchers deployed before it only
+    /// accept 1. Defaults to 1.
+    format?: 1 | 2,
+    /// Seconds since the epoch, defaults to the timestamp of the latest block
+    creationTime?: number | bigint,
+    /// Gas which must be left when the dispatcher starts on the transaction, so that a pollinator
+    /// cannot make calls run out of gas. Requires format 2.
+    minGas?: number | bigint,
+    /// Data hash of another transaction from the same signer which must run first, see
+    /// signCallsWithHash(). It must also run before that transaction expires, so do not let it
+    /// expire later. Requires format 2.
+    requires?: string,
+    /// Only `pollinator` may dispatch during the first `seconds` after the creation time, so that
+    /// others cannot front-run the pollinator it is sent to. Requires format 2.
+    exclusive?: { pollinator: string, seconds: number },
+    /// Pay the fees in this ERC20 token instead of the base coin, the amounts are then in its
+    /// smallest unit. The signer must approve the dispatcher's feeCollector() to spend it.
+    /// Requires format 2.
+    feeToken?: string,
+};
+
+// The signature version is carried in v: v = 27 + (version << 1) + recovery id
+const SIG_VERSION_EIP712 = 1;
+const SIG_VERSION_ERC1271 = 2;
+
+const EIP712_FEE_TYPES = {
+    Fee: [
+        { name: 'amount', type: 'uint256' },
+        { name: 'afterSeconds', type: 'uint64' },
+    ],
+};
+
+const EIP712_TYPES = {
+    PayAfter: [
+        { name: 'creationTime', type: 'uint64' },
+        { name: 'fees', type: 'Fee[]' },
+        { name: 'calls', type: 'Call[]' },
+    ],
+    Call: [
+        { name: 'target', type: 'address' },
+        { name: 'data', type: 'bytes' },
+    ],
+    ...EIP712_FEE_TYPES,
+};
+
+const EIP712_TYPES_V2 = {
+    PayAfterV2: [
+        { name: 'creationTime', type: 'uint64' },
+        { name: 'flags', type: 'uint32' },
+        { name: 'options', type: 'bytes' },
+        { name: 'fees', type: 'Fee[]' },
+        { name: 'calls', type: 'CallV2[]' },
+    ],
+    CallV2: [
+        { name: 'target', type: 'address' },
+        { name: 'flags', type: 'uint8' },
+        { name: 'value', type: 'uint256' },
+        { name: 'data', type: 'bytes' },
+    ],
+    ...EIP712_FEE_TYPES,
+};
+
+// Header flags, each selects a fixed size option, the options are in order of the flag bits
+const FLAG_MIN_GAS = 1;
+const FLAG_REQUIRES = 2;
+const FLAG_EXCLUSIVE = 4;
+const FLAG_FEE_TOKEN = 8;
+
+// A version 2 header starts with a zero byte, which a version 1 timestamp never does
+const encodeHeader = (format: number, creationTime: bigint, flags: number, options: string): string => {
+    if (format === 1) {
+        if (creationTime >= (BigInt(1) << BigInt(32)) || creationTime < (BigInt(1) << BigInt(24))) {
+            throw new Error("signCalls(): creation time cannot be represented in format 1, use format 2");
+        }
+        return creationTime.toString(16).padStart(8, '0');
+    } else if (format === 2) {
+        return '0002' + creationTime.toString(16).padStart(16, '0') +
+            flags.toString(16).padStart(8, '0') + options;
+    }
+    throw new Error("signCalls(): unsupported format " + format);
+};
+
+type Header = { format: number, creationTime: bigint, flags: number, options: string };
+
+const typedPayAfter = (
+    dispatcher: string,
+    chainId: bigint,
+    header: Header,
+    fees: FeeEntry_t[],
+    data: string,
+) => {
+    const domain = {
+        name: 'PayAfterDispatcher',
+        version: '1',
+        chainId,
+        verifyingContract: dispatcher,
+    };
+    const feeValues = fees.map((f) => ({
+        amount: (f.amt === KILL_FEE) ? ethers.MaxUint256 : f.amtRounded(),
+        afterSeconds: f.timeSeconds(),
+    }));
+    const calls = splitCalls(data, header.format);
+    if (header.format === 1) {
+        const value = {
+            creationTime: header.creationTime,
+            fees: feeValues,
+            calls: calls.map((c) => ({ target: c.target, data: '0x' + c.data })),
+        };
+        return { domain, types: EIP712_TYPES, value };
+    }
+    const value = {
+        creationTime: header.creationTime,
+        flags: header.flags,
+        options: '0x' + header.options,
+        fees: feeValues,
+        calls: calls.map((c) => ({ ...c, data: '0x' + c.data })),
+    };
+    return { domain, types: EIP712_TYPES_V2, value };
+};
+
+// For a smart contract wallet the PayAfter is wrapped in a struct naming the wallet, so that an
+// owner's signature for the wallet cannot be replayed as the owner's own PayAfter or another
+// wallet's
+const typedCalls = (
+    dispatcher: string,
+    chainId: bigint,
+    header: Header,
+    fees: FeeEntry_t[],
+    data: string,
+    wallet?: string,
+) => {
+    const { domain, types, value } = typedPayAfter(dispatcher, chainId, header, fees, data);
+    if (wallet === undefined) {
+        return { domain, types, value };
+    }
+    const inner = (header.format === 1) ? 'PayAfter' : 'PayAfterV2';
+    return {
+        domain,
+        types: {
+            ['Wallet' + inner]: [
+                { name: 'wallet', type: 'address' },
+                { name: 'payAfter', type: inner },
+            ],
+            ...types,
+        },
+        value: { wallet, payAfter: value },
+    };
+};
+
+const signTypedCalls = async (
+    signer: Signer,
+    dispatcher: string,
+    chainId: bigint,
+    header: Header,
+    fees: FeeEntry_t[],
+    data: string,
+): Promise<string> => {
+    const { domain, types, value } = typedCalls(dispatcher, chainId, header, fees, data);
+    const sig = ethers.Signature.from(await signer.signTypedData(domain, types, value));
+    const v = sig.v + (SIG_VERSION_EIP712 << 1);
+    return sig.r + sig.s.slice(2) + v.toString(16);
+};
+
+// Returns the signature field, the wallet's signature must then follow the payload
+const signErc1271Calls = async (
+    sign: TypedDataSigner,
+    wallet: string,
+    dispatcher: string,
+    chainId: bigint,
+    header: Header,
+    fees: FeeEntry_t[],
+    data: string,
+): Promise<{ field: string, walletSig: string }> => {
+    const { domain, types, value } = typedCalls(dispatcher, chainId, header, fees, data, wallet);
+    const walletSig = (await sign(domain, types, value)).replace(/^0x/, '');
+    const sigLen = walletSig.length / 2;
+    if (sigLen > 0xffff) {
+        throw new Error("signCalls(): the wallet signature is too long");
+    }
+    const v = 27 + (SIG_VERSION_ERC1271 << 1);
+    const field = wallet.replace(/^0x/, '').toLowerCase() +
+        sigLen.toString(16).padStart(4, '0') + '00'.repeat(42) + v.toString(16);
+    return { field: '0x' + field, walletSig };
+};
+
+/// Like signCalls() but also returns the data hash which identifies the transaction, as used
+/// by the dispatcher's PayAfter event and the `requires` option.
+export const signCallsWithHash = async (
+    signer: Signer,
+    calls: string[],
+    fees: FeeEntry_t[],
+    opts?: SignOpts
+): Promise<{ signed: string, dataHash: string }> => {
+    if (opts?.mode === 'erc1271' && !opts.wallet) {
+        throw new Error("signCalls(): the 'erc1271' mode requires a wallet address");
+    }
+    const csum = (opts?.mode === 'erc1271') ?
+        opts.wallet.slice(-6).toLowerCase() : (await signer.getAddress()).slice(-6);
+    const header = {
+        format: opts?.format || 1,
+        creationTime: BigInt(opts?.creationTime ?? (await signer.provider.getBlock('latest')).timestamp),
+        flags: 0,
+        options: '',
+    };
+    if (opts?.requires !== undefined) {
+        if (header.format === 1) {
+            throw new Error("signCalls(): requires needs format 2");
+        }
+        if (!/^(0x)?[0-9a-fA-F]{64}$/.test(opts.requires)) {
+            throw new Error("signCalls(): requires must be a 32 byte data hash");
+        }
+    }
+    if (opts?.exclusive !== undefined) {
+        if (header.format === 1) {
+            throw new Error("signCalls(): exclusive needs format 2");
+        }
+        if (!ethers.isAddress(opts.exclusive.pollinator)) {
+            throw new Error("signCalls(): exclusive.pollinator must be an address");
+        }
+        const secs = opts.exclusive.seconds;
+        if (!Number.isInteger(secs) || secs < 0 || secs >= 2 ** 32) {
+            throw new Error("signCalls(): exclusive.seconds must fit in 32 bits");
+        }
+    }
+    if (opts?.feeToken !== undefined) {
+        if (header.format === 1) {
+            throw new Error("signCalls(): feeToken needs format 2");
+        }
+        if (!ethers.isAddress(opts.feeToken)) {
+            throw new Error("signCalls(): feeToken must be an address");
+        }
+    }
+    if (opts?.minGas !== undefined) {
+        const minGas = BigInt(opts.minGas);
+        if (header.format === 1) {
+            throw new Error("signCalls(): minGas requires format 2");
+        }
+        if (minGas < 0 || minGas >= (BigInt(1) << BigInt(32))) {
+            throw new Error("signCalls(): minGas must fit in 32 bits");
+        }
+        header.flags |= FLAG_MIN_GAS;
+        header.options += minGas.toString(16).padStart(8, '0');
+    }
+    // Options are in order of the flag bits
+    if (opts?.requires !== undefined) {
+        header.flags |= FLAG_REQUIRES;
+        header.options += opts.requires.replace(/^0x/, '').toLowerCase();
+    }
+    if (opts?.exclusive !== undefined) {
+        header.flags |= FLAG_EXCLUSIVE;
+        header.options += opts.exclusive.pollinator.replace(/^0x/, '').toLowerCase() +
+            opts.exclusive.seconds.toString(16).padStart(8, '0');
+    }
+    if (opts?.feeToken !== undefined) {
+        header.flags |= FLAG_FEE_TOKEN;
+        header.options += opts.feeToken.replace(/^0x/, '').toLowerCase();
+    }
+    const hdr = encodeHeader(header.format, header.creationTime, header.flags, header.options);
 
     if (fees.length < 1) { 
         throw new Error("signCalls() at least one fee specification is required."); 
@@ -228,18 +563,85 @@ export const signCalls = async (signer: Signer, calls: string[], fees: FeeEntry_
 
     const feeEntries = makeFeeEntries(fees);
 
-    const data = calls.map((c) => c.replace(/^0x/, '')).join('');
+    const data = calls
+        .flatMap((c) => splitCalls(c.replace(/^0x/, ''), 2))
+        .map((c) => encodeCall(c, header.format))
+        .join('');
 
-    const signedData = csum + ts + feeEntries + data;
+    const signedData = csum + hdr + feeEntries + data;
 
     const network = await signer.provider.getNetwork();
+    if (opts?.mode === 'eip712' || opts?.mode === 'erc1271') {
+        const dispatcher = opts.dispatcher || PAYAFTER_DISPATCHER_ADDR;
+        const wallet = (opts.mode === 'erc1271') ? opts.wallet : undefined;
+        const { domain, types, value } =
+            typedCalls(dispatcher, network.chainId, header, fees, data, wallet);
+        const dataHash = ethers.TypedDataEncoder.hash(domain, types, value);
+        if (opts.mode === 'eip712') {
+            const sig = await signTypedCalls(signer, dispatcher, network.chainId, header, fees, data);
+            return { signed: sig + signedData, dataHash };
+        }
+        const sign = opts.signTypedData || signer.signTypedData.bind(signer);
+        const { field, walletSig } = await signErc1271Calls(
+            sign, opts.wallet, dispatcher, network.chainId, header, fees, data);
+        return { signed: field + signedData + walletSig, dataHash };
+    }
     const chainId = network.chainId.toString(16).padStart(64, '0');
 
     const hash = ethers.keccak256('0x' + signedData);
     const hash2 = ethers.keccak256(hash + chainId);
-    const signature = await signer.signMessage(hexToBytes(hash2.slice(2)));
+    const message = hexToBytes(hash2.slice(2));
+    const signature = await signer.signMessage(message);
 
-    return signature + signedData;
+    return { signed: signature + signedData, dataHash: ethers.hashMessage(message) };
+};
+
+export const signCalls = async (
+    signer: Signer,
+    calls: string[],
+    fees: FeeEntry_t[],
+    opts?: SignOpts
+): Promise<string> => (await signCallsWithHash(signer, calls, fees, opts)).signed;
+
+export type CancelOpts = {
+    /// The dispatcher the transaction was signed for, defaults to the deployed one
+    dispatcher?: string,
+    /// When the transaction was signed in 'erc1271' mode, the address of the smart contract
+    /// wallet, and optionally how to produce a signature which it accepts
+    wallet?: string,
+    signTypedData?: TypedDataSigner,
+};
+
+/// Ask pollinators to stop serving a waiting transaction, by POSTing the result to
+/// /api/v1/cancel. This is best-effort: the transaction stays valid on-chain and any pollinator
+/// which has not heard of the cancellation can still run it. Only the dispatcher's
+/// killTransaction() is binding.
+export const signCancel = async (
+    signer: Signer,
+    dataHash: string,
+    opts?: CancelOpts
+): Promise<{ signer: string, data_hash: string, signature: string }> => {
+    if (!/^(0x)?[0-9a-fA-F]{64}$/.test(dataHash)) {
+        throw new Error("signCancel(): dataHash must be a 32 byte data hash");
+    }
+    const network = await signer.provider.getNetwork();
+    const from = opts?.wallet || await signer.getAddress();
+    const domain = {
+        name: 'PayAfterDispatcher',
+        version: '1',
+        chainId: network.chainId,
+        verifyingContract: opts?.dispatcher || PAYAFTER_DISPATCHER_ADDR,
+    };
+    const types = {
+        PayAfterCancel: [
+            { name: 'signer', type: 'address' },
+            { name: 'dataHash', type: 'bytes32' },
+        ],
+    };
+    const value = { signer: from, dataHash: '0x' + dataHash.replace(/^0x/, '') };
+    const sign = opts?.signTypedData || signer.signTypedData.bind(signer);
+    const signature = await sign(domain, types, value);
+    return { signer: from, data_hash: value.dataHash, signature };
 };
 
 // echo 'estimateGas' | sha256sum
@@ -251,7 +653,8 @@ export const estimateGasCustom = async (
     signer: ethers.Signer,
     calls: string[],
     fees: number | FeeEntry_t[],
-    dispatcher: ethers.Contract
+    dispatcher: ethers.Contract,
+    mode?: SignMode
 ): Promise<bigint> => {
     const fakeSigner = new Wallet(ESTIMATEGAS_PRIVATE_KEY, signer.provider);
     let realFees: FeeEntry_t[];
@@ -273,7 +676,15 @@ export const estimateGasCustom = async (
         realFees = fees as FeeEntry_t[];
     }
 
-    const signed = await signCalls(fakeSigner, calls, realFees);
+    // The typed data is hashed on-chain, so the mode affects the gas used. The gas used by a
+    // wallet's isValidSignature() can't be known so 'erc1271' is estimated like 'eip712'.
+    // Calls with a value or allowFailure can only be signed in format 2
+    const needsV2 = calls.some((c) => splitCalls(c.replace(/^0x/, ''), 2).some((e) => e.flags !== 0));
+    const signed = await signCalls(fakeSigner, calls, realFees, {
+        mode: (mode === 'erc1271') ? 'eip712' : mode,
+        dispatcher: await dispatcher.getAddress(),
+        format: needsV2 ? 2 : 1,
+    });
     return await (dispatcher.connect(signer) as ethers.Contract).dispatch.estimateGas(signed, '0x');
 };
 
@@ -287,6 +698,7 @@ export const estimateGas = async (
     signer: ethers.Signer,
     calls: string[],
     fees: number | FeeEntry_t[],
-    dispatcherAddress?: string
+    dispatcherAddress?: string,
+    mode?: SignMode
 ): Promise<bigint> => await estimateGasCustom(
-    signer, calls, fees, getDispatcher(signer.provider, dispatcherAddress));
\ No newline at end of file
+    signer, calls, fees, getDispatcher(signer.provider, dispatcherAddress), mode);
\ No newline at end of file
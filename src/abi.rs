@@ -14,4 +14,40 @@ alloy::sol!(
     #[sol(rpc)]
     #[sol(all_derives)]
     "contracts/interface/IPeriodicDispatcher.sol"
-);
\ No newline at end of file
+);
+
+alloy::sol!(
+    #[sol(rpc)]
+    #[sol(all_derives)]
+    "contracts/interface/IUniswapV2Helper.sol"
+);
+
+alloy::sol!(
+    #[sol(rpc)]
+    #[sol(all_derives)]
+    interface IERC20 {
+        function transfer(address to, uint256 value) external returns (bool);
+        function approve(address spender, uint256 value) external returns (bool);
+        function transferFrom(address from, address to, uint256 value) external returns (bool);
+        function balanceOf(address account) external view returns (uint256);
+        function decimals() external view returns (uint8);
+    }
+);
+
+alloy::sol!(
+    #[sol(rpc)]
+    #[sol(all_derives)]
+    interface IUniswapV2Pair {
+        function token0() external view returns (address);
+        function token1() external view returns (address);
+        function getReserves() external view returns (uint112 reserve0, uint112 reserve1, uint32 blockTimestampLast);
+    }
+);
+
+alloy::sol!(
+    #[sol(rpc)]
+    #[sol(all_derives)]
+    interface IERC1271 {
+        function isValidSignature(bytes32 hash, bytes signature) external view returns (bytes4 magicValue);
+    }
+);
@@ -0,0 +1,117 @@
+use std::{collections::{HashMap, VecDeque}, fmt, net::SocketAddr, sync::Arc};
+
+use alloy::primitives::B256;
+use eyre::{bail, Report, Result};
+
+use crate::{general::Server, util::now_sec};
+
+/// Bounded memory of transactions we rejected recently, so that resubmitting the same
+/// transaction does not cost any RPC calls.
+pub struct RejectedCache {
+    max_entries: usize,
+    ttl_sec: u64,
+    entries: HashMap<B256, (u64, Vec<String>)>,
+    order: VecDeque<B256>,
+}
+
+impl RejectedCache {
+    pub fn new(max_entries: usize, ttl_sec: u64) -> Self {
+        Self { max_entries, ttl_sec, entries: HashMap::new(), order: VecDeque::new() }
+    }
+
+    pub fn get(&self, data_hash: &B256, now: u64) -> Option<&Vec<String>> {
+        self.entries.get(data_hash)
+            .filter(|(time, _)|time + self.ttl_sec > now)
+            .map(|(_, err)|err)
+    }
+
+    pub fn insert(&mut self, data_hash: B256, now: u64, err: Vec<String>) {
+        if self.max_entries == 0 {
+            return;
+        }
+        if self.entries.insert(data_hash, (now, err)).is_none() {
+            self.order.push_back(data_hash);
+        }
+        while self.order.len() > self.max_entries {
+            if let Some(old) = self.order.pop_front() {
+                self.entries.remove(&old);
+            }
+        }
+    }
+}
+
+/// A reason for refusing a transaction which resubmitting it will not change, such as a policy,
+/// a bad signature or a revert. Only these are remembered in RejectedCache, a failure to reach
+/// the node is not.
+#[derive(Debug)]
+pub struct Rejection(pub String);
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+/// Like bail!() but with a Rejection
+#[macro_export]
+macro_rules! reject {
+    ($($arg:tt)*) => {
+        return Err($crate::antispam::Rejection(format!($($arg)*)).into())
+    };
+}
+
+pub fn is_rejection(e: &Report) -> bool {
+    e.chain().any(|c|c.is::<Rejection>())
+}
+
+/// Count a request against the per-IP rate limit.
+pub async fn check_ip(srv: &Arc<Server>, addr: Option<SocketAddr>) -> Result<()> {
+    let Some(addr) = addr else {
+        return Ok(());
+    };
+    let mut m = srv.m.lock().await;
+    if !m.ip_limiter.check(addr.ip(), now_sec()) {
+        bail!("Too many requests from {}, limit is {} per {} seconds",
+            addr.ip(),
+            srv.cfg.antispam.ip_rate_limit.unwrap_or_default(),
+            srv.cfg.antispam.ip_rate_window_seconds);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::primitives::B256;
+    use eyre::{eyre, Result, WrapErr};
+
+    use super::{is_rejection, RejectedCache};
+
+    #[test]
+    fn test_rejected_cache() {
+        let mut rc = RejectedCache::new(2, 100);
+        let (h1, h2, h3) = (B256::with_last_byte(1), B256::with_last_byte(2), B256::with_last_byte(3));
+        rc.insert(h1, 1000, vec!["one".into()]);
+        rc.insert(h2, 1000, vec!["two".into()]);
+        assert_eq!(rc.get(&h1, 1000), Some(&vec!["one".to_string()]));
+        assert_eq!(rc.get(&h1, 1100), None, "Expired");
+
+        rc.insert(h3, 1000, vec!["three".into()]);
+        assert_eq!(rc.get(&h1, 1000), None, "Oldest entry is dropped");
+        assert!(rc.get(&h2, 1000).is_some());
+        assert!(rc.get(&h3, 1000).is_some());
+    }
+
+    #[test]
+    fn test_is_rejection() {
+        fn rejected() -> Result<()> {
+            crate::reject!("Transaction has expired");
+        }
+        let e = rejected().unwrap_err();
+        assert!(is_rejection(&e));
+        assert_eq!(e.to_string(), "Transaction has expired");
+        assert!(is_rejection(&rejected().wrap_err("discover_txn()").unwrap_err()));
+        assert!(!is_rejection(&eyre!("connection refused")));
+    }
+}
@@ -0,0 +1,64 @@
+use alloy::primitives::U256;
+
+/// Gas paid once per transaction no matter how many items are in it
+pub const BASE_TX_GAS: u64 = 21000;
+
+/// Don't let a bundle grow so big that it risks the block gas limit
+pub const MAX_BUNDLE_ITEMS: usize = 32;
+
+pub struct BundleItem {
+    /// What we are paid for including the item
+    pub value: U256,
+    /// Gas used by the item, not counting the base transaction cost
+    pub gas: u64,
+}
+
+/// Choose which items to put in one transaction so that together they pay for the base
+/// transaction cost plus min_profit. Every item which pays for its own gas adds to the
+/// profit, so the most profitable ones are taken first, up to MAX_BUNDLE_ITEMS.
+/// Returns the indexes of the chosen items, or None if no bundle is profitable.
+pub fn plan_bundle(
+    items: &[BundleItem],
+    base_gas: u64,
+    gas_price: u128,
+    min_profit: U256,
+) -> Option<Vec<usize>> {
+    let gp = U256::from(gas_price);
+    let mut net = items.iter().enumerate()
+        .filter_map(|(i, it)| {
+            let cost = U256::from(it.gas) * gp;
+            (it.value > cost).then(|| (it.value - cost, i))
+        })
+        .collect::<Vec<_>>();
+    net.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+    net.truncate(MAX_BUNDLE_ITEMS);
+
+    let total = net.iter().fold(U256::ZERO, |acc, (n, _)| acc + n);
+    if net.is_empty() || total <= U256::from(base_gas) * gp + min_profit {
+        return None;
+    }
+    Some(net.into_iter().map(|(_, i)| i).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::primitives::U256;
+
+    use super::{plan_bundle, BundleItem};
+
+    fn item(value: u64, gas: u64) -> BundleItem {
+        BundleItem { value: U256::from(value), gas }
+    }
+
+    #[test]
+    fn test_plan_bundle() {
+        // With gas price 1, base cost 100, each item alone would need value > gas + 100
+        let items = [item(150, 100), item(180, 100), item(90, 100), item(130, 100)];
+        assert_eq!(plan_bundle(&items[..1], 100, 1, U256::ZERO), None, "Unprofitable alone");
+        assert_eq!(plan_bundle(&items, 100, 1, U256::ZERO), Some(vec![1, 0, 3]),
+            "Item which doesn't pay its own gas is left out");
+        assert_eq!(plan_bundle(&items, 100, 1, U256::from(59)), Some(vec![1, 0, 3]));
+        assert_eq!(plan_bundle(&items, 100, 1, U256::from(60)), None, "Not enough profit");
+        assert_eq!(plan_bundle(&[], 100, 1, U256::ZERO), None);
+    }
+}
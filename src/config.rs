@@ -1,6 +1,20 @@
-use alloy::primitives::Address;
+use std::{collections::HashMap, net::{IpAddr, Ipv4Addr}, path::PathBuf};
+
+use alloy::primitives::{Address, FixedBytes};
 use serde::Deserialize;
 
+fn default_bind_address() -> Vec<IpAddr> {
+    vec![IpAddr::V4(Ipv4Addr::LOCALHOST)]
+}
+
+fn default_cors_origins() -> Vec<String> {
+    vec!["*".into()]
+}
+
+fn default_gas_margin_percent() -> u64 {
+    20
+}
+
 #[derive(Deserialize,Default)]
 pub struct Config {
     /// List of Periodic smart contracts which we should be watching
@@ -15,8 +29,160 @@ pub struct Config {
     pub chain_id: u32,
     /// Port to bind the webserver
     pub bind_port: u16,
-    /// How often to re-check periodic contracts to see if they qualift for re-running
+    /// Addresses to bind the webserver, there is one listener per address
+    #[serde(default = "default_bind_address")]
+    pub bind_address: Vec<IpAddr>,
+    /// TLS certificate (PEM), if this and tls_key_path are set the API is served over HTTPS
+    #[serde(default)]
+    pub tls_cert_path: Option<PathBuf>,
+    /// TLS private key (PEM)
+    #[serde(default)]
+    pub tls_key_path: Option<PathBuf>,
+    /// Origins which browsers may call the API from, "*" allows any origin
+    #[serde(default = "default_cors_origins")]
+    pub cors_origins: Vec<String>,
+    /// How often to re-check periodic contracts whose nectar does not grow, normally they are
+    /// scheduled for the moment when their nectar will cover the gas cost plus minimum_profit.
+    /// A contract whose check fails is retried after this long, doubling with each failure.
     pub periodic_recheck_seconds: u64,
+    /// Extra gas to send PayAfter dispatches with, as a percentage of the simulated gas. It is
+    /// never less than a transaction's signed minimum gas requires.
+    #[serde(default = "default_gas_margin_percent")]
+    pub gas_margin_percent: u64,
     /// Encryted seed words for wallet
     pub seed: String,
-}
\ No newline at end of file
+    /// Rules deciding which PayAfter transactions we will accept
+    #[serde(default)]
+    pub policy: PolicyConfig,
+    /// Limits protecting the public API from abuse
+    #[serde(default)]
+    pub antispam: AntiSpamConfig,
+    /// Finding Periodic contracts to serve from chain activity
+    #[serde(default)]
+    pub discovery: DiscoveryConfig,
+    /// How to value the ERC20 tokens which some Periodic contracts pay nectar in and which
+    /// PayAfter fees may be paid in, by token address. Contracts paying in a token which is not
+    /// listed are never cycled.
+    #[serde(default)]
+    pub token_prices: HashMap<Address, PriceSource>,
+    /// Other pollinators which PayAfter cancellations we receive are passed on to, by the base
+    /// URL of their API such as https://pollinator.example.com
+    #[serde(default)]
+    pub cancel_peers: Vec<String>,
+}
+
+/// Where to get the value of a token in the base coin
+#[derive(Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PriceSource {
+    /// A fixed price of one whole token, in whole units of the base coin
+    Fixed { price: String },
+    /// The reserves of a UniswapV2 pair of the token and the wrapped base coin, less the
+    /// 0.3% swap fee
+    UniswapV2 { pair: Address },
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct PolicyConfig {
+    /// If not empty, only calls to these contracts are accepted
+    pub allow_targets: Vec<Address>,
+    /// Calls to these contracts are never accepted
+    pub deny_targets: Vec<Address>,
+    /// If not empty, only calls to these function selectors are accepted
+    pub allow_selectors: Vec<FixedBytes<4>>,
+    /// Calls to these function selectors are never accepted
+    pub deny_selectors: Vec<FixedBytes<4>>,
+    /// Maximum number of PayAfters accepted from one signer per signer_rate_window_seconds
+    pub signer_rate_limit: Option<u32>,
+    /// Window for signer_rate_limit
+    pub signer_rate_window_seconds: u64,
+    /// Maximum size of a signed PayAfter in bytes
+    pub max_payload_bytes: Option<usize>,
+    /// Maximum number of calls in a PayAfter
+    pub max_calls: Option<usize>,
+    /// Maximum gas a PayAfter may use
+    pub max_gas: Option<u64>,
+    /// Maximum number of seconds we will hold a PayAfter before it becomes worth running
+    pub max_wait_seconds: Option<u64>,
+    /// ERC20 tokens which PayAfter fees may be paid in, PayAfters with fees in any other token
+    /// are refused. Each needs an entry in token_prices.
+    pub fee_tokens: Vec<Address>,
+}
+
+impl Default for PolicyConfig {
+    fn default() -> Self {
+        Self {
+            allow_targets: Vec::new(),
+            deny_targets: Vec::new(),
+            allow_selectors: Vec::new(),
+            deny_selectors: Vec::new(),
+            signer_rate_limit: None,
+            signer_rate_window_seconds: 60,
+            max_payload_bytes: None,
+            max_calls: None,
+            max_gas: None,
+            max_wait_seconds: None,
+            fee_tokens: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct AntiSpamConfig {
+    /// Maximum number of API requests which make RPC calls, such as PayAfter submissions,
+    /// per IP address per ip_rate_window_seconds
+    pub ip_rate_limit: Option<u32>,
+    /// Window for ip_rate_limit
+    pub ip_rate_window_seconds: u64,
+    /// Maximum size of a request body in bytes
+    pub max_body_bytes: u64,
+    /// Number of rejected transactions to remember
+    pub rejected_cache_size: usize,
+    /// How long to remember a rejected transaction
+    pub rejected_cache_seconds: u64,
+    /// Maximum number of waiting PayAfters, when full the least profitable one is evicted
+    pub max_waiting: Option<usize>,
+}
+impl Default for AntiSpamConfig {
+    fn default() -> Self {
+        Self {
+            ip_rate_limit: None,
+            ip_rate_window_seconds: 60,
+            max_body_bytes: 64 * 1024,
+            rejected_cache_size: 4096,
+            rejected_cache_seconds: 600,
+            max_waiting: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct DiscoveryConfig {
+    /// Look for funded Periodic contracts and add the profitable ones to periodic_contracts
+    pub enabled: bool,
+    /// Block to start looking for events from, when there is no progress in the state file
+    pub from_block: u64,
+    /// Maximum number of blocks to request events for at once
+    pub max_blocks_per_query: u64,
+    /// How long before re-checking a contract which was not worth adding
+    pub retry_seconds: u64,
+    /// If not empty, only these contracts may be added
+    pub allow: Vec<Address>,
+    /// These contracts are never added
+    pub deny: Vec<Address>,
+}
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            from_block: 0,
+            max_blocks_per_query: 10000,
+            retry_seconds: 3600,
+            allow: Vec::new(),
+            deny: Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use std::fmt::Display;
+
+use alloy::primitives::{Address, FixedBytes, U256};
+use alloy_sol_types::SolCall;
+use eyre::{bail, eyre, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::abi::{IPayAfterDispatcher, IUniswapV2Helper, IERC20};
+use crate::fee::FORMAT_V1;
+
+/// The call sends `value` of the base coin, see PayAfterDispatcher.nextCall()
+pub const CALL_FLAG_VALUE: u8 = 1;
+/// A revert of the call is tolerated, the dispatcher emits PayAfterCallFailed and carries on
+pub const CALL_FLAG_ALLOW_FAILURE: u8 = 2;
+pub const KNOWN_CALL_FLAGS: u8 = CALL_FLAG_VALUE | CALL_FLAG_ALLOW_FAILURE;
+const CALL_VALUE_LEN: usize = 12;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DecodedArg {
+    pub name: String,
+    pub value: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DecodedCall {
+    /// The contract which is called
+    pub target: Address,
+    /// The 4 byte function selector, None if the calldata is shorter than 4 bytes
+    pub selector: Option<FixedBytes<4>>,
+    /// Function signature, only present if the ABI is known
+    pub function: Option<String>,
+    /// Decoded arguments, only present if the ABI is known
+    pub args: Option<Vec<DecodedArg>>,
+    /// Amount of base coin sent with the call
+    #[serde(default)]
+    pub value: U256,
+    /// The transaction goes on even if this call reverts
+    #[serde(default)]
+    pub allow_failure: bool,
+}
+
+fn arg(name: &str, value: impl Display) -> DecodedArg {
+    DecodedArg { name: name.to_string(), value: value.to_string() }
+}
+
+/// Decode the arguments of `data` if it is a call to `C`
+fn args_of<C: SolCall>(
+    data: &[u8],
+    f: impl FnOnce(C) -> Vec<DecodedArg>,
+) -> Option<(String, Vec<DecodedArg>)> {
+    if data.get(..4)? != C::SELECTOR {
+        return None;
+    }
+    let call = C::abi_decode(data, true).ok()?;
+    Some((C::SIGNATURE.to_string(), f(call)))
+}
+
+fn decode_known(data: &[u8]) -> Option<(String, Vec<DecodedArg>)> {
+    args_of::<IERC20::transferCall>(data, |c| vec![
+        arg("to", c.to),
+        arg("value", c.value),
+    ])
+    .or_else(|| args_of::<IERC20::approveCall>(data, |c| vec![
+        arg("spender", c.spender),
+        arg("value", c.value),
+    ]))
+    .or_else(|| args_of::<IERC20::transferFromCall>(data, |c| vec![
+        arg("from", c.from),
+        arg("to", c.to),
+        arg("value", c.value),
+    ]))
+    .or_else(|| args_of::<IUniswapV2Helper::coverFeeCall>(data, |c| vec![
+        arg("token", c.token),
+    ]))
+    .or_else(|| args_of::<IUniswapV2Helper::coverFeeWithLimitCall>(data, |c| vec![
+        arg("token", c.token),
+        arg("maxTokens", c.maxTokens),
+    ]))
+    .or_else(|| args_of::<IPayAfterDispatcher::killTransactionCall>(data, |c| vec![
+        arg("signatureHash", c.signatureHash),
+    ]))
+}
+
+pub fn decode_call(target: Address, data: &[u8]) -> DecodedCall {
+    let selector = data.get(..4).map(FixedBytes::<4>::from_slice);
+    let (function, args) = match decode_known(data) {
+        Some((function, args)) => (Some(function), Some(args)),
+        None => (None, None),
+    };
+    DecodedCall { target, selector, function, args, value: U256::ZERO, allow_failure: false }
+}
+
+/// A call entry from the multicall payload
+pub struct RawCall<'a> {
+    pub target: Address,
+    /// Always 0 in format version 1
+    pub flags: u8,
+    pub value: U256,
+    pub data: &'a [u8],
+}
+
+/// Splits the multicall payload which follows the fee entries into calls.
+/// Format 1 is: [contractAddr: 20] [callData length: 2] [callData: variable]
+/// Format 2 is: [contractAddr: 20] [call flags: 1] [callData length: 2]
+///              [value: 12, only with CALL_FLAG_VALUE] [callData: variable]
+/// Like PayAfterDispatcher.dispatchMulti(), trailing data no longer than a call entry header
+/// is ignored.
+pub fn split_calls(mut payload: &[u8], format: u8) -> Result<Vec<RawCall<'_>>> {
+    let header_len = if format == FORMAT_V1 { 22 } else { 23 };
+    let mut out = Vec::new();
+    while payload.len() > header_len {
+        let target = Address::from_slice(&payload[0..20]);
+        let mut rest = &payload[20..];
+        let mut flags = 0;
+        if format != FORMAT_V1 {
+            flags = rest[0];
+            if flags & !KNOWN_CALL_FLAGS != 0 {
+                bail!("Call {} has unsupported flags {flags:#x}", out.len());
+            }
+            rest = &rest[1..];
+        }
+        let length = u16::from_be_bytes([rest[0], rest[1]]) as usize;
+        rest = &rest[2..];
+        let mut value = U256::ZERO;
+        if flags & CALL_FLAG_VALUE != 0 {
+            if rest.len() < CALL_VALUE_LEN {
+                bail!("Call {} overflows the payload", out.len());
+            }
+            value = U256::from_be_slice(&rest[..CALL_VALUE_LEN]);
+            rest = &rest[CALL_VALUE_LEN..];
+        }
+        if rest.len() < length {
+            bail!("Call {} overflows the payload", out.len());
+        }
+        out.push(RawCall { target, flags, value, data: &rest[..length] });
+        payload = &rest[length..];
+    }
+    Ok(out)
+}
+
+/// Appends a call entry, the inverse of split_calls().
+/// CALL_FLAG_VALUE is set according to the value, other flags are taken from call.flags.
+pub fn encode_call(out: &mut Vec<u8>, format: u8, call: &RawCall) -> Result<()> {
+    let length = u16::try_from(call.data.len()).map_err(|_|eyre!("Call data is too long"))?;
+    let mut flags = call.flags & !CALL_FLAG_VALUE;
+    if call.value > U256::ZERO {
+        flags |= CALL_FLAG_VALUE;
+    }
+    out.extend_from_slice(call.target.as_slice());
+    if format == FORMAT_V1 {
+        if call.value > U256::ZERO {
+            bail!("Sending value with a call requires format version 2");
+        }
+        if flags != 0 {
+            bail!("Call flags require format version 2");
+        }
+        out.extend_from_slice(&length.to_be_bytes());
+    } else {
+        if flags & !KNOWN_CALL_FLAGS != 0 {
+            bail!("Unsupported call flags {flags:#x}");
+        }
+        out.push(flags);
+        out.extend_from_slice(&length.to_be_bytes());
+        if flags & CALL_FLAG_VALUE != 0 {
+            if call.value >= U256::from(1) << (CALL_VALUE_LEN * 8) {
+                bail!("Call value is too large");
+            }
+            out.extend_from_slice(&call.value.to_be_bytes::<32>()[32 - CALL_VALUE_LEN..]);
+        }
+    }
+    out.extend_from_slice(call.data);
+    Ok(())
+}
+
+/// Decodes the multicall payload which follows the fee entries
+pub fn decode_calls(payload: &[u8], format: u8) -> Result<Vec<DecodedCall>> {
+    Ok(split_calls(payload, format)?.into_iter()
+        .map(|c|DecodedCall {
+            value: c.value,
+            allow_failure: c.flags & CALL_FLAG_ALLOW_FAILURE != 0,
+            ..decode_call(c.target, c.data)
+        })
+        .collect())
+}
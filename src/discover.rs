@@ -0,0 +1,141 @@
+use std::{collections::{HashMap, HashSet}, sync::Arc, time::Duration};
+
+use alloy::{
+    primitives::{utils::format_ether, Address, U256},
+    providers::Provider,
+    rpc::types::Filter,
+};
+use alloy_sol_types::SolEvent;
+use eyre::{Context, Result};
+
+use crate::{
+    abi::{IPeriodic, IPeriodicDispatcher},
+    general::{gas_price, save_state, Server},
+    generate::PERIODIC_DISPATCHER_ADDR,
+    periodic::{estimate_gas, read_params},
+    price::nectar_to_base,
+    util::now_sec,
+};
+
+/// How often to look for new events
+const POLL_SECONDS: u64 = 30;
+
+/// Find the Periodic contracts which were cycled or funded in a range of blocks
+async fn find_candidates(srv: &Arc<Server>, from: u64, to: u64) -> Result<HashSet<Address>> {
+    let filter = Filter::new()
+        .address(PERIODIC_DISPATCHER_ADDR)
+        .event_signature(vec![
+            IPeriodicDispatcher::PeriodicPollinated::SIGNATURE_HASH,
+            IPeriodicDispatcher::NectarAdded::SIGNATURE_HASH,
+        ])
+        .from_block(from)
+        .to_block(to);
+    let logs = srv.prov.get_logs(&filter).await.context("get_logs()")?;
+    let mut out = HashSet::new();
+    for log in logs {
+        if let Ok(ev) = log.log_decode::<IPeriodicDispatcher::PeriodicPollinated>() {
+            out.insert(ev.inner.data._0);
+        } else if let Ok(ev) = log.log_decode::<IPeriodicDispatcher::NectarAdded>() {
+            out.insert(ev.inner.data.periodicContract);
+        }
+    }
+    Ok(out)
+}
+
+/// Check whether a contract is a funded Periodic using our dispatcher, and is worth cycling.
+/// Returns the reason if it is not.
+async fn evaluate(srv: &Arc<Server>, addr: Address) -> Result<Option<String>> {
+    let ip = IPeriodic::new(addr, srv.prov.clone());
+    let dispatcher = match ip.periodicDispatcher().call().await {
+        Ok(r) => r._0,
+        Err(_) => return Ok(Some("periodicDispatcher() failed".into())),
+    };
+    if dispatcher != PERIODIC_DISPATCHER_ADDR {
+        return Ok(Some(format!("uses dispatcher {dispatcher}")));
+    }
+
+    let disp = IPeriodicDispatcher::new(PERIODIC_DISPATCHER_ADDR, srv.prov.clone());
+    let balance = disp.balanceOf(addr).call().await.context("balanceOf()")?._0;
+    if balance == U256::ZERO {
+        return Ok(Some("no nectar".into()));
+    }
+
+    let gas = match estimate_gas(srv, &addr).await {
+        Ok(gas) => gas,
+        Err(e) => return Ok(Some(format!("dispatch() fails: {e}"))),
+    };
+    // Cycling at the target rate pays currentPayPerPeriod each time
+    let params = match read_params(srv, &addr).await {
+        Ok(params) => params,
+        Err(e) => return Ok(Some(format!("cannot read its parameters: {e}"))),
+    };
+    let pay = match nectar_to_base(srv, params.nectar_token, params.current_pay_per_period).await {
+        Ok(pay) => pay,
+        Err(e) => return Ok(Some(format!("cannot value its nectar: {e}"))),
+    };
+    let cost = U256::from(gas) * U256::from(gas_price(srv).await?);
+    if pay <= cost + srv.minimum_profit {
+        return Ok(Some(format!("pays {} per period but costs {}",
+            format_ether(pay), format_ether(cost))));
+    }
+    Ok(None)
+}
+
+/// Look through one batch of blocks, returns the next block to look at
+async fn discover(srv: &Arc<Server>, checked: &mut HashMap<Address, u64>) -> Result<u64> {
+    let cfg = &srv.cfg.discovery;
+    let latest = srv.prov.get_block_number().await.context("get_block_number()")?;
+    let from = srv.m.lock().await.state.discovery_block.unwrap_or(cfg.from_block);
+    if from > latest {
+        return Ok(from);
+    }
+    let to = latest.min(from + cfg.max_blocks_per_query.max(1) - 1);
+
+    let now = now_sec();
+    for addr in find_candidates(srv, from, to).await? {
+        if srv.m.lock().await.state.periodic_contracts.contains_key(&addr) ||
+            cfg.deny.contains(&addr) ||
+            (!cfg.allow.is_empty() && !cfg.allow.contains(&addr)) ||
+            checked.get(&addr).is_some_and(|t|*t + cfg.retry_seconds > now)
+        {
+            continue;
+        }
+        checked.insert(addr, now);
+        let verdict = evaluate(srv, addr).await;
+        if verdict.is_err() {
+            // Only node failures get here, the batch is retried and so is this contract
+            checked.remove(&addr);
+        }
+        match verdict? {
+            Some(reason) => println!("Discovered Periodic {addr} is not worth serving: {reason}"),
+            None => {
+                println!("Discovered Periodic {addr}, serving it");
+                srv.m.lock().await.state.periodic_contracts.insert(addr, Default::default());
+            }
+        }
+    }
+    srv.m.lock().await.state.discovery_block = Some(to + 1);
+    // So that a restart neither rescans these blocks nor forgets what they turned up
+    save_state(srv).await?;
+    Ok(to + 1)
+}
+
+pub async fn discover_periodics_thread(srv: Arc<Server>) {
+    let mut checked = HashMap::new();
+    loop {
+        let caught_up = match discover(&srv, &mut checked).await {
+            Ok(next) => next > srv.prov.get_block_number().await.unwrap_or(0),
+            Err(e) => {
+                println!("Error discovering Periodics: {e}");
+                for ee in e.chain() {
+                    println!("  - {ee}");
+                }
+                true
+            }
+        };
+        // When catching up on old blocks, go straight on to the next batch
+        if caught_up {
+            tokio::time::sleep(Duration::from_secs(POLL_SECONDS)).await;
+        }
+    }
+}
@@ -1,5 +1,5 @@
-use alloy::primitives::{bytes::Buf, U256};
-use eyre::{bail,Result};
+use alloy::primitives::{bytes::Buf, Address, B256, U256};
+use eyre::{bail,eyre,OptionExt,Result};
 
 // Time unit constants in seconds
 const MINUTE_SEC: u64 = 60;
@@ -87,12 +87,218 @@ pub fn unpack_fee(packed: u32) -> (U256, u64) {
     (amt, time)
 }
 
-pub fn get_fees(mut buffer: impl Buf) -> Result<(u64, Vec<(U256, u64)>)> {
+/// Packs a fee entry, the inverse of unpack_fee() except that the amount is rounded down to
+/// what can be represented. The time must be a whole number of one of the time units,
+/// U256::MAX packs as the kill fee. The last entry bit is not set.
+pub fn pack_fee(amount: U256, after_seconds: u64) -> Result<u32> {
+    let packed_fee = if amount == U256::MAX {
+        PACKED_KILL_FEE
+    } else {
+        if amount >= U256::from(1) << 96 {
+            bail!("Unable to represent fees larger than 2**96 - 1");
+        }
+        let mut base = amount;
+        let mut exp = 0u32;
+        while base >= U256::from(1 << FEE_BASE_WIDTH) {
+            base >>= 1;
+            exp += 1;
+        }
+        (exp << FEE_BASE_WIDTH) | base.to::<u32>()
+    };
+    // Zero is always packed as zero
+    let packed_time = if after_seconds == 0 {
+        0
+    } else {
+        let tu = TIME_UNITS.iter()
+            .position(|unit|after_seconds % unit == 0 && after_seconds / unit < (1 << FEE_TIME_WIDTH))
+            .ok_or_else(||eyre!("Cannot represent {after_seconds} seconds as a fee time"))?;
+        ((tu as u32) << FEE_TIME_WIDTH) | (after_seconds / TIME_UNITS[tu]) as u32
+    };
+    Ok((packed_time << PACKED_FEE_WIDTH) | packed_fee)
+}
+
+pub const FORMAT_V1: u8 = 1;
+pub const FORMAT_V2: u8 = 2;
+
+/// The dispatch must start with at least this much gas left, the option is a 32 bit gas amount
+pub const FLAG_MIN_GAS: u32 = 1;
+
+/// The transaction may only run after the same signer's transaction with this data hash, the
+/// option is the 32 byte data hash
+pub const FLAG_REQUIRES: u32 = 2;
+
+/// Only one pollinator may dispatch until some seconds after the creation time, the option is
+/// the 20 byte pollinator address followed by the 32 bit number of seconds
+pub const FLAG_EXCLUSIVE: u32 = 4;
+
+/// The fees are amounts of an ERC20 token which the dispatcher transfers from the signer to the
+/// pollinator, the option is the 20 byte token address
+pub const FLAG_FEE_TOKEN: u32 = 8;
+
+/// Flags which we understand, see PayAfterDispatcher.KNOWN_FLAGS
+pub const KNOWN_FLAGS: u32 = FLAG_MIN_GAS | FLAG_REQUIRES | FLAG_EXCLUSIVE | FLAG_FEE_TOKEN;
+
+/// Size of the option which goes with a flag
+fn option_len(flag: u32) -> usize {
+    match flag {
+        FLAG_MIN_GAS => 4,
+        FLAG_REQUIRES => 32,
+        FLAG_EXCLUSIVE => 24,
+        FLAG_FEE_TOKEN => 20,
+        _ => 0,
+    }
+}
+
+/// Where the option for `flag` starts, options are in order of the flag bits
+fn option_start(flags: u32, flag: u32) -> usize {
+    (0..32).map(|b|1u32 << b)
+        .take_while(|f|*f < flag)
+        .filter(|f|flags & f != 0)
+        .map(option_len)
+        .sum()
+}
+
+/// The part of a PayAfter between the checksum and the fee entries. Format version 1 is
+/// just a 32 bit timestamp, version 2 is marked by a zero byte followed by the version, a
+/// 64 bit timestamp, flags and the options selected by the flags.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Header {
+    pub format: u8,
+    pub create_time: u64,
+    pub flags: u32,
+    pub options: Vec<u8>,
+}
+impl Header {
+    pub fn v1(create_time: u32) -> Self {
+        Self { format: FORMAT_V1, create_time: create_time as u64, ..Default::default() }
+    }
+    pub fn v2(create_time: u64, flags: u32, options: Vec<u8>) -> Self {
+        Self { format: FORMAT_V2, create_time, flags, options }
+    }
+    /// The option which goes with `flag`, None if the flag is not set
+    pub fn option(&self, flag: u32) -> Option<&[u8]> {
+        if self.flags & flag == 0 {
+            return None;
+        }
+        let start = option_start(self.flags, flag);
+        self.options.get(start..start + option_len(flag))
+    }
+    /// Set `flag` along with its option
+    pub fn set_option(&mut self, flag: u32, value: &[u8]) -> Result<()> {
+        if self.format == FORMAT_V1 {
+            bail!("Header flags require format version 2");
+        }
+        if flag.count_ones() != 1 || flag & !KNOWN_FLAGS != 0 {
+            bail!("Unsupported flag {flag:#x}");
+        }
+        if value.len() != option_len(flag) {
+            bail!("Option for flag {flag:#x} must be {} bytes", option_len(flag));
+        }
+        let start = option_start(self.flags, flag);
+        let end = if self.flags & flag != 0 { start + value.len() } else { start };
+        self.options.splice(start..end, value.iter().copied());
+        self.flags |= flag;
+        Ok(())
+    }
+    /// The gas which the dispatch must start with, see FLAG_MIN_GAS
+    pub fn min_gas(&self) -> Option<u64> {
+        self.option(FLAG_MIN_GAS)
+            .map(|o|u32::from_be_bytes(o.try_into().expect("4 byte option")) as u64)
+    }
+    /// The transaction which must run first, see FLAG_REQUIRES
+    pub fn requires(&self) -> Option<B256> {
+        self.option(FLAG_REQUIRES).map(B256::from_slice)
+    }
+    /// The pollinator which alone may dispatch, and until when, see FLAG_EXCLUSIVE
+    pub fn exclusive(&self) -> Option<(Address, u64)> {
+        self.option(FLAG_EXCLUSIVE).map(|o|{
+            let secs = u32::from_be_bytes(o[20..].try_into().expect("24 byte option"));
+            // get_header() refuses a window which overflows, as the dispatcher does
+            (Address::from_slice(&o[..20]), self.create_time.saturating_add(secs as u64))
+        })
+    }
+    /// The token which the fees are paid in, None for the base coin, see FLAG_FEE_TOKEN
+    pub fn fee_token(&self) -> Option<Address> {
+        self.option(FLAG_FEE_TOKEN).map(Address::from_slice)
+    }
+    /// Let only `pollinator` dispatch for `seconds` after the creation time
+    pub fn set_exclusive(&mut self, pollinator: Address, seconds: u32) -> Result<()> {
+        let mut o = pollinator.to_vec();
+        o.extend_from_slice(&seconds.to_be_bytes());
+        self.set_option(FLAG_EXCLUSIVE, &o)
+    }
+    pub fn encode(&self, out: &mut Vec<u8>) -> Result<()> {
+        match self.format {
+            FORMAT_V1 => {
+                let t = u32::try_from(self.create_time)
+                    .map_err(|_|eyre!("Creation time does not fit in format version 1"))?;
+                if t >> 24 == 0 {
+                    bail!("Creation time is too early for format version 1");
+                }
+                out.extend_from_slice(&t.to_be_bytes());
+            }
+            FORMAT_V2 => {
+                out.extend_from_slice(&[0, FORMAT_V2]);
+                out.extend_from_slice(&self.create_time.to_be_bytes());
+                out.extend_from_slice(&self.flags.to_be_bytes());
+                out.extend_from_slice(&self.options);
+            }
+            f => bail!("Unsupported format version {f}"),
+        }
+        Ok(())
+    }
+}
+
+/// Length of the options selected by `flags`
+fn options_len(flags: u32) -> Result<usize> {
+    if flags & !KNOWN_FLAGS != 0 {
+        bail!("Unsupported flags {flags:#x}");
+    }
+    Ok((0..32).map(|b|1u32 << b)
+        .filter(|f|flags & f != 0)
+        .map(option_len)
+        .sum())
+}
+
+fn get_header(buffer: &mut impl Buf) -> Result<Header> {
+    if buffer.remaining() < 4 {
+        bail!("Buffer overflow");
+    }
+    if buffer.chunk()[0] != 0 {
+        return Ok(Header::v1(buffer.get_u32()));
+    }
+    buffer.advance(1);
+    let format = buffer.get_u8();
+    if format != FORMAT_V2 {
+        bail!("Unsupported format version {format}");
+    }
+    if buffer.remaining() < 8 + 4 {
+        bail!("Buffer overflow");
+    }
+    let create_time = buffer.get_u64();
+    let flags = buffer.get_u32();
+    let len = options_len(flags)?;
+    if buffer.remaining() < len {
+        bail!("Buffer overflow");
+    }
+    let mut options = vec![0; len];
+    buffer.copy_to_slice(&mut options);
+    let header = Header::v2(create_time, flags, options);
+    if let Some(o) = header.option(FLAG_EXCLUSIVE) {
+        let secs = u32::from_be_bytes(o[20..].try_into()?);
+        create_time.checked_add(secs as u64).ok_or_eyre("Exclusive window overflow")?;
+    }
+    Ok(header)
+}
+
+/// Parse the header and the fee entries, each fee is (amount, absolute time)
+pub fn get_fees(mut buffer: impl Buf) -> Result<(Header, Vec<(U256, u64)>)> {
     if buffer.remaining() < 68+4 {
         bail!("Buffer overflow");
     }
     buffer.advance(68); // sig and checksum
-    let t0 = buffer.get_u32() as u64;
+    let header = get_header(&mut buffer)?;
+    let t0 = header.create_time;
     let mut out = Vec::new();
     loop {
         if buffer.remaining() < 4 {
@@ -100,9 +306,10 @@ pub fn get_fees(mut buffer: impl Buf) -> Result<(u64, Vec<(U256, u64)>)> {
         }
         let fee = buffer.get_u32();
         let (fee_unpacked, time) = unpack_fee(fee);
-        out.push((fee_unpacked, time + t0));
+        let time = t0.checked_add(time).ok_or_eyre("Fee time overflow")?;
+        out.push((fee_unpacked, time));
         if (fee >> 31) > 0 {
-            return Ok((t0, out));
+            return Ok((header, out));
         }
     }
 }
@@ -141,4 +348,63 @@ mod tests {
         let hash_bytes = sha256(string.as_bytes());
         assert_eq!(hex::encode(hash_bytes), RES_HASH);
     }
+
+    #[test]
+    fn test_pack_fee() {
+        for (amount, secs) in [(0u64, 0u64), (8191, 25), (1 << 40, 3600 * 20), (12345, 1270)] {
+            let packed = pack_fee(U256::from(amount), secs).unwrap();
+            let (amt, time) = unpack_fee(packed);
+            assert_eq!(time, secs);
+            // Rounded down by less than one part in 2**13
+            assert!(amt <= U256::from(amount) && U256::from(amount) - amt <= U256::from(amount >> 13));
+        }
+        assert_eq!(unpack_fee(pack_fee(U256::MAX, 600).unwrap()), (U256::MAX, 600));
+        assert!(pack_fee(U256::ZERO, 127 * 10 + 1).is_err(), "Not a whole number of any unit");
+        assert!(pack_fee(U256::from(1) << 96, 0).is_err());
+    }
+
+    #[test]
+    fn test_header_options() {
+        let mut h = Header::v2(1_700_000_000, 0, Vec::new());
+        assert_eq!(h.min_gas(), None);
+        h.set_option(FLAG_MIN_GAS, &300_000u32.to_be_bytes()).unwrap();
+        h.set_option(FLAG_MIN_GAS, &200_000u32.to_be_bytes()).unwrap();
+        assert_eq!(h.min_gas(), Some(200_000));
+        let required = B256::repeat_byte(0xab);
+        h.set_option(FLAG_REQUIRES, required.as_slice()).unwrap();
+        assert_eq!(h.options.len(), 4 + 32);
+        assert_eq!(h.min_gas(), Some(200_000));
+        assert_eq!(h.requires(), Some(required));
+        // Set out of order, the options still follow the flag bits
+        let mut h2 = Header::v2(1_700_000_000, 0, Vec::new());
+        h2.set_option(FLAG_REQUIRES, required.as_slice()).unwrap();
+        h2.set_option(FLAG_MIN_GAS, &200_000u32.to_be_bytes()).unwrap();
+        assert_eq!(h2, h);
+        let pollinator = Address::repeat_byte(0x11);
+        h.set_exclusive(pollinator, 300).unwrap();
+        assert_eq!(h.options.len(), 4 + 32 + 24);
+        assert_eq!(h.exclusive(), Some((pollinator, 1_700_000_300)));
+        assert_eq!(h.requires(), Some(required));
+        assert_eq!(h.fee_token(), None);
+        let token = Address::repeat_byte(0x22);
+        h.set_option(FLAG_FEE_TOKEN, token.as_slice()).unwrap();
+        assert_eq!(h.fee_token(), Some(token));
+        assert_eq!(h.exclusive(), Some((pollinator, 1_700_000_300)));
+        assert!(h.set_option(1 << 31, &[]).is_err(), "Unknown flag");
+        assert!(Header::v1(1_700_000_000).set_option(FLAG_MIN_GAS, &[0; 4]).is_err());
+
+        let mut bin = vec![0; 68];
+        h.encode(&mut bin).unwrap();
+        bin.extend_from_slice(&(1u32 << 31).to_be_bytes());
+        let (parsed, fees) = get_fees(&bin[..]).unwrap();
+        assert_eq!(parsed, h);
+        assert_eq!(fees.len(), 1);
+
+        let mut late = Header::v2(u64::MAX - 10, 0, Vec::new());
+        late.set_exclusive(pollinator, 300).unwrap();
+        let mut bin = vec![0; 68];
+        late.encode(&mut bin).unwrap();
+        bin.extend_from_slice(&(1u32 << 31).to_be_bytes());
+        assert!(get_fees(&bin[..]).is_err(), "Exclusive window overflows");
+    }
 }
\ No newline at end of file
@@ -1,4 +1,4 @@
-use std::{collections::HashMap, sync::Arc, time::SystemTime};
+use std::{collections::HashMap, net::IpAddr, sync::Arc, time::SystemTime};
 
 use alloy::{
     network::EthereumWallet,
@@ -14,7 +14,10 @@ use eyre::{Context, Result};
 use serde::{Deserialize, Serialize};
 use tokio::sync::{mpsc, Mutex};
 
-use crate::config::Config;
+use crate::{
+    antispam::RejectedCache, config::Config, decode::DecodedCall, price::Rate,
+    periodic::FundingEntry, ratelimit::RateLimiter, retarget::RetargetParams,
+};
 
 
 pub type MyProvider = FillProvider<
@@ -35,19 +38,89 @@ pub type MyProvider = FillProvider<
     alloy::network::Ethereum
 >;
 
+/// Economics of a Periodic contract, as read from its view functions
 #[derive(Serialize, Deserialize, Default, Clone)]
+pub struct PeriodicParams {
+    pub current_pay_per_period: U256,
+    pub last_cycle_time: u64,
+    #[serde(default)]
+    pub last_retarget_time: u64,
+    pub cycles: u64,
+    pub cycles_per_retarget: u64,
+    pub target_seconds_per_cycle: u64,
+    #[serde(default)]
+    pub retarget: RetargetParams,
+    /// The token nectar is paid in, zero for the base coin
+    #[serde(default)]
+    pub nectar_token: Address,
+}
+impl PeriodicParams {
+    /// The rate at which nectar accrues, this only changes on a retarget
+    pub fn pay_per_second(&self) -> U256 {
+        if self.target_seconds_per_cycle == 0 {
+            return U256::ZERO;
+        }
+        self.current_pay_per_period / U256::from(self.target_seconds_per_cycle)
+    }
+
+    /// Exactly what nectarAvailable() will return at `time`, assuming nobody cycles the
+    /// contract before then.
+    pub fn nectar_at(&self, time: u64) -> U256 {
+        let secs = time.saturating_sub(self.last_cycle_time);
+        U256::from(secs) * self.pay_per_second()
+    }
+
+    /// The first time at which nectarAvailable() will be greater than `amount`,
+    /// None if the contract pays no nectar.
+    pub fn when_nectar_exceeds(&self, amount: U256) -> Option<u64> {
+        let pay_per_second = self.pay_per_second();
+        if pay_per_second == U256::ZERO {
+            return None;
+        }
+        let secs: u64 = (amount / pay_per_second + U256::from(1)).try_into().ok()?;
+        self.last_cycle_time.checked_add(secs)
+    }
+}
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(default)]
 pub struct StatePeriodic {
     pub last_checked_sec: u64,
-    pub last_updated_sec: u64,
     pub last_estimated_gas: u64,
-    pub last_available_nectar: U256,
-    pub nectar_growth_per_sec: U256,
+    /// None until the contract has been read, or after it has been cycled
+    pub params: Option<PeriodicParams>,
+    /// Set when dispatch() cannot pay the nectar, cleared once it can
+    pub underfunded: Option<Underfunded>,
+    /// Counts the cycles and fundings seen in events, what was read before the latest of them
+    /// is stale
+    #[serde(skip)]
+    pub events_seen: u64,
+}
+
+/// A Periodic contract whose nectar is not covered by its balance in the dispatcher, and whose
+/// periodic() does not raise the difference.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Underfunded {
+    pub reason: String,
+    /// Number of checks in a row which found it underfunded
+    pub failures: u32,
+    /// When it will next be checked
+    pub retry_at: u64,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct PayAfterWaiting {
     pub bin: Bytes,
     pub time_to_run: u64,
+    /// The most we can expect to earn from this transaction, used for choosing what to evict
+    #[serde(default)]
+    pub max_profit: U256,
+    /// Gas used when run alone, used for deciding what to batch together
+    #[serde(default)]
+    pub estimated_gas: u64,
+    /// Data hash of the transaction which must run before this one can be simulated
+    #[serde(default)]
+    pub requires: Option<B256>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -55,6 +128,8 @@ pub enum PayAfterTxnStatus {
     Waiting(PayAfterWaiting),
     Error(Vec<String>),
     Success(B256),
+    /// The signer asked us not to run it, see payafter::cancel_txn()
+    Cancelled,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -63,6 +138,8 @@ pub struct PayAfterTxn {
     pub data_hash: B256,
     pub insert_time: u64,
     pub create_time: u64,
+    #[serde(default)]
+    pub calls: Vec<DecodedCall>,
     pub status: PayAfterTxnStatus,
 }
 
@@ -70,13 +147,23 @@ pub struct PayAfterTxn {
 pub struct State {
     pub periodic_contracts: HashMap<Address, StatePeriodic>,
     pub payafter: HashMap<B256, PayAfterTxn>,
+    /// The next block to look for Periodic contracts in, when discovery is enabled
+    #[serde(default)]
+    pub discovery_block: Option<u64>,
 }
 
 pub struct ServerMut {
     pub state: State,
     pub gas_price: u128,
     pub gas_price_last_checked: u64,
+    /// Exchange rates of nectar tokens, and when they were read
+    pub token_rates: HashMap<Address, (u64, Rate)>,
     pub send_wakeup: mpsc::Sender<()>,
+    pub signer_limiter: RateLimiter<Address>,
+    pub ip_limiter: RateLimiter<IpAddr>,
+    pub rejected: RejectedCache,
+    /// Funding of the watched Periodic contracts, as last read for the API
+    pub funding: HashMap<Address, FundingEntry>,
 }
 
 pub struct Server {
@@ -88,6 +175,16 @@ pub struct Server {
     pub txn_lock: Mutex<()>,
 }
 
+/// Write the state to state_file, through a temporary file so that a crash never leaves it
+/// half written
+pub async fn save_state(srv: &Arc<Server>) -> Result<()> {
+    let state = serde_json::to_string(&srv.m.lock().await.state)?;
+    let tmp = format!("{}.tmp", srv.cfg.state_file);
+    tokio::fs::write(&tmp, state).await.with_context(||format!("writing {tmp}"))?;
+    tokio::fs::rename(&tmp, &srv.cfg.state_file).await
+        .with_context(||format!("renaming {tmp} to {}", srv.cfg.state_file))
+}
+
 pub async fn gas_price(srv: &Arc<Server>) -> Result<u128> {
     let now = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs();
     {
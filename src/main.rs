@@ -12,6 +12,16 @@ mod general;
 mod fee;
 mod util;
 mod generate;
+mod decode;
+mod antispam;
+mod policy;
+mod ratelimit;
+mod bundle;
+mod discover;
+mod retarget;
+mod periodic_sim;
+mod price;
+mod sign;
 
 #[derive(Parser)]
 #[command(name = "pollinated")]
@@ -31,6 +41,21 @@ enum Commands {
         #[arg(value_name = "CONFIG_PATH")]
         config_path: PathBuf,
     },
+    /// Models how a Periodic contract's pay converges, to help choose its parameters
+    SimulatePeriodic {
+        /// Path to the scenario file
+        #[arg(value_name = "SCENARIO_PATH")]
+        scenario_path: PathBuf,
+        /// Write every cycle to this CSV file
+        #[arg(long, value_name = "CSV_PATH")]
+        csv: Option<PathBuf>,
+    },
+    /// Builds and signs a PayAfter transaction, prompting for the private key
+    SignPayafter {
+        /// Path to a file describing the fees and calls
+        #[arg(value_name = "SPEC_PATH")]
+        spec_path: PathBuf,
+    },
 }
 
 #[tokio::main]
@@ -62,9 +87,91 @@ chain_id: 52014
 # Port number to bind webserver
 bind_port: 8080
 
-# How often to re-check periodic contracts to see if they qualify for re-running
+# Addresses to bind webserver, IPv4 or IPv6, one listener per address. Only add "::1" if the
+# host has IPv6 loopback, otherwise serve fails to start.
+bind_address:
+  - "127.0.0.1"
+  # - "::1"
+
+# Serve the API over HTTPS using this certificate and key (PEM format)
+# tls_cert_path: "./cert.pem"
+# tls_key_path: "./key.pem"
+
+# Origins which browsers may call the API from, "*" allows any
+cors_origins:
+  - "*"
+
+# Periodic contracts are checked when their nectar is expected to cover gas plus minimum_profit,
+# this is how often to re-check those whose nectar does not grow. A failed check is retried
+# after this long, doubling with each failure up to an hour.
 periodic_recheck_seconds: 60
 
+# Send PayAfter dispatches with this much more gas than simulated, in percent. Never less than
+# the transaction's signed minimum gas requires.
+gas_margin_percent: 20
+
+# Rules for which PayAfter transactions will be accepted, everything is optional
+policy:
+  # Only accept calls to these contracts / functions (empty means any)
+  allow_targets: []
+  allow_selectors: []
+  # Never accept calls to these contracts / functions
+  deny_targets: []
+  deny_selectors: []
+  # At most 10 PayAfters per signer per minute
+  signer_rate_limit: 10
+  signer_rate_window_seconds: 60
+  max_payload_bytes: 16384
+  max_calls: 16
+  max_gas: 3000000
+  # Refuse transactions which will not be worth running within a day
+  max_wait_seconds: 86400
+  # ERC20 tokens which PayAfter fees may be paid in, each needs an entry in token_prices
+  fee_tokens: []
+
+# Protection of the public API against abuse
+antispam:
+  # At most 30 PayAfter submissions (and other requests which cost RPC calls) per IP address
+  # per minute
+  ip_rate_limit: 30
+  ip_rate_window_seconds: 60
+  max_body_bytes: 65536
+  # Remember rejected transactions so resubmitting them costs nothing
+  rejected_cache_size: 4096
+  rejected_cache_seconds: 600
+  # When more PayAfters than this are waiting, the least profitable is evicted
+  max_waiting: 10000
+
+# Find funded Periodic contracts from PeriodicPollinated and NectarAdded events
+# and serve the profitable ones as though they were listed in periodic_contracts
+discovery:
+  enabled: false
+  from_block: 0
+  max_blocks_per_query: 10000
+  # Contracts which were not worth adding are checked again after this long
+  retry_seconds: 3600
+  # Only add these contracts (empty means any)
+  allow: []
+  # Never add these contracts
+  deny: []
+
+# How to value the ERC20 tokens which some Periodic contracts pay nectar in, and which
+# PayAfter fees in policy.fee_tokens are paid in.
+# Contracts paying in a token which is not listed here are never cycled.
+token_prices: {}
+#  "0x0000000000000000000000000000000000000000":
+#    # A fixed price of one whole token, in the base coin
+#    type: fixed
+#    price: "0.0001"
+#  "0x0000000000000000000000000000000000000000":
+#    # Read from the reserves of a UniswapV2 pair of the token and the wrapped base coin
+#    type: uniswap_v2
+#    pair: "0x0000000000000000000000000000000000000000"
+
+# Other pollinators which PayAfter cancellations signed by users are passed on to, by API base URL
+cancel_peers: []
+#  - "https://pollinator.example.com"
+
 # Encrypted seed words for wallet, by default these are randomly generated
 # But when you start the server, you will be prompted for a passphrase
 # These words plus your choice of passphrase will decide what actual address
@@ -80,6 +187,12 @@ seed: "{seed}"
                 std::process::exit(1);
             }
         }
+        Commands::SimulatePeriodic { scenario_path, csv } => {
+            periodic_sim::simulate_periodic(scenario_path, csv)?;
+        }
+        Commands::SignPayafter { spec_path } => {
+            sign::sign_payafter_cmd(spec_path)?;
+        }
     }
     Ok(())
 }
\ No newline at end of file
@@ -1,21 +1,29 @@
-use std::{sync::Arc, time::Duration};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
 use alloy::hex;
-use alloy::primitives::{eip191_hash_message, Bytes, PrimitiveSignature};
+use alloy::primitives::{Bytes, PrimitiveSignature, U64};
 use alloy::primitives::keccak256;
 use alloy::{
     primitives::{utils::format_ether, Address, B256, U256},
     providers::Provider,
 };
-use alloy_sol_types::SolValue;
-use eyre::{bail, OptionExt, Result};
+use alloy::rpc::types::{Filter, TransactionRequest};
+use alloy_sol_types::{decode_revert_reason, SolCall, SolEvent, SolInterface, SolValue};
+use eyre::{bail, Context, OptionExt, Result};
+use serde::{Deserialize, Serialize};
 use tokio::select;
 use tokio::sync::mpsc;
 
+use crate::bundle::{plan_bundle, BundleItem, BASE_TX_GAS};
+use crate::decode::{decode_calls, DecodedCall};
 use crate::general::{PayAfterTxn, PayAfterTxnStatus, PayAfterWaiting};
+use crate::policy;
+use crate::price::{base_to_nectar, nectar_to_base};
+use crate::sign::{cancel_hash, parse_signature, signed_data, signed_hash, TxnSignature, SIG_LEN};
+use crate::reject;
 use crate::util::vstr_from_error;
 use crate::{
-    abi::IPayAfterDispatcher,
+    abi::{IPayAfterDispatcher, IERC1271},
     generate::PAYAFTER_DISPATCHER_ADDR,
     config::Config, general::{
         gas_price,
@@ -26,6 +34,20 @@ use crate::{
 
 const TIME_SKEW: u64 = 2;
 
+/// How often to look for waiting transactions which are profitable when run together
+const BATCH_RECHECK_SECONDS: u64 = 30;
+
+/// How often to look for PayAfter events which transactions held by FLAG_REQUIRES wait for
+const REQUIRES_POLL_SECONDS: u64 = 15;
+
+/// Most blocks to ask for PayAfter events at once, polling often keeps each query small but
+/// catching up after downtime needs several
+const REQUIRES_POLL_MAX_BLOCKS: u64 = 1000;
+
+/// How long to hold a transaction waiting for another before checking the chain, in case we
+/// missed the event
+const REQUIRES_RECHECK_SECONDS: u64 = 600;
+
 /// This address substitutes msg.sender for the signer so for us, it's always invalid
 const ESTIMATE_GAS_ADDR: Address =
     Address::new(hex!("0x4f4082f93978CCb77661f797cc36521Af262f6B8"));
@@ -36,15 +58,39 @@ pub struct Transaction {
 
     pub create_time: u64,
 
+    /// Binary format version, see fee::Header
+    pub format: u8,
+
+    /// Always 0 in format version 1
+    pub flags: u32,
+
+    /// Gas which dispatch0() requires to be left, see fee::FLAG_MIN_GAS
+    pub min_gas: Option<u64>,
+
+    /// Data hash of the signer's transaction which must run first, see fee::FLAG_REQUIRES
+    pub requires: Option<B256>,
+
+    /// The pollinator which alone may dispatch until the time, see fee::FLAG_EXCLUSIVE
+    pub exclusive: Option<(Address, u64)>,
+
+    /// The ERC20 token which the fees are amounts of, None for the base coin
+    pub fee_token: Option<Address>,
+
     /// The hash used for signing the transaction
     pub data_hash: B256,
 
     /// Signer address
     pub signer: Address,
 
+    /// For a smart contract wallet signer, the signature to pass to its isValidSignature()
+    pub erc1271_signature: Option<Bytes>,
+
     pub estimated_gas: Option<u64>,
 
-    pub fees: Vec<(U256, u64)>
+    pub fees: Vec<(U256, u64)>,
+
+    /// The calls which make up the payload
+    pub calls: Vec<DecodedCall>,
 }
 impl Transaction {
     pub fn when_valid(&self) -> u64 {
@@ -59,6 +105,38 @@ impl Transaction {
             .map(|(_,when)|*when)
             .unwrap_or(u64::MAX)
     }
+    /// When `pollinator` may first dispatch, 0 unless the transaction is exclusive to another
+    pub fn when_open_to(&self, pollinator: Address) -> u64 {
+        match self.exclusive {
+            Some((p, until)) if p != pollinator => until,
+            _ => 0,
+        }
+    }
+    /// The largest fee this transaction will ever pay
+    pub fn max_fee(&self) -> U256 {
+        self.fees.iter()
+            .map(|(amt, _)|*amt)
+            .filter(|amt|*amt < U256::MAX)
+            .max()
+            .unwrap_or_default()
+    }
+    /// The fee the dispatcher will require at `time`, computed like
+    /// PayAfterDispatcher.computeRequiredFee(). None if the transaction is not valid then.
+    pub fn fee_at(&self, time: u64) -> Option<U256> {
+        let scale = U256::from(1_000_000_000_000_000_000u64);
+        let i = self.fees.iter().rposition(|(_, t)|*t <= time)?;
+        let (f0, t0) = self.fees[i];
+        if f0 == U256::MAX {
+            return None;
+        }
+        match self.fees.get(i + 1) {
+            Some((f1, t1)) if *f1 < U256::MAX => {
+                let progress = U256::from(time - t0) * scale / U256::from(t1 - t0);
+                Some(f0 + progress * f1.checked_sub(f0)? / scale)
+            }
+            _ => Some(f0),
+        }
+    }
     pub fn when_is_fee_at_least(&self, min_fee: U256) -> Option<u64> {
         for (i, (fee, time)) in self.fees.iter().enumerate() {
             if *fee < min_fee {
@@ -93,25 +171,22 @@ impl Transaction {
 
 pub fn parse_transaction(config: &Config, bin: Bytes) -> Result<Transaction>
 {
-    const SIG_START: usize = 0;
-    const SIG_LEN: usize = 65;
-    const CSUM_START: usize = SIG_START + SIG_LEN;
+    const CSUM_START: usize = SIG_LEN;
     const CSUM_LEN: usize = 3;
 
-    let (signer, data_hash) = {
-        // Extract signature (65 bytes from SIG_START)
-        let signature = PrimitiveSignature::try_from(&bin[SIG_START..SIG_START + SIG_LEN])?;
-
-        // Compute data hash: keccak256 of data after signature, then Ethereum signed message hash
-        let data = &bin[SIG_START + SIG_LEN..];
-        let data_hash = keccak256(data);
-        let data_hash = keccak256((data_hash, U256::from(config.chain_id)).abi_encode());
-        let data_hash = eip191_hash_message(&data_hash);
-        let signer = signature.recover_address_from_prehash(&data_hash)?;
-        (signer, data_hash)
+    let (signer, data_hash, erc1271_signature) = {
+        // The signature version is carried in v
+        let (version, signature) = parse_signature(&bin)?;
+        let data_hash =
+            signed_hash(version, &bin, config.chain_id as u64, PAYAFTER_DISPATCHER_ADDR)?;
+        match signature {
+            TxnSignature::Ecdsa(sig) => (sig.recover_address_from_prehash(&data_hash)?, data_hash, None),
+            // Checked on-chain by check_erc1271()
+            TxnSignature::Erc1271 { signer, signature } => (signer, data_hash, Some(signature)),
+        }
     };
 
-    if &signer.0.0[17..] != &bin[CSUM_START .. CSUM_START+CSUM_LEN] {
+    if bin.len() < CSUM_START + CSUM_LEN || signer.0.0[17..] != bin[CSUM_START .. CSUM_START+CSUM_LEN] {
         bail!("Corrupted signature");
     }
 
@@ -120,47 +195,300 @@ pub fn parse_transaction(config: &Config, bin: Bytes) -> Result<Transaction>
     }
 
     // A kill fee is shown as U256::MAX
-    let (create_time, fees) = crate::fee::get_fees(&bin[..])?;
+    let mut payload = signed_data(&bin)?;
+    let (header, fees) = crate::fee::get_fees(&mut payload)?;
+    let calls = decode_calls(payload, header.format)?;
 
     Ok(Transaction{
-        create_time,
+        create_time: header.create_time,
+        format: header.format,
+        flags: header.flags,
+        min_gas: header.min_gas(),
+        requires: header.requires(),
+        exclusive: header.exclusive(),
+        fee_token: header.fee_token(),
         bin,
         data_hash,
         signer,
+        erc1271_signature,
         estimated_gas: None,
         fees,
+        calls,
     })
 }
 
-async fn is_dead(txn: &Transaction, provider: MyProvider) -> Result<bool> {
+/// The dispatcher's executionBlacklist entry for a transaction: 0 if it has not run, its
+/// expiration if it has, U256::MAX if it was killed
+async fn execution_entry(data_hash: B256, signer: Address, provider: MyProvider) -> Result<U256> {
     let contract = IPayAfterDispatcher::new(PAYAFTER_DISPATCHER_ADDR, provider);
-    let eh = keccak256((&txn.data_hash, &txn.signer).abi_encode());
-    let x: U256 = contract.executionBlacklist(eh).call().await?._0;
-    Ok(x > U256::ZERO)
+    // Same as PayAfterDispatcher.executionHash()
+    let eh = keccak256((signer, data_hash).abi_encode());
+    Ok(contract.executionBlacklist(eh).call().await?._0)
 }
 
-async fn simulate_txn(txn: &Transaction, provider: MyProvider, at_time: u64) -> Result<u64> {
-    let contract =
-        IPayAfterDispatcher::new(PAYAFTER_DISPATCHER_ADDR, provider.clone());
-    let time = at_time.to_be_bytes();
-    let call = contract.dispatch(
-        txn.bin.clone().into(),
-        time.into(),
-    );
-    let mut tx_request = call.into_transaction_request();
-    tx_request.from = Some(Address::ZERO);
-    let gas = provider.estimate_gas(&tx_request).await?;
-    Ok(gas)
+async fn is_dead(txn: &Transaction, provider: MyProvider) -> Result<bool> {
+    Ok(execution_entry(txn.data_hash, txn.signer, provider).await? > U256::ZERO)
+}
+
+/// Ask a smart contract wallet whether it accepts a signature of the hash
+async fn erc1271_accepts(
+    wallet: Address,
+    hash: B256,
+    signature: Bytes,
+    provider: MyProvider,
+) -> Result<bool> {
+    let wallet = IERC1271::new(wallet, provider);
+    let magic = match wallet.isValidSignature(hash, signature).call().await {
+        Ok(r) => r.magicValue.0,
+        // Only a failure to reach the node is an error, a revert or a wallet which is not a
+        // contract at all means the signature is not accepted
+        Err(alloy::contract::Error::TransportError(e)) if e.as_error_resp().is_none() => {
+            return Err(e).context("isValidSignature()");
+        }
+        Err(_) => Default::default(),
+    };
+    Ok(magic == IERC1271::isValidSignatureCall::SELECTOR)
+}
+
+/// Ask a smart contract wallet signer whether it accepts the signature
+async fn check_erc1271(txn: &Transaction, provider: MyProvider) -> Result<()> {
+    let Some(signature) = &txn.erc1271_signature else {
+        return Ok(());
+    };
+    if !erc1271_accepts(txn.signer, txn.data_hash, signature.clone(), provider).await? {
+        reject!("Invalid ERC-1271 signature");
+    }
+    Ok(())
+}
+
+/// State override for one account in eth_call / eth_estimateGas
+#[derive(Serialize, Clone, Debug, Default)]
+struct AccountOverride {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    balance: Option<U256>,
+}
+
+/// Block override for eth_call
+#[derive(Serialize, Clone, Debug, Default)]
+struct BlockOverrides {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    time: Option<U64>,
+}
+
+#[derive(Deserialize, Default, Clone)]
+pub struct SimulateOpts {
+    /// Simulate as though the block timestamp were this, None means now
+    pub at_time: Option<u64>,
+    /// Override the balances of these accounts, typically the signer
+    #[serde(default)]
+    pub balances: HashMap<Address, U256>,
+}
+
+#[derive(Serialize, Default, Clone)]
+pub struct SimulationResult {
+    pub success: bool,
+    /// The block timestamp used for the simulation
+    pub at_time: u64,
+    pub revert_reason: Option<String>,
+    pub gas_used: Option<u64>,
+    /// The fee which would be paid to the pollinator at at_time
+    pub fee: Option<U256>,
+    /// The ERC20 token which the fee is in, None for the base coin
+    pub fee_token: Option<Address>,
+    /// Calls with allow_failure which reverted, None if the node could not tell us
+    pub failed_calls: Option<Vec<FailedCall>>,
+}
+
+/// A call which was allowed to fail, and did
+#[derive(Serialize, Clone, Debug)]
+pub struct FailedCall {
+    /// Position of the call in the transaction
+    pub index: usize,
+    pub reason: String,
+}
+
+/// Request for eth_simulateV1, which unlike eth_call gives us the logs
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct SimulatePayload {
+    block_state_calls: [SimulateBlock; 1],
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct SimulateBlock {
+    block_overrides: BlockOverrides,
+    state_overrides: HashMap<Address, AccountOverride>,
+    calls: [TransactionRequest; 1],
+}
+
+#[derive(Deserialize, Debug)]
+struct SimulatedBlock {
+    calls: Vec<SimulatedCall>,
 }
 
-async fn estimate_gas(txn: &Transaction, provider: MyProvider) -> Result<u64> {
+#[derive(Deserialize, Debug)]
+struct SimulatedCall {
+    #[serde(default)]
+    logs: Vec<SimulatedLog>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SimulatedLog {
+    address: Address,
+    topics: Vec<B256>,
+    data: Bytes,
+}
+
+/// Find out which of the calls that may fail did, from the PayAfterCallFailed events.
+/// Returns None if the node does not support eth_simulateV1.
+async fn failed_calls(
+    srv: &Arc<Server>,
+    call: &TransactionRequest,
+    state: &HashMap<Address, AccountOverride>,
+    block: &BlockOverrides,
+) -> Option<Vec<FailedCall>> {
+    let payload = SimulatePayload{
+        block_state_calls: [SimulateBlock{
+            block_overrides: block.clone(),
+            state_overrides: state.clone(),
+            calls: [call.clone()],
+        }],
+    };
+    let r: Result<Vec<SimulatedBlock>, _> = srv.prov.raw_request(
+        "eth_simulateV1".into(),
+        (payload, "latest"),
+    ).await;
+    let blocks = match r {
+        Ok(blocks) => blocks,
+        Err(e) => {
+            println!("eth_simulateV1() failed, cannot tell which optional calls failed: {e}");
+            return None;
+        }
+    };
+    Some(blocks.iter()
+        .flat_map(|b|&b.calls)
+        .flat_map(|c|&c.logs)
+        .filter(|l|l.address == PAYAFTER_DISPATCHER_ADDR)
+        .filter_map(|l| {
+            IPayAfterDispatcher::PayAfterCallFailed::decode_raw_log(
+                l.topics.iter().copied(), &l.data, true,
+            ).ok()
+        })
+        .map(|ev|FailedCall{ index: ev.index.to(), reason: revert_reason(&ev.reason) })
+        .collect())
+}
+
+fn revert_reason(data: &[u8]) -> String {
+    use IPayAfterDispatcher::IPayAfterDispatcherErrors as E;
+    match E::abi_decode(data, true) {
+        Ok(E::FailedCallNoReason(e)) =>
+            format!("Call to {} function {} failed without a reason", e.contractAddr, e.functionId),
+        Ok(E::FeeNotCovered(e)) =>
+            format!("Fee not covered, dispatcher has {} but needs {}",
+                format_ether(e.balance), format_ether(e.needed)),
+        Ok(E::ValueNotCovered(e)) =>
+            format!("Call value not covered, dispatcher has {} but needs {}",
+                format_ether(e.balance), format_ether(e.needed)),
+        Ok(E::FeeTokenNotCovered(e)) =>
+            format!("Fee of {} in token {} could not be transferred from the signer",
+                e.needed, e.token),
+        Err(_) => decode_revert_reason(data)
+            .unwrap_or_else(|| format!("Unknown revert: 0x{}", hex::encode(data))),
+    }
+}
+
+/// Simulate the transaction using eth_call with block and state overrides, then estimate its gas.
+/// The dispatcher balance is always overridden to zero so that coins which happen to be sitting
+/// in the dispatcher cannot make an unfunded transaction look funded.
+pub async fn simulate_txn(
+    srv: &Arc<Server>,
+    txn: &Transaction,
+    opts: &SimulateOpts,
+) -> Result<SimulationResult> {
+    let now = now_sec();
+    let at_time = opts.at_time.unwrap_or(now).max(now);
+    let mut res = SimulationResult{
+        at_time,
+        fee: txn.fee_at(at_time),
+        fee_token: txn.fee_token,
+        ..Default::default()
+    };
+
+    let mut state = opts.balances.iter()
+        .map(|(addr, bal)|(*addr, AccountOverride{ balance: Some(*bal) }))
+        .collect::<HashMap<_, _>>();
+    state.insert(PAYAFTER_DISPATCHER_ADDR, AccountOverride{ balance: Some(U256::ZERO) });
+    let block = BlockOverrides{
+        time: if at_time > now { Some(U64::from(at_time)) } else { None },
+    };
+
     let contract =
-        IPayAfterDispatcher::new(PAYAFTER_DISPATCHER_ADDR, provider);
-    let gas = contract.dispatch(
+        IPayAfterDispatcher::new(PAYAFTER_DISPATCHER_ADDR, srv.prov.clone());
+    let mut call = contract.dispatch(
         txn.bin.clone().into(),
         [].into(),
-    ).estimate_gas().await?;
-    Ok(gas)
+    ).into_transaction_request();
+    call.from = Some(srv.my_addr);
+    let r: Result<Bytes, _> = srv.prov.raw_request(
+        "eth_call".into(),
+        (call.clone(), "latest", state.clone(), block.clone()),
+    ).await;
+    if let Err(e) = r {
+        let Some(resp) = e.as_error_resp() else {
+            return Err(e).context("eth_call()");
+        };
+        res.revert_reason = Some(match resp.as_revert_data() {
+            Some(data) => revert_reason(&data),
+            None => resp.message.to_string(),
+        });
+        return Ok(res);
+    }
+    res.failed_calls = if txn.calls.iter().any(|c|c.allow_failure) {
+        failed_calls(srv, &call, &state, &block).await
+    } else {
+        Some(Vec::new())
+    };
+
+    // Not all nodes support block overrides in eth_estimateGas, so for a future time we use
+    // the SIMULATE_ADDRESS, which lets us pass the time in the pollinatorData.
+    let estimate = if at_time > now {
+        let mut estimate = contract.dispatch(
+            txn.bin.clone().into(),
+            at_time.to_be_bytes().into(),
+        ).into_transaction_request();
+        estimate.from = Some(Address::ZERO);
+        estimate
+    } else {
+        call
+    };
+    let gas: U64 = srv.prov.raw_request(
+        "eth_estimateGas".into(),
+        (estimate, "latest", state),
+    ).await.context("eth_estimateGas()")?;
+    res.gas_used = Some(gas.to());
+    res.success = true;
+    Ok(res)
+}
+
+/// Intrinsic gas of a transaction carrying `bin` as calldata, not counting the ABI encoding
+fn calldata_gas(bin: &[u8]) -> u64 {
+    bin.iter().map(|b|if *b == 0 { 4 } else { 16 }).sum()
+}
+
+/// The least gas a dispatch of `txn` can succeed with, dispatch0() refuses to run the calls
+/// with less than the signed minimum left.
+fn min_gas_floor(txn: &Transaction) -> u64 {
+    match txn.min_gas {
+        Some(min) => BASE_TX_GAS + calldata_gas(&txn.bin) + min,
+        None => 0,
+    }
+}
+
+/// Gas limit to send a dispatch with: the simulated gas plus gas_margin_percent, but never
+/// below `floor`.
+fn gas_limit(srv: &Server, estimate: u64, floor: u64) -> u64 {
+    estimate.saturating_add(estimate.saturating_mul(srv.cfg.gas_margin_percent) / 100).max(floor)
 }
 
 async fn run_txn(txn: &Transaction, srv: &Arc<Server>) -> Result<B256> {
@@ -174,7 +502,7 @@ async fn run_txn(txn: &Transaction, srv: &Arc<Server>) -> Result<B256> {
     )
     .max_priority_fee_per_gas(gp)
     .max_fee_per_gas(gp)
-    .gas(txn.estimated_gas.ok_or_eyre("missing gas")?)
+    .gas(gas_limit(srv, txn.estimated_gas.ok_or_eyre("missing gas")?, min_gas_floor(txn)))
     .send().await?;
 
     let _l = srv.txn_lock.lock().await;
@@ -186,64 +514,373 @@ async fn run_txn(txn: &Transaction, srv: &Arc<Server>) -> Result<B256> {
     println!("  - TXID {}", txid);
     let recp = tx.get_receipt().await?;
     println!("  - In block {}", recp.block_number.unwrap_or(0));
+    for log in recp.inner.logs() {
+        if let Ok(ev) = log.log_decode::<IPayAfterDispatcher::PayAfterCallFailed>() {
+            println!("  - Optional call {} failed: {}",
+                ev.inner.data.index, revert_reason(&ev.inner.data.reason));
+        }
+    }
+
+    print_profit(srv, bal).await?;
+    if let Some(token) = txn.fee_token {
+        println!("  - Fee paid in token {token}");
+    }
+    Ok(txid)
+}
 
+async fn print_profit(srv: &Arc<Server>, bal: U256) -> Result<()> {
     let bal2 = srv.prov.get_balance(srv.my_addr.clone()).await?;
     if bal2 > bal {
         println!("  - Profit: {}", format_ether(bal2 - bal));
     } else {
         println!("  - Loss: {}", format_ether(bal - bal2));
     }
-    Ok(txid)
+    Ok(())
 }
 
-async fn accept_txn(srv: &Arc<Server>, txn: &Transaction, time_to_run: u64) -> Result<()> {
+async fn set_status(srv: &Arc<Server>, data_hash: B256, status: PayAfterTxnStatus) {
     let mut m = srv.m.lock().await;
+    if let Some(p) = m.state.payafter.get_mut(&data_hash) {
+        p.status = status;
+        p.insert_time = now_sec();
+    }
+}
+
+/// Run the transactions with dispatchBatch() and record which of them succeeded
+async fn run_batch(srv: &Arc<Server>, batch: &[&Transaction], fees: U256) -> Result<()> {
+    let contract =
+        IPayAfterDispatcher::new(PAYAFTER_DISPATCHER_ADDR, srv.prov.clone());
+    let bins = batch.iter().map(|txn|txn.bin.clone()).collect::<Vec<_>>();
+
+    let gp = gas_price(srv).await?;
+    let gas = contract.dispatchBatch(bins.clone(), [].into())
+        .from(srv.my_addr)
+        .estimate_gas().await
+        .context("dispatchBatch().estimate_gas()")?;
+    let cost = U256::from(gas) * U256::from(gp);
+    if fees <= cost + srv.minimum_profit {
+        println!("Batch of {} PayAfters is not profitable, pays {fees} but cost is {cost} required profit: {}",
+            batch.len(), srv.minimum_profit);
+        return Ok(());
+    }
+
+    // dispatchBatchItem() is called with at most 63/64 of the gas which is left
+    let floor = BASE_TX_GAS + batch.iter()
+        .map(|txn|calldata_gas(&txn.bin) + txn.min_gas.map(|g|g * 64 / 63 + 1).unwrap_or(0))
+        .sum::<u64>();
+    let tx = contract.dispatchBatch(bins, [].into())
+        .max_priority_fee_per_gas(gp)
+        .max_fee_per_gas(gp)
+        .gas(gas_limit(srv, gas, floor))
+        .send().await?;
+
+    let _l = srv.txn_lock.lock().await;
+    println!("Trying batch of {} PayAfters", batch.len());
+    let bal = srv.prov.get_balance(srv.my_addr.clone()).await?;
+
+    let tx = tx.with_timeout(Some(Duration::from_secs(60)));
+    let txid = *tx.tx_hash();
+    println!("  - TXID {}", txid);
+    let recp = tx.get_receipt().await?;
+    println!("  - In block {}", recp.block_number.unwrap_or(0));
+
+    let mut failed = HashMap::new();
+    for log in recp.inner.logs() {
+        if let Ok(ev) = log.log_decode::<IPayAfterDispatcher::PayAfterFailed>() {
+            let index: usize = ev.inner.data.index.to();
+            failed.insert(index, revert_reason(&ev.inner.data.reason));
+        }
+    }
+    for (i, txn) in batch.iter().enumerate() {
+        let status = match failed.remove(&i) {
+            Some(reason) => {
+                println!("  - {} failed: {reason}", txn.data_hash);
+                PayAfterTxnStatus::Error(vec![format!("Failed in batch: {reason}")])
+            }
+            None => PayAfterTxnStatus::Success(txid),
+        };
+        set_status(srv, txn.data_hash, status).await;
+    }
+
+    print_profit(srv, bal).await
+}
+
+/// Transactions which don't pay enough to be worth running alone may be worth running
+/// together, because they share the base transaction cost.
+async fn try_batch(srv: &Arc<Server>) -> Result<()> {
+    let now = now_sec() - TIME_SKEW;
+    let waiting = srv.m.lock().await.state.payafter.values()
+        .filter_map(|p|match &p.status {
+            PayAfterTxnStatus::Waiting(w) if w.estimated_gas > 0 =>
+                Some((w.bin.clone(), w.estimated_gas)),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    let mut txns = Vec::new();
+    let mut items = Vec::new();
+    for (bin, gas) in waiting {
+        let Ok(txn) = parse_transaction(&srv.cfg, bin) else {
+            continue;
+        };
+        if txn.when_open_to(srv.my_addr) > now {
+            continue;
+        }
+        let Some(fee) = txn.fee_at(now) else {
+            continue;
+        };
+        let Ok(fee) = fee_in_base(srv, &txn, fee).await else {
+            continue;
+        };
+        items.push(BundleItem { value: fee, gas: gas.saturating_sub(BASE_TX_GAS) });
+        txns.push(txn);
+    }
+    let gp = gas_price(srv).await?;
+    let Some(chosen) = plan_bundle(&items, BASE_TX_GAS, gp, srv.minimum_profit) else {
+        return Ok(());
+    };
+    // A single transaction is run by itself when its time comes
+    if chosen.len() < 2 {
+        return Ok(());
+    }
+
+    // A failing transaction costs gas even though it is skipped, so check them first
+    let mut batch = Vec::new();
+    let mut fees = U256::ZERO;
+    for i in chosen {
+        let txn = &txns[i];
+        let sim = simulate_txn(srv, txn, &SimulateOpts::default()).await?;
+        if sim.success {
+            batch.push(txn);
+            fees += items[i].value;
+        } else {
+            let reason = sim.revert_reason.unwrap_or_default();
+            println!("Dropping {} from batch: {reason}", txn.data_hash);
+            set_status(srv, txn.data_hash, PayAfterTxnStatus::Error(vec![
+                format!("Transaction failed simulation: {reason}"),
+            ])).await;
+        }
+    }
+    if batch.len() < 2 {
+        return Ok(());
+    }
+    run_batch(srv, &batch, fees).await
+}
+
+/// The most the transaction can make us, its largest fee less the gas cost
+async fn max_profit(srv: &Arc<Server>, txn: &Transaction, gas: u64) -> Result<U256> {
+    let cost = U256::from(gas) * U256::from(gas_price(srv).await?);
+    Ok(fee_in_base(srv, txn, txn.max_fee()).await?.saturating_sub(cost))
+}
+
+/// Value of an amount of the transaction's fee in the base coin
+async fn fee_in_base(srv: &Arc<Server>, txn: &Transaction, fee: U256) -> Result<U256> {
+    nectar_to_base(srv, txn.fee_token.unwrap_or_default(), fee).await
+}
+
+async fn accept_txn(
+    srv: &Arc<Server>,
+    txn: &Transaction,
+    time_to_run: u64,
+    max_profit: U256,
+    requires: Option<B256>,
+) -> Result<()> {
+    let mut m = srv.m.lock().await;
+    let existing = m.state.payafter.get(&txn.data_hash);
+    if existing.is_some_and(|p|matches!(p.status, PayAfterTxnStatus::Cancelled)) {
+        reject!("Transaction was cancelled by its signer");
+    }
+    let already_waiting =
+        existing.is_some_and(|p|matches!(p.status, PayAfterTxnStatus::Waiting(_)));
+    if let Some(max_waiting) = srv.cfg.antispam.max_waiting {
+        let waiting = m.state.payafter.values()
+            .filter_map(|p|match &p.status {
+                PayAfterTxnStatus::Waiting(w) => Some((p.data_hash, w.max_profit)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if !already_waiting && waiting.len() >= max_waiting {
+            let Some((evict, evict_profit)) = waiting.into_iter().min_by_key(|(_, p)|*p) else {
+                bail!("Not accepting waiting transactions");
+            };
+            if evict_profit >= max_profit {
+                bail!("Too many waiting transactions and this one is not more profitable than any of them");
+            }
+            println!("Evicting {} (max profit {}) for {} (max profit {})",
+                evict, format_ether(evict_profit), txn.data_hash, format_ether(max_profit));
+            if let Some(p) = m.state.payafter.get_mut(&evict) {
+                p.status = PayAfterTxnStatus::Error(vec![
+                    "Evicted from the waiting queue by a more profitable transaction".into(),
+                ]);
+                p.insert_time = now_sec();
+            }
+        }
+    }
     m.state.payafter.insert(txn.data_hash, PayAfterTxn{
         create_time: txn.create_time,
         signer: txn.signer.clone(),
         data_hash: txn.data_hash,
         insert_time: now_sec(),
-        status: PayAfterTxnStatus::Waiting(PayAfterWaiting { bin: txn.bin.clone(), time_to_run }),
+        calls: txn.calls.clone(),
+        status: PayAfterTxnStatus::Waiting(PayAfterWaiting {
+            bin: txn.bin.clone(),
+            time_to_run,
+            max_profit,
+            estimated_gas: txn.estimated_gas.unwrap_or_default(),
+            requires,
+        }),
     });
     let _ = m.send_wakeup.send(()).await;
     Ok(())
 }
 
+pub enum CancelOutcome {
+    Cancelled,
+    AlreadyCancelled,
+    /// We do not have the transaction
+    Unknown,
+}
+
+/// Stop serving a transaction at the request of its signer. This is only a request to us, the
+/// transaction stays valid on-chain until the signer calls killTransaction().
+pub async fn cancel_txn(
+    srv: &Arc<Server>,
+    signer: Address,
+    data_hash: B256,
+    signature: Bytes,
+) -> Result<CancelOutcome> {
+    // Look before checking the signature, which may cost an RPC call
+    let check_status = |p: &PayAfterTxn| -> Result<Option<CancelOutcome>> {
+        if p.signer != signer {
+            bail!("Transaction {data_hash} was not signed by {signer}");
+        }
+        match &p.status {
+            PayAfterTxnStatus::Cancelled => Ok(Some(CancelOutcome::AlreadyCancelled)),
+            PayAfterTxnStatus::Success(txid) => bail!("Transaction has already run in {txid}"),
+            // A failed transaction is retried if it is resubmitted, so it is cancelled too
+            PayAfterTxnStatus::Waiting(_) | PayAfterTxnStatus::Error(_) => Ok(None),
+        }
+    };
+    match srv.m.lock().await.state.payafter.get(&data_hash) {
+        None => return Ok(CancelOutcome::Unknown),
+        Some(p) => if let Some(outcome) = check_status(p)? {
+            return Ok(outcome);
+        },
+    }
+
+    let hash = cancel_hash(srv.cfg.chain_id as u64, PAYAFTER_DISPATCHER_ADDR, signer, data_hash);
+    let recovered = PrimitiveSignature::try_from(&signature[..]).ok()
+        .and_then(|sig|sig.recover_address_from_prehash(&hash).ok());
+    // The signer may be a smart contract wallet
+    if recovered != Some(signer) &&
+        !erc1271_accepts(signer, hash, signature, srv.prov.clone()).await?
+    {
+        bail!("Invalid cancel signature");
+    }
+
+    let mut m = srv.m.lock().await;
+    let Some(p) = m.state.payafter.get_mut(&data_hash) else {
+        return Ok(CancelOutcome::Unknown);
+    };
+    // It may have run while we were checking
+    if let Some(outcome) = check_status(p)? {
+        return Ok(outcome);
+    }
+    println!("PayAfter {data_hash} cancelled by its signer");
+    p.status = PayAfterTxnStatus::Cancelled;
+    p.insert_time = now_sec();
+    Ok(CancelOutcome::Cancelled)
+}
+
 pub enum DiscoverTxnRes {
     SentTxid(B256),
     WaitUntil(u64),
+    /// Held until the transaction with this data hash has run, rechecked at the time
+    WaitFor(B256, u64),
 }
 
-pub async fn discover_txn(srv: &Arc<Server>, mut txn: Transaction) -> Result<DiscoverTxnRes> {
+/// Errors which resubmitting the transaction will not change are Rejections. A `submitted`
+/// transaction is new from the API, and so counts against its signer's rate limit once its
+/// signature has been checked.
+pub async fn discover_txn(
+    srv: &Arc<Server>,
+    mut txn: Transaction,
+    submitted: bool,
+) -> Result<DiscoverTxnRes> {
     let now = now_sec() - TIME_SKEW;
     if txn.when_expires() <= now {
-        bail!("Transaction has expired");
+        reject!("Transaction has expired");
     }
+    policy::check_txn(&srv.cfg.policy, &txn, now)?;
     let dead = is_dead(&txn, srv.prov.clone()).await?;
     if dead {
-        bail!("Transaction already run or killed");
+        reject!("Transaction already run or killed");
     }
-    let gas = match if txn.when_valid() < now {
-        // Run a gas estimation directly since it's more exact
-        println!("Run estimate_gas on {}", txn.data_hash);
-        estimate_gas(&txn, srv.prov.clone()).await
-    } else {
-        println!("Run simulate_txn on {}", txn.data_hash);
-        simulate_txn(&txn, srv.prov.clone(), txn.when_valid()).await
-    } {
-        Ok(gas) => gas,
-        Err(e) => bail!("Transaction failed simulation: Error: {e}"),
+    check_erc1271(&txn, srv.prov.clone()).await?;
+    // Anyone can claim to be a smart contract wallet, so the signer is only charged now
+    if submitted {
+        policy::check_rate(srv, &txn).await?;
+    }
+    // Once the required transaction has run, its entry is its expiration, after which the entry
+    // may be cleared and this can no longer run
+    let mut required_until = None;
+    if let Some(requires) = txn.requires {
+        let entry = execution_entry(requires, txn.signer, srv.prov.clone()).await?;
+        if entry == U256::MAX {
+            reject!("Required transaction {requires} was killed");
+        }
+        required_until = Some(entry.saturating_to::<u64>());
+        if entry == U256::ZERO {
+            // It cannot be simulated until then, check_payafter_thread() wakes it up when it
+            // sees the PayAfter event, or else we look again after a while
+            let recheck = (now + REQUIRES_RECHECK_SECONDS).min(txn.when_expires());
+            println!("Holding {} until {requires} has run", txn.data_hash);
+            // It cannot be simulated either, so its gas is at least what it is signed to need
+            let gas = BASE_TX_GAS + txn.min_gas.unwrap_or(0);
+            let max_profit = max_profit(srv, &txn, gas).await?;
+            accept_txn(srv, &txn, recheck, max_profit, Some(requires)).await?;
+            return Ok(DiscoverTxnRes::WaitFor(requires, recheck));
+        }
+    }
+    // Another pollinator's exclusive transaction can only be simulated or run by us after its
+    // window, until then we wait to compete for it
+    let open = txn.when_open_to(srv.my_addr);
+    if open >= txn.when_expires() {
+        reject!("Transaction is exclusive to another pollinator until it expires");
+    }
+    if let Some((pollinator, until)) = txn.exclusive {
+        println!("Txn {} is exclusive to {pollinator} until {until}", txn.data_hash);
+    }
+    println!("Run simulate_txn on {}", txn.data_hash);
+    let sim = simulate_txn(srv, &txn, &SimulateOpts{
+        at_time: Some(txn.when_valid().max(open)),
+        ..Default::default()
+    }).await?;
+    let gas = match (sim.success, sim.gas_used) {
+        (true, Some(gas)) => gas,
+        _ => reject!("Transaction failed simulation: {}", sim.revert_reason.unwrap_or_default()),
     };
+    for f in sim.failed_calls.iter().flatten() {
+        println!("Txn {} optional call {} fails: {}", txn.data_hash, f.index, f.reason);
+    }
+    policy::check_gas(&srv.cfg.policy, gas.max(txn.min_gas.unwrap_or(0)))?;
     txn.estimated_gas = Some(gas);
     println!("Txn {} has estimated gas: {}", txn.data_hash, gas);
     let min_payout =
         U256::from(gas) * U256::from(gas_price(srv).await?) + srv.minimum_profit;
-    let time_to_run = match txn.when_is_fee_at_least(min_payout) {
-        Some(t) => t,
+    // The fees may be in a token, min_fee is the amount of it which is worth min_payout
+    let min_fee = base_to_nectar(srv, txn.fee_token.unwrap_or_default(), min_payout).await?;
+    let time_to_run = match txn.when_is_fee_at_least(min_fee) {
+        Some(t) => t.max(open),
         None => bail!("Transaction never pays minimum fee"),
     };
+    policy::check_wait(&srv.cfg.policy, time_to_run, now)?;
+    if required_until.is_some_and(|until|time_to_run >= until) {
+        reject!("Transaction does not pay enough before the transaction it requires expires");
+    }
     println!("Expected min payoout for txn: {} is {}", txn.data_hash, min_payout);
-    accept_txn(srv, &txn, time_to_run).await?;
+    let max_profit = max_profit(srv, &txn, gas).await?;
+    accept_txn(srv, &txn, time_to_run, max_profit, None).await?;
     if time_to_run <= now {
         println!("Running {}", txn.data_hash);
         let txid = run_txn(&txn, srv).await?;
@@ -270,15 +907,67 @@ async fn get_ready_txn(srv: &Arc<Server>) -> (Option<PayAfterTxn>, u64) {
     (None, shortest_time)
 }
 
+/// Look for PayAfter events since the last call and make any transactions which were held
+/// waiting for them ready to be rediscovered. Returns the next block to look at.
+async fn wake_dependents(srv: &Arc<Server>, from: Option<u64>) -> Result<u64> {
+    let latest = srv.prov.get_block_number().await.context("get_block_number()")?;
+    // Anything which ran before we started is picked up by the recheck in discover_txn()
+    let Some(from) = from else { return Ok(latest + 1) };
+    if from > latest {
+        return Ok(from);
+    }
+    let to = latest.min(from + REQUIRES_POLL_MAX_BLOCKS - 1);
+    let filter = Filter::new()
+        .address(PAYAFTER_DISPATCHER_ADDR)
+        .event_signature(IPayAfterDispatcher::PayAfter::SIGNATURE_HASH)
+        .from_block(from)
+        .to_block(to);
+    let logs = srv.prov.get_logs(&filter).await.context("get_logs()")?;
+    let ran = logs.iter()
+        .filter_map(|l|l.log_decode::<IPayAfterDispatcher::PayAfter>().ok())
+        .map(|ev|(ev.inner.data.dataHash, ev.inner.data.signer))
+        .collect::<Vec<_>>();
+    if !ran.is_empty() {
+        let now = now_sec() - TIME_SKEW;
+        let mut m = srv.m.lock().await;
+        for p in m.state.payafter.values_mut() {
+            let PayAfterTxnStatus::Waiting(w) = &mut p.status else { continue };
+            let Some(requires) = w.requires else { continue };
+            if ran.contains(&(requires, p.signer)) {
+                println!("PayAfter {requires} has run, waking {}", p.data_hash);
+                w.time_to_run = now;
+            }
+        }
+    }
+    Ok(to + 1)
+}
+
 pub async fn check_payafter_thread(srv: Arc<Server>, mut recv_wakeup: mpsc::Receiver<()>) {
     // Walk over our list of txns, if there's one which is ready to be run, re-discover it
+    let mut next_batch = 0;
+    let mut next_requires = 0;
+    let mut requires_block = None;
     loop {
+        if next_batch <= now_sec() {
+            if let Err(e) = try_batch(&srv).await {
+                println!("Error batching PayAfters: {e}");
+            }
+            next_batch = now_sec() + BATCH_RECHECK_SECONDS;
+        }
+        if next_requires <= now_sec() {
+            match wake_dependents(&srv, requires_block).await {
+                Ok(next) => requires_block = Some(next),
+                Err(e) => println!("Error looking for PayAfter events: {e}"),
+            }
+            next_requires = now_sec() + REQUIRES_POLL_SECONDS;
+        }
         let (pat, wait_until) = get_ready_txn(&srv).await;
         let now = now_sec() - TIME_SKEW;
         let Some(mut pat) = pat else {
+            let wait_until = wait_until.min(next_batch).min(next_requires);
             select! {
                 _ = recv_wakeup.recv() => {},
-                _ = tokio::time::sleep(Duration::from_secs(wait_until - now)) => {},
+                _ = tokio::time::sleep(Duration::from_secs(wait_until.saturating_sub(now))) => {},
             }
             continue;
         };
@@ -295,12 +984,13 @@ pub async fn check_payafter_thread(srv: Arc<Server>, mut recv_wakeup: mpsc::Rece
             }
         };
         println!("PayAfter: (re)discover_txn {}", txn.data_hash);
-        match discover_txn(&srv, txn).await {
+        match discover_txn(&srv, txn, false).await {
             Ok(DiscoverTxnRes::SentTxid(txid)) => {
                 pat.status = PayAfterTxnStatus::Success(txid);
             }
-            Ok(DiscoverTxnRes::WaitUntil(time)) => {
-                pat.status = PayAfterTxnStatus::Waiting(PayAfterWaiting{ bin: w.bin.clone(), time_to_run: time });
+            Ok(DiscoverTxnRes::WaitUntil(_) | DiscoverTxnRes::WaitFor(..)) => {
+                // accept_txn() has already stored the updated waiting entry
+                continue;
             }
             Err(e) => {
                 println!("Error in stored payafter: {}: {}", pat.data_hash, e);
@@ -309,6 +999,12 @@ pub async fn check_payafter_thread(srv: Arc<Server>, mut recv_wakeup: mpsc::Rece
         }
         pat.insert_time = now;
         let mut m = srv.m.lock().await;
+        let cancelled = m.state.payafter.get(&pat.data_hash)
+            .is_some_and(|p|matches!(p.status, PayAfterTxnStatus::Cancelled));
+        if cancelled && matches!(pat.status, PayAfterTxnStatus::Error(_)) {
+            // Cancelled while we were looking at it
+            continue;
+        }
         m.state.payafter.insert(pat.data_hash, pat);
     }
 }
@@ -317,7 +1013,7 @@ pub async fn check_payafter_thread(srv: Arc<Server>, mut recv_wakeup: mpsc::Rece
 mod tests {
     use alloy::{hex, primitives::{Address, B256, U256}};
 
-    use crate::{config::Config, payafter::{parse_transaction, Transaction}};
+    use crate::{config::Config, fee::FORMAT_V1, payafter::{parse_transaction, Transaction}};
 
     #[test]
     fn test_parse_txn() {
@@ -345,6 +1041,17 @@ mod tests {
 
         // The fee on this txn indicated expiration after 20 hours.
         assert!(txn.when_expires() - txn.when_valid() == 20*60*60);
+
+        // transfer(), approve() then coverFee()
+        let functions = txn.calls.iter().map(|c|c.function.as_deref()).collect::<Vec<_>>();
+        assert_eq!(functions, vec![
+            Some("transfer(address,uint256)"),
+            Some("approve(address,uint256)"),
+            Some("coverFee(address)"),
+        ]);
+        let args = txn.calls[0].args.as_ref().unwrap();
+        assert_eq!(args[0].value, "0xf39Fd6e51aad88F6F4ce6aB8827279cffFb92266");
+        assert_eq!(args[1].value, "50000000000000000000");
     }
 
     #[test]
@@ -356,10 +1063,18 @@ mod tests {
                 (U256::from(300), 3000),
             ],
             create_time: 0,
+            format: FORMAT_V1,
+            flags: 0,
+            min_gas: None,
+            requires: None,
+            exclusive: None,
+            fee_token: None,
             bin: [].into(),
             data_hash: B256::ZERO,
             signer: Address::ZERO,
+            erc1271_signature: None,
             estimated_gas: None,
+            calls: Vec::new(),
         };
 
         // Test case 1: Interpolated time between 100 and 200
@@ -385,4 +1100,33 @@ mod tests {
 
         println!("All tests passed!"); // Optional confirmation
     }
+
+    #[test]
+    fn test_fee_at() {
+        let tx = Transaction {
+            fees: vec![
+                (U256::from(100), 1000),
+                (U256::from(200), 2000),
+                (U256::MAX, 3000),
+            ],
+            create_time: 0,
+            format: FORMAT_V1,
+            flags: 0,
+            min_gas: None,
+            requires: None,
+            exclusive: None,
+            fee_token: None,
+            bin: [].into(),
+            data_hash: B256::ZERO,
+            signer: Address::ZERO,
+            erc1271_signature: None,
+            estimated_gas: None,
+            calls: Vec::new(),
+        };
+        assert_eq!(tx.fee_at(999), None, "Not yet valid");
+        assert_eq!(tx.fee_at(1000), Some(U256::from(100)));
+        assert_eq!(tx.fee_at(1250), Some(U256::from(125)));
+        assert_eq!(tx.fee_at(2500), Some(U256::from(200)), "Last fee before the kill applies");
+        assert_eq!(tx.fee_at(3000), None, "Expired");
+    }
 }
\ No newline at end of file
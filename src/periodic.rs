@@ -1,20 +1,46 @@
-use std::{sync::Arc, time::Duration};
+use std::{collections::{BTreeSet, HashMap, HashSet}, sync::Arc, time::Duration};
 
 use alloy::{
     primitives::{utils::format_ether, Address, U256},
     providers::Provider,
+    rpc::types::Filter,
 };
+use alloy_sol_types::SolEvent;
 use eyre::{Context,Result};
+use serde::Serialize;
+use tokio::{select, sync::mpsc};
 
 use crate::{
     abi::{IPeriodic, IPeriodicDispatcher},
+    bundle::{plan_bundle, BundleItem, BASE_TX_GAS},
+    price::base_to_nectar,
+    retarget::{predict_payouts, RetargetParams},
     generate::PERIODIC_DISPATCHER_ADDR,
     general::{
-        gas_price, Server, StatePeriodic
-    }, util::now_sec,
+        gas_price, PeriodicParams, Server, StatePeriodic, Underfunded
+    }, util::{now_sec, vstr_from_error},
 };
 
-async fn estimate_gas(srv: &Arc<Server>, addr: &Address) -> Result<u64> {
+/// How often to look for PeriodicPollinated events and gas price changes
+const POLL_SECONDS: u64 = 5;
+
+/// Number of future cycles to predict payouts for in the API
+const PREDICTED_CYCLES: usize = 10;
+
+/// Longest time to wait before re-checking an underfunded contract
+const MAX_UNDERFUNDED_BACKOFF_SECONDS: u64 = 60 * 60 * 24;
+
+/// Longest time to wait before re-checking a contract whose check keeps failing
+const MAX_ERROR_BACKOFF_SECONDS: u64 = 60 * 60;
+
+/// Most blocks to request PeriodicDispatcher events for at once, more are left for the next
+/// poll so that catching up after downtime does not exceed what RPC servers allow
+const EVENTS_POLL_MAX_BLOCKS: u64 = 1000;
+
+/// How often the funding which the API reports is read again from the chain
+const FUNDING_REFRESH_SECONDS: u64 = 60;
+
+pub async fn estimate_gas(srv: &Arc<Server>, addr: &Address) -> Result<u64> {
     println!("estimate_gas()");
     let disp =
         IPeriodicDispatcher::new(PERIODIC_DISPATCHER_ADDR, srv.prov.clone());
@@ -22,72 +48,234 @@ async fn estimate_gas(srv: &Arc<Server>, addr: &Address) -> Result<u64> {
         .context("dispatch().estimate_gas()")?)
 }
 
-async fn get_nectar(srv: &Arc<Server>, addr: &Address) -> Result<U256> {
-    println!("nectarAvailable()");
+pub async fn read_params(srv: &Arc<Server>, addr: &Address) -> Result<PeriodicParams> {
+    println!("Reading Periodic parameters of {addr}");
     let ip = IPeriodic::new(addr.clone(), srv.prov.clone());
-    Ok(ip.nectarAvailable().call().await.context("nectarAvailable()")?._0)
+    let rp = ip.retargetParams().call().await.context("retargetParams()")?;
+    Ok(PeriodicParams {
+        current_pay_per_period: ip.currentPayPerPeriod().call().await
+            .context("currentPayPerPeriod()")?._0,
+        last_cycle_time: ip.lastCycleTime().call().await.context("lastCycleTime()")?._0,
+        last_retarget_time: ip.lastRetargetTime().call().await.context("lastRetargetTime()")?._0,
+        cycles: ip.cycles().call().await.context("cycles()")?._0,
+        cycles_per_retarget: ip.cyclesPerRetarget().call().await
+            .context("cyclesPerRetarget()")?._0,
+        target_seconds_per_cycle: ip.targetSecondsPerCycle().call().await
+            .context("targetSecondsPerCycle()")?._0,
+        retarget: RetargetParams {
+            min_factor: rp.minFactor,
+            max_factor: rp.maxFactor,
+            smoothing: rp.smoothing,
+            min_pay: rp.minPay,
+            max_pay: rp.maxPay,
+        },
+        // The dispatcher's record, which the contract cannot change once it has been funded
+        nectar_token: IPeriodicDispatcher::new(PERIODIC_DISPATCHER_ADDR, srv.prov.clone())
+            .nectarTokenOf(*addr).call().await.context("nectarTokenOf()")?._0,
+    })
 }
 
-async fn is_advantageous(srv: &Arc<Server>, nectar: U256, info: &StatePeriodic) -> Result<bool> {
-    let fee = U256::from(info.last_estimated_gas) * U256::from(gas_price(srv).await?);
-    Ok(nectar > fee + srv.minimum_profit)
+async fn get_last_cycle_time(srv: &Arc<Server>, addr: &Address) -> Result<u64> {
+    let ip = IPeriodic::new(addr.clone(), srv.prov.clone());
+    Ok(ip.lastCycleTime().call().await.context("lastCycleTime()")?._0)
 }
 
-async fn check_periodics(srv: &Arc<Server>) -> Result<bool> {
-    let now = now_sec();
-    let Some((addr, mut info)) = ({
-        let m = srv.m.lock().await;
-        let mut out = None;
-        for (addr, info) in &m.state.periodic_contracts {
-            if info.last_checked_sec + srv.cfg.periodic_recheck_seconds < now {
-                out = Some((addr.clone(), info.clone()));
-                break;
-            }
+#[derive(Serialize, Clone)]
+pub struct Funder {
+    pub funder: Address,
+    /// What this funder has deposited, less what was withdrawn back to them
+    pub deposited: U256,
+}
+
+#[derive(Serialize, Clone)]
+pub struct PeriodicFunding {
+    pub address: Address,
+    /// Unused nectar held for the contract by the dispatcher
+    pub balance: U256,
+    pub funders: Vec<Funder>,
+    /// Nectar paid out per second when the contract is cycled at its target rate
+    pub burn_per_second: U256,
+    /// How long the balance will last at that rate, None if the contract pays nothing
+    pub seconds_remaining: Option<u64>,
+    pub params: PeriodicParams,
+    /// Why the contract is not being cycled, if it cannot pay its nectar
+    pub underfunded: Option<Underfunded>,
+    /// How often we would cycle the contract, given its pay, the gas price and minimum_profit
+    pub cycle_every_seconds: Option<u64>,
+    /// Predicted payouts of the next cycles if it is cycled that often, including retargets
+    pub predicted_payouts: Vec<U256>,
+}
+
+/// One entry of the funding of every watched contract
+#[derive(Serialize, Clone)]
+#[serde(untagged)]
+pub enum FundingEntry {
+    Funding(PeriodicFunding),
+    Error { address: Address, error: String },
+}
+
+pub async fn read_funding(srv: &Arc<Server>, addr: Address) -> Result<PeriodicFunding> {
+    let disp =
+        IPeriodicDispatcher::new(PERIODIC_DISPATCHER_ADDR, srv.prov.clone());
+    let balance = disp.balanceOf(addr).call().await.context("balanceOf()")?._0;
+    let mut funders = Vec::new();
+    for funder in disp.fundersOf(addr).call().await.context("fundersOf()")?._0 {
+        let deposited = disp.depositOf(addr, funder).call().await.context("depositOf()")?._0;
+        funders.push(Funder { funder, deposited });
+    }
+    let params = read_params(srv, &addr).await?;
+    let burn_per_second = params.pay_per_second();
+    let seconds_remaining = if burn_per_second > U256::ZERO {
+        Some((balance / burn_per_second).saturating_to())
+    } else {
+        None
+    };
+    let (underfunded, gas) = srv.m.lock().await.state.periodic_contracts.get(&addr)
+        .map(|info|(info.underfunded.clone(), info.last_estimated_gas))
+        .unwrap_or_default();
+    let needed = U256::from(gas) * U256::from(gas_price(srv).await?) + srv.minimum_profit;
+    // Without a price for its token, we do not cycle it at all
+    let needed = base_to_nectar(srv, params.nectar_token, needed).await.ok();
+    let cycle_every_seconds = match needed {
+        Some(needed) if burn_per_second > U256::ZERO =>
+            Some((needed / burn_per_second + U256::from(1)).saturating_to()),
+        _ => None,
+    };
+    let predicted_payouts = cycle_every_seconds
+        .map(|secs|predict_payouts(&params, secs, PREDICTED_CYCLES))
+        .unwrap_or_default();
+    Ok(PeriodicFunding {
+        address: addr,
+        balance,
+        funders,
+        burn_per_second,
+        seconds_remaining,
+        params,
+        underfunded,
+        cycle_every_seconds,
+        predicted_payouts,
+    })
+}
+
+/// Read the funding of every watched contract for the API, so that requests do not each make
+/// a dozen RPC calls per contract
+async fn refresh_funding_thread(srv: Arc<Server>) {
+    loop {
+        let addrs = srv.m.lock().await.state.periodic_contracts.keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        for addr in addrs {
+            let entry = match read_funding(&srv, addr).await {
+                Ok(f) => FundingEntry::Funding(f),
+                Err(e) => FundingEntry::Error { address: addr, error: format!("{e}") },
+            };
+            srv.m.lock().await.funding.insert(addr, entry);
+        }
+        {
+            let mut m = srv.m.lock().await;
+            let m = &mut *m;
+            m.funding.retain(|addr, _|m.state.periodic_contracts.contains_key(addr));
         }
-        out
-    }) else {
-        return Ok(false);
+        tokio::time::sleep(Duration::from_secs(FUNDING_REFRESH_SECONDS)).await;
+    }
+}
+
+/// Decide whether dispatch() will be able to pay the nectar, and estimate its gas.
+/// If the dispatcher holds less than nectarAvailable(), periodic() must raise the rest,
+/// and only the simulation can tell us whether it does.
+async fn check_funding(
+    srv: &Arc<Server>,
+    addr: &Address,
+    info: &mut StatePeriodic,
+    now: u64,
+) -> Result<bool> {
+    let disp =
+        IPeriodicDispatcher::new(PERIODIC_DISPATCHER_ADDR, srv.prov.clone());
+    let ip = IPeriodic::new(addr.clone(), srv.prov.clone());
+    let balance = disp.balanceOf(addr.clone()).call().await.context("balanceOf()")?._0;
+    let shortfall = ip.nectarShortfall().call().await.context("nectarShortfall()")?._0;
+    let err = match estimate_gas(srv, addr).await {
+        Ok(gas) => {
+            info.last_estimated_gas = gas;
+            info.underfunded = None;
+            return Ok(true);
+        }
+        Err(e) if shortfall > U256::ZERO => e,
+        Err(e) => return Err(e),
     };
+    let failures = info.underfunded.as_ref().map(|u|u.failures + 1).unwrap_or(1);
+    let backoff = srv.cfg.periodic_recheck_seconds.saturating_mul(1 << failures.min(16))
+        .min(MAX_UNDERFUNDED_BACKOFF_SECONDS);
+    let reason = format!("Dispatcher holds {} but {} more is needed and periodic() does not raise it: {}",
+        format_ether(balance), format_ether(shortfall), vstr_from_error(err).join(": "));
+    println!("Periodic {addr} is underfunded, checking again in {backoff} seconds: {reason}");
+    info.underfunded = Some(Underfunded {
+        reason,
+        failures,
+        retry_at: now + backoff,
+    });
+    Ok(false)
+}
+
+/// The amount of nectar which makes it worth cycling the contract, in the token it pays in.
+/// Fails if it pays in a token which we have no price for.
+async fn nectar_needed(srv: &Arc<Server>, info: &StatePeriodic, gas_price: u128) -> Result<U256> {
+    let needed = U256::from(info.last_estimated_gas) * U256::from(gas_price) + srv.minimum_profit;
+    let token = info.params.as_ref().map(|p|p.nectar_token).unwrap_or_default();
+    base_to_nectar(srv, token, needed).await
+}
+
+async fn is_advantageous(srv: &Arc<Server>, nectar: U256, info: &StatePeriodic) -> Result<bool> {
+    Ok(nectar > nectar_needed(srv, info, gas_price(srv).await?).await?)
+}
 
+/// Check one Periodic contract and cycle it if it's profitable
+async fn check_periodic(srv: &Arc<Server>, addr: Address) -> Result<()> {
+    let now = now_sec();
+    let Some(mut info) = srv.m.lock().await.state.periodic_contracts.get(&addr).cloned() else {
+        return Ok(());
+    };
+    let events_seen = info.events_seen;
     info.last_checked_sec = now;
 
-    let nectar = if info.last_estimated_gas != 0 && info.last_available_nectar != U256::ZERO {
-        if info.nectar_growth_per_sec != U256::ZERO {
-            // We have all of the data, if the fee is not enough, we can skip
-            let secs = now - info.last_updated_sec;
-            info.last_available_nectar + info.nectar_growth_per_sec * U256::from(secs)
-        } else {
-            let nectar = get_nectar(srv, &addr).await?;
-            // This doesn't count as an update
-            info.nectar_growth_per_sec = nectar / U256::from(now - info.last_updated_sec);
-            nectar
+    if info.params.is_none() {
+        if !check_funding(srv, &addr, &mut info, now).await? {
+            store_check(srv, addr, info, events_seen).await;
+            return Ok(());
         }
-    } else {
-        info.last_available_nectar = get_nectar(srv, &addr).await?;
-        info.last_estimated_gas = estimate_gas(srv, &addr).await?;
-        info.last_updated_sec = now;
-        info.nectar_growth_per_sec = U256::ZERO;
-        info.last_available_nectar
-    };
-    
+        info.params = Some(read_params(srv, &addr).await?);
+    }
+    let nectar = info.params.as_ref().map(|p|p.nectar_at(now)).unwrap_or_default();
+
     if !is_advantageous(srv, nectar, &info).await? {
-        println!("Not enough nectar to run {addr} yet, has {nectar} but projected cost is {} required profit: {}",
-            U256::from(info.last_estimated_gas) * U256::from(gas_price(srv).await?), srv.minimum_profit);
-        srv.m.lock().await.state.periodic_contracts.insert(addr, info);
-        return Ok(true);
-    }
-
-    if nectar != info.last_available_nectar {
-        info.last_estimated_gas = estimate_gas(srv, &addr).await?;
-        info.last_available_nectar = get_nectar(srv, &addr).await?;
-        info.last_updated_sec = now;
-        info.nectar_growth_per_sec = U256::ZERO;
-        if !is_advantageous(srv, info.last_available_nectar, &info).await? {
-            srv.m.lock().await.state.periodic_contracts.insert(addr, info);
-            return Ok(true);
-        }
+        println!("Not enough nectar to run {addr} yet, has {nectar} but needs more than {} (cost plus required profit)",
+            nectar_needed(srv, &info, gas_price(srv).await?).await?);
+        store_check(srv, addr, info, events_seen).await;
+        return Ok(());
     }
 
+    // The scheduler learns about other pollinators through events, which lag a little,
+    // so make sure nobody has cycled the contract in the meantime.
+    let last_cycle_time = info.params.as_ref().map(|p|p.last_cycle_time).unwrap_or_default();
+    if get_last_cycle_time(srv, &addr).await? != last_cycle_time {
+        info.params = None;
+        store_check(srv, addr, info, events_seen).await;
+        return Ok(());
+    }
+
+    // Gas usage may depend on how long it has been since the last cycle,
+    // and the balance may have been spent by someone else cycling it
+    if !check_funding(srv, &addr, &mut info, now).await? ||
+        !is_advantageous(srv, nectar, &info).await?
+    {
+        store_check(srv, addr, info, events_seen).await;
+        return Ok(());
+    }
+
+    // Once we cycle the contract, the parameters need to be re-read
+    info.params = None;
+    store_check(srv, addr, info, events_seen).await;
+
     let _l = srv.txn_lock.lock().await;
     println!("Trying Periodic for {}", addr);
 
@@ -96,36 +284,366 @@ async fn check_periodics(srv: &Arc<Server>) -> Result<bool> {
     let disp =
         IPeriodicDispatcher::new(PERIODIC_DISPATCHER_ADDR, srv.prov.clone());
     let x =
-        disp.dispatch(addr.clone(), info.last_available_nectar).send().await
+        disp.dispatch(addr.clone(), nectar).send().await
             .context("dispatch()")?
             .with_timeout(Some(Duration::from_secs(60)));
     println!("  - TXID {}", x.tx_hash());
     let recp = x.get_receipt().await?;
     println!("  - Landed in block {}", recp.block_number.unwrap_or(0));
 
+    print_profit(srv, bal).await
+}
+
+/// Write back what check_periodic() found, unless poll_events() has since seen the contract
+/// cycled or funded, which makes the params and funding it read stale
+async fn store_check(srv: &Arc<Server>, addr: Address, info: StatePeriodic, events_seen: u64) {
+    let mut m = srv.m.lock().await;
+    let Some(stored) = m.state.periodic_contracts.get_mut(&addr) else {
+        return;
+    };
+    stored.last_checked_sec = info.last_checked_sec;
+    stored.last_estimated_gas = info.last_estimated_gas;
+    if stored.events_seen == events_seen {
+        stored.params = info.params;
+        stored.underfunded = info.underfunded;
+    }
+}
+
+async fn print_profit(srv: &Arc<Server>, bal: U256) -> Result<()> {
     let bal2 = srv.prov.get_balance(srv.my_addr.clone()).await?;
     if bal2 > bal {
         println!("  - Profit: {}", format_ether(bal2 - bal));
     } else {
         println!("  - Loss: {}", format_ether(bal - bal2));
     }
+    Ok(())
+}
+
+/// Cycle a number of Periodic contracts in one transaction using dispatchMany()
+async fn dispatch_bundle(srv: &Arc<Server>, bundle: &[(Address, U256)]) -> Result<()> {
+    let mut addrs = Vec::new();
+    let mut min_nectar = Vec::new();
+    for (addr, nectar) in bundle {
+        let last_cycle_time = srv.m.lock().await.state.periodic_contracts.get(addr)
+            .and_then(|info|info.params.as_ref())
+            .map(|p|p.last_cycle_time);
+        if last_cycle_time == Some(get_last_cycle_time(srv, addr).await?) {
+            addrs.push(*addr);
+            min_nectar.push(*nectar);
+        } else if let Some(info) = srv.m.lock().await.state.periodic_contracts.get_mut(addr) {
+            info.params = None;
+        }
+    }
+    if addrs.len() < 2 {
+        return Ok(());
+    }
 
-    Ok(true)
+    let disp =
+        IPeriodicDispatcher::new(PERIODIC_DISPATCHER_ADDR, srv.prov.clone());
+    let gas = disp.dispatchMany(addrs.clone(), min_nectar.clone()).estimate_gas().await
+        .context("dispatchMany().estimate_gas()")?;
+    let nectar = min_nectar.iter().fold(U256::ZERO, |acc, n|acc + n);
+    let cost = U256::from(gas) * U256::from(gas_price(srv).await?);
+    if nectar <= cost + srv.minimum_profit {
+        println!("Bundle of {} Periodics is not profitable, has {nectar} but cost is {cost} required profit: {}",
+            addrs.len(), srv.minimum_profit);
+        return Ok(());
+    }
+
+    {
+        let mut m = srv.m.lock().await;
+        for addr in &addrs {
+            if let Some(info) = m.state.periodic_contracts.get_mut(addr) {
+                info.params = None;
+            }
+        }
+    }
+
+    let _l = srv.txn_lock.lock().await;
+    println!("Trying bundle of Periodics {:?}", addrs);
+
+    let bal = srv.prov.get_balance(srv.my_addr.clone()).await?;
+    let x =
+        disp.dispatchMany(addrs, min_nectar).send().await
+            .context("dispatchMany()")?
+            .with_timeout(Some(Duration::from_secs(60)));
+    println!("  - TXID {}", x.tx_hash());
+    let recp = x.get_receipt().await?;
+    println!("  - Landed in block {}", recp.block_number.unwrap_or(0));
+
+    print_profit(srv, bal).await
+}
+
+/// Keeps a timer for every watched Periodic contract, set to the moment when its nectar
+/// will exceed the gas cost plus minimum_profit.
+struct Scheduler {
+    srv: Arc<Server>,
+    /// (time, contract), ordered so the first entry is the next one due
+    queue: BTreeSet<(u64, Address)>,
+    scheduled: HashMap<Address, u64>,
+    /// Contracts which are being checked right now, they are rescheduled when done
+    in_flight: HashSet<Address>,
+    gas_price: u128,
+    last_block: Option<u64>,
+    /// Number of checks in a row which failed for each contract
+    failures: HashMap<Address, u32>,
+}
+
+impl Scheduler {
+    fn schedule(&mut self, addr: Address, time: u64) {
+        if let Some(old) = self.scheduled.insert(addr, time) {
+            self.queue.remove(&(old, addr));
+        }
+        self.queue.insert((time, addr));
+    }
+
+    /// Compute when the contract will next be worth cycling
+    async fn reschedule(&mut self, addr: Address) {
+        let now = now_sec();
+        let info = self.srv.m.lock().await.state.periodic_contracts.get(&addr).cloned();
+        let time = match info {
+            None => {
+                if let Some(old) = self.scheduled.remove(&addr) {
+                    self.queue.remove(&(old, addr));
+                }
+                return;
+            }
+            Some(StatePeriodic { underfunded: Some(u), .. }) => u.retry_at,
+            Some(StatePeriodic { params: None, .. }) => now,
+            Some(info) => {
+                let needed = match nectar_needed(&self.srv, &info, self.gas_price).await {
+                    Ok(needed) => needed,
+                    Err(e) => {
+                        println!("Cannot schedule Periodic {addr}: {e}");
+                        U256::MAX
+                    }
+                };
+                info.params.as_ref()
+                    .and_then(|p|p.when_nectar_exceeds(needed))
+                    // If it pays nothing, the pay might be raised by someone else cycling it,
+                    // otherwise there is no reason to check it until then.
+                    .unwrap_or(now + self.srv.cfg.periodic_recheck_seconds)
+            }
+        };
+        self.schedule(addr, time);
+    }
+
+    /// Reschedule every contract, used when the gas price moves
+    async fn reschedule_all(&mut self) {
+        let addrs = self.scheduled.keys().cloned().collect::<Vec<_>>();
+        for addr in addrs {
+            self.reschedule(addr).await;
+        }
+    }
+
+    /// Pick up contracts which were added to the state since we last looked
+    async fn find_new(&mut self) {
+        let addrs = self.srv.m.lock().await.state.periodic_contracts.keys()
+            .filter(|a|!self.scheduled.contains_key(a) && !self.in_flight.contains(a))
+            .cloned()
+            .collect::<Vec<_>>();
+        for addr in addrs {
+            self.reschedule(addr).await;
+        }
+    }
+
+    async fn check_gas_price(&mut self) -> Result<()> {
+        let gp = gas_price(&self.srv).await?;
+        if gp != self.gas_price {
+            println!("Gas price moved from {} to {}, rescheduling Periodics", self.gas_price, gp);
+            self.gas_price = gp;
+            self.reschedule_all().await;
+        }
+        Ok(())
+    }
+
+    /// When someone else cycles a contract, its nectar resets and the pay may be retargeted.
+    /// When a contract is funded, it is no longer underfunded.
+    async fn poll_events(&mut self) -> Result<()> {
+        let latest = self.srv.prov.get_block_number().await.context("get_block_number()")?;
+        let from = self.last_block.map(|b|b + 1).unwrap_or(latest);
+        if from > latest {
+            return Ok(());
+        }
+        let block = latest.min(from + EVENTS_POLL_MAX_BLOCKS - 1);
+        let filter = Filter::new()
+            .address(PERIODIC_DISPATCHER_ADDR)
+            .event_signature(vec![
+                IPeriodicDispatcher::PeriodicPollinated::SIGNATURE_HASH,
+                IPeriodicDispatcher::NectarAdded::SIGNATURE_HASH,
+            ])
+            .from_block(from)
+            .to_block(block);
+        let logs = self.srv.prov.get_logs(&filter).await.context("get_logs()")?;
+        for log in logs {
+            let addr = if let Ok(ev) = log.log_decode::<IPeriodicDispatcher::PeriodicPollinated>() {
+                println!("Periodic {} was cycled, paying {}",
+                    ev.inner.data._0, format_ether(ev.inner.data._1));
+                ev.inner.data._0
+            } else {
+                let ev = log.log_decode::<IPeriodicDispatcher::NectarAdded>()?;
+                println!("Periodic {} was funded with {}",
+                    ev.inner.data.periodicContract, format_ether(ev.inner.data.amount));
+                ev.inner.data.periodicContract
+            };
+            {
+                let mut m = self.srv.m.lock().await;
+                let Some(info) = m.state.periodic_contracts.get_mut(&addr) else {
+                    continue;
+                };
+                info.params = None;
+                info.underfunded = None;
+                info.events_seen += 1;
+            }
+            if !self.in_flight.contains(&addr) {
+                self.reschedule(addr).await;
+            }
+        }
+        self.last_block = Some(block);
+        Ok(())
+    }
+
+    async fn poll(&mut self) -> Result<()> {
+        self.find_new().await;
+        self.check_gas_price().await?;
+        self.poll_events().await
+    }
+
+    /// Contracts which are each unprofitable alone may be worth cycling together.
+    /// Only contracts paying in the base coin are bundled.
+    async fn try_bundle(&mut self, send_done: &mpsc::Sender<(Address, bool)>) {
+        let now = now_sec();
+        let candidates = {
+            let m = self.srv.m.lock().await;
+            self.scheduled.keys()
+                .filter_map(|addr| {
+                    let info = m.state.periodic_contracts.get(addr)
+                        .filter(|info|info.underfunded.is_none())?;
+                    let params = info.params.as_ref()
+                        .filter(|p|p.nectar_token == Address::ZERO)?;
+                    let nectar = params.nectar_at(now);
+                    Some((*addr, nectar, info.last_estimated_gas))
+                })
+                .collect::<Vec<_>>()
+        };
+        let items = candidates.iter()
+            .map(|(_, value, gas)|BundleItem { value: *value, gas: gas.saturating_sub(BASE_TX_GAS) })
+            .collect::<Vec<_>>();
+        let Some(chosen) =
+            plan_bundle(&items, BASE_TX_GAS, self.gas_price, self.srv.minimum_profit) else {
+            return;
+        };
+        // A single contract will be dispatched by its own timer
+        if chosen.len() < 2 {
+            return;
+        }
+        let bundle = chosen.into_iter()
+            .map(|i|(candidates[i].0, candidates[i].1))
+            .collect::<Vec<_>>();
+        for (addr, _) in &bundle {
+            if let Some(old) = self.scheduled.remove(addr) {
+                self.queue.remove(&(old, *addr));
+            }
+            self.in_flight.insert(*addr);
+        }
+        let srv = Arc::clone(&self.srv);
+        let send_done = send_done.clone();
+        tokio::task::spawn(async move {
+            let ok = match dispatch_bundle(&srv, &bundle).await {
+                Ok(()) => true,
+                Err(e) => {
+                    println!("Error dispatching bundle of Periodics: {e}");
+                    for ee in e.chain() {
+                        println!("  - {ee}");
+                    }
+                    false
+                }
+            };
+            for (addr, _) in bundle {
+                let _ = send_done.send((addr, ok)).await;
+            }
+        });
+    }
+
+    /// Start a check of every contract which is due, they all run concurrently
+    fn run_due(&mut self, send_done: &mpsc::Sender<(Address, bool)>) {
+        let now = now_sec();
+        while let Some(&(time, addr)) = self.queue.first() {
+            if time > now {
+                break;
+            }
+            self.queue.pop_first();
+            self.scheduled.remove(&addr);
+            self.in_flight.insert(addr);
+            let srv = Arc::clone(&self.srv);
+            let send_done = send_done.clone();
+            tokio::task::spawn(async move {
+                let ok = match check_periodic(&srv, addr).await {
+                    Ok(()) => true,
+                    Err(e) => {
+                        println!("Error checking Periodic {addr}: {e}");
+                        for ee in e.chain() {
+                            println!("  - {ee}");
+                        }
+                        false
+                    }
+                };
+                let _ = send_done.send((addr, ok)).await;
+            });
+        }
+    }
 }
 
 pub async fn check_periodics_thread(srv: Arc<Server>) {
+    tokio::task::spawn(refresh_funding_thread(Arc::clone(&srv)));
+    let (send_done, mut recv_done) = mpsc::channel(64);
+    let mut sch = Scheduler {
+        srv,
+        queue: BTreeSet::new(),
+        scheduled: HashMap::new(),
+        in_flight: HashSet::new(),
+        gas_price: 0,
+        last_block: None,
+        failures: HashMap::new(),
+    };
+    let mut next_poll = 0;
+    let mut next_bundle = 0;
     loop {
-        match check_periodics(&srv).await {
-            Err(e) => {
-                println!("Error running check_periodics: {e}");
+        let now = now_sec();
+        if next_poll <= now {
+            if let Err(e) = sch.poll().await {
+                println!("Error polling Periodics: {e}");
                 for ee in e.chain() {
                     println!("  - {ee}");
                 }
-                tokio::time::sleep(Duration::from_secs(60)).await;
             }
-            Ok(true) => { continue; }
-            Ok(false) => {},
+            next_poll = now + POLL_SECONDS;
+        }
+        sch.run_due(&send_done);
+        if next_bundle <= now {
+            sch.try_bundle(&send_done).await;
+            next_bundle = now + sch.srv.cfg.periodic_recheck_seconds;
+        }
+
+        let wake = sch.queue.first().map(|(t, _)|*t).unwrap_or(u64::MAX).min(next_poll);
+        select! {
+            done = recv_done.recv() => {
+                if let Some((addr, ok)) = done {
+                    sch.in_flight.remove(&addr);
+                    if ok {
+                        sch.failures.remove(&addr);
+                        sch.reschedule(addr).await;
+                    } else {
+                        let failures = sch.failures.entry(addr).or_default();
+                        *failures += 1;
+                        let backoff = sch.srv.cfg.periodic_recheck_seconds.max(1)
+                            .saturating_mul(1 << (*failures - 1).min(16))
+                            .min(MAX_ERROR_BACKOFF_SECONDS);
+                        sch.schedule(addr, now_sec() + backoff);
+                    }
+                }
+            }
+            _ = tokio::time::sleep(Duration::from_secs(wake.saturating_sub(now_sec()))) => {}
         }
-        tokio::time::sleep(Duration::from_secs(5)).await;
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,299 @@
+use std::{fmt::Write, path::PathBuf};
+
+use alloy::primitives::{
+    utils::{format_ether, format_units, parse_ether, parse_units},
+    Address, U256,
+};
+use eyre::{bail, Context, Result};
+use serde::Deserialize;
+
+use crate::{
+    general::PeriodicParams,
+    retarget::{post_periodic, RetargetParams},
+};
+
+/// The contract is converged once the average cycle time over a retarget window stays within
+/// this fraction of the target.
+const CONVERGED_TOLERANCE: f64 = 0.1;
+
+fn default_min_factor() -> f64 { 0.5 }
+fn default_max_factor() -> f64 { 2.0 }
+fn default_smoothing() -> f64 { 1.0 }
+
+/// Like BasePeriodic.retargetParams() but with factors as plain numbers
+#[derive(Deserialize)]
+pub struct ScenarioRetarget {
+    #[serde(default = "default_min_factor")]
+    pub min_factor: f64,
+    #[serde(default = "default_max_factor")]
+    pub max_factor: f64,
+    #[serde(default = "default_smoothing")]
+    pub smoothing: f64,
+    /// Floor on pay per period in whole units of the base token
+    #[serde(default)]
+    pub min_pay: Option<String>,
+    /// Ceiling on pay per period in whole units of the base token
+    #[serde(default)]
+    pub max_pay: Option<String>,
+}
+impl Default for ScenarioRetarget {
+    fn default() -> Self {
+        Self {
+            min_factor: default_min_factor(),
+            max_factor: default_max_factor(),
+            smoothing: default_smoothing(),
+            min_pay: None,
+            max_pay: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct GasPricePoint {
+    /// Seconds since the start of the simulation
+    pub at_seconds: u64,
+    pub gwei: String,
+}
+
+#[derive(Deserialize)]
+pub struct SimPollinator {
+    /// Minimum amount of profit in whole units of the base token
+    pub minimum_profit: String,
+    /// Time between the contract becoming profitable and this pollinator's transaction landing
+    #[serde(default)]
+    pub latency_seconds: u64,
+}
+
+#[derive(Deserialize)]
+pub struct Scenario {
+    pub target_seconds_per_cycle: u64,
+    pub cycles_per_retarget: u64,
+    /// In whole units of the base token
+    pub initial_pay_per_period: String,
+    #[serde(default)]
+    pub retarget: ScenarioRetarget,
+    /// How long to simulate
+    pub duration_seconds: u64,
+    /// Gas used by PeriodicDispatcher.dispatch()
+    pub dispatch_gas: u64,
+    /// Gas price over time, each entry applies from at_seconds until the next one
+    pub gas_price: Vec<GasPricePoint>,
+    pub pollinators: Vec<SimPollinator>,
+}
+
+pub struct CycleRow {
+    pub cycle: u64,
+    pub time: u64,
+    pub cycle_seconds: u64,
+    pub nectar: U256,
+    /// Pay per period after the cycle, including any retarget
+    pub pay_per_period: U256,
+    pub gas_price: U256,
+    /// Index of the pollinator which cycled the contract
+    pub pollinator: usize,
+}
+
+pub struct Summary {
+    pub cycles: u64,
+    pub total_nectar: U256,
+    pub final_pay_per_period: U256,
+    /// Average cycle time over the last retarget window
+    pub final_cycle_seconds: Option<f64>,
+    /// When the average cycle time settled within CONVERGED_TOLERANCE of the target
+    pub converged_at: Option<u64>,
+}
+
+fn factor(x: f64) -> U256 {
+    U256::from((x * 1e18) as u128)
+}
+
+fn gas_price_at(prices: &[(u64, U256)], time: u64) -> (U256, u64) {
+    let i = prices.iter().rposition(|(t, _)|*t <= time).unwrap_or(0);
+    let next = prices.get(i + 1).map(|(t, _)|*t).unwrap_or(u64::MAX);
+    (prices[i].1, next)
+}
+
+pub fn simulate(sc: &Scenario) -> Result<(Vec<CycleRow>, Summary)> {
+    if sc.target_seconds_per_cycle == 0 || sc.cycles_per_retarget == 0 {
+        bail!("target_seconds_per_cycle and cycles_per_retarget must not be zero");
+    }
+    if sc.pollinators.is_empty() {
+        bail!("At least one pollinator is required");
+    }
+    let mut prices = sc.gas_price.iter()
+        .map(|gp|Ok((gp.at_seconds, parse_units(&gp.gwei, "gwei")?.get_absolute())))
+        .collect::<Result<Vec<_>>>()
+        .context("gas_price")?;
+    prices.sort_by_key(|(t, _)|*t);
+    if prices.is_empty() {
+        bail!("At least one gas_price is required");
+    }
+    let pollinators = sc.pollinators.iter()
+        .map(|p|Ok((parse_ether(&p.minimum_profit)?, p.latency_seconds)))
+        .collect::<Result<Vec<_>>>()
+        .context("pollinators")?;
+
+    let mut p = PeriodicParams {
+        current_pay_per_period: parse_ether(&sc.initial_pay_per_period)
+            .context("initial_pay_per_period")?,
+        last_cycle_time: 0,
+        last_retarget_time: 0,
+        cycles: 1,
+        cycles_per_retarget: sc.cycles_per_retarget,
+        target_seconds_per_cycle: sc.target_seconds_per_cycle,
+        retarget: RetargetParams {
+            min_factor: factor(sc.retarget.min_factor),
+            max_factor: factor(sc.retarget.max_factor),
+            smoothing: factor(sc.retarget.smoothing),
+            min_pay: match &sc.retarget.min_pay {
+                Some(x) => parse_ether(x).context("retarget.min_pay")?,
+                None => U256::ZERO,
+            },
+            max_pay: match &sc.retarget.max_pay {
+                Some(x) => parse_ether(x).context("retarget.max_pay")?,
+                None => U256::MAX,
+            },
+        },
+        nectar_token: Address::ZERO,
+    };
+
+    let mut rows = Vec::new();
+    let mut now = 0;
+    loop {
+        let (gp, next_change) = gas_price_at(&prices, now);
+        let cost = U256::from(sc.dispatch_gas) * gp;
+        // Whoever's transaction lands first wins the nectar
+        let best = pollinators.iter().enumerate()
+            .filter_map(|(i, (min_profit, latency))| {
+                let due = p.when_nectar_exceeds(cost + min_profit)?.max(now);
+                Some((due + latency, due, i))
+            })
+            .min();
+        let Some((time, due, pollinator)) = best else {
+            // Nobody will ever cycle it at this gas price
+            if next_change == u64::MAX {
+                break;
+            }
+            now = next_change;
+            continue;
+        };
+        if due > next_change {
+            now = next_change;
+            continue;
+        }
+        if time > sc.duration_seconds {
+            break;
+        }
+        let nectar = p.nectar_at(time);
+        let cycle_seconds = time - p.last_cycle_time;
+        post_periodic(&mut p, time).with_context(||format!("cycle at {time} seconds"))?;
+        rows.push(CycleRow {
+            cycle: p.cycles - 1,
+            time,
+            cycle_seconds,
+            nectar,
+            pay_per_period: p.current_pay_per_period,
+            gas_price: gp,
+            pollinator,
+        });
+        now = time;
+    }
+
+    let window = sc.cycles_per_retarget as usize;
+    let target = sc.target_seconds_per_cycle as f64;
+    let mut converged_at = None;
+    let mut final_cycle_seconds = None;
+    for end in window..=rows.len() {
+        let avg = rows[end - window..end].iter().map(|r|r.cycle_seconds as f64).sum::<f64>()
+            / window as f64;
+        final_cycle_seconds = Some(avg);
+        if (avg - target).abs() <= target * CONVERGED_TOLERANCE {
+            converged_at.get_or_insert(rows[end - 1].time);
+        } else {
+            converged_at = None;
+        }
+    }
+    let summary = Summary {
+        cycles: rows.len() as u64,
+        total_nectar: rows.iter().fold(U256::ZERO, |acc, r|acc + r.nectar),
+        final_pay_per_period: p.current_pay_per_period,
+        final_cycle_seconds,
+        converged_at,
+    };
+    Ok((rows, summary))
+}
+
+fn to_csv(rows: &[CycleRow]) -> String {
+    let mut out = String::from("cycle,time,cycle_seconds,nectar_wei,pay_per_period_wei,gas_price_wei,pollinator\n");
+    for r in rows {
+        let _ = writeln!(out, "{},{},{},{},{},{},{}",
+            r.cycle, r.time, r.cycle_seconds, r.nectar, r.pay_per_period, r.gas_price, r.pollinator);
+    }
+    out
+}
+
+pub fn simulate_periodic(scenario_path: PathBuf, csv_path: Option<PathBuf>) -> Result<()> {
+    let sc = std::fs::read_to_string(&scenario_path)
+        .with_context(|| format!("Reading {}", scenario_path.display()))?;
+    let sc: Scenario = serde_yaml::from_str(&sc)?;
+    let (rows, summary) = simulate(&sc)?;
+
+    println!("Cycles: {}", summary.cycles);
+    println!("Total nectar spent: {}", format_ether(summary.total_nectar));
+    println!("Final pay per period: {}", format_ether(summary.final_pay_per_period));
+    match summary.final_cycle_seconds {
+        Some(s) => println!("Final cycle time: {s:.1} seconds (target {})", sc.target_seconds_per_cycle),
+        None => println!("Final cycle time: not enough cycles"),
+    }
+    match summary.converged_at {
+        Some(t) => println!("Converged after {t} seconds ({:.1} days)", t as f64 / 86400.0),
+        None => println!("Did not converge within {}% of the target", CONVERGED_TOLERANCE * 100.0),
+    }
+    let mut wins = vec![0u64; sc.pollinators.len()];
+    for r in &rows {
+        wins[r.pollinator] += 1;
+    }
+    for (i, w) in wins.iter().enumerate() {
+        println!("Pollinator {i} (minimum profit {}): {w} cycles", sc.pollinators[i].minimum_profit);
+    }
+    if let Some(last) = rows.last() {
+        println!("Last gas price: {} gwei", format_units(last.gas_price, "gwei")?);
+    }
+
+    if let Some(csv_path) = csv_path {
+        std::fs::write(&csv_path, to_csv(&rows))
+            .with_context(|| format!("Writing {}", csv_path.display()))?;
+        println!("Wrote {} rows to {}", rows.len(), csv_path.display());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{simulate, GasPricePoint, Scenario, SimPollinator};
+
+    #[test]
+    fn test_too_generous_converges() {
+        // Dispatch costs 0.0001 and the pay is 100x that, so the contract is cycled far too
+        // often at first and the retargets must cut the pay back.
+        let sc = Scenario {
+            target_seconds_per_cycle: 600,
+            cycles_per_retarget: 10,
+            initial_pay_per_period: "0.01".into(),
+            retarget: Default::default(),
+            duration_seconds: 60 * 60 * 24 * 30,
+            dispatch_gas: 100_000,
+            gas_price: vec![GasPricePoint { at_seconds: 0, gwei: "1".into() }],
+            pollinators: vec![
+                SimPollinator { minimum_profit: "0".into(), latency_seconds: 2 },
+                SimPollinator { minimum_profit: "0.0001".into(), latency_seconds: 0 },
+            ],
+        };
+        let (rows, summary) = simulate(&sc).unwrap();
+        assert!(rows[0].cycle_seconds < 60, "Cycled quickly at first");
+        assert!(summary.converged_at.is_some(), "Converges to the target");
+        let last = summary.final_cycle_seconds.unwrap();
+        assert!((last - 600.0).abs() <= 60.0, "Final cycle time {last}");
+        assert!(rows.iter().all(|r|r.pollinator == 0), "The cheaper pollinator always wins");
+    }
+}
@@ -0,0 +1,86 @@
+use std::sync::Arc;
+
+use eyre::{bail, Result};
+
+use crate::{
+    config::PolicyConfig,
+    reject,
+    general::Server,
+    payafter::Transaction,
+    util::now_sec,
+};
+
+/// Checks which can be made without touching the chain, these run before simulation.
+pub fn check_txn(policy: &PolicyConfig, txn: &Transaction, now: u64) -> Result<()> {
+    if let Some(max) = policy.max_payload_bytes {
+        if txn.bin.len() > max {
+            reject!("Rejected by policy: transaction is {} bytes, maximum is {max}", txn.bin.len());
+        }
+    }
+    if let Some(max) = policy.max_calls {
+        if txn.calls.len() > max {
+            reject!("Rejected by policy: transaction has {} calls, maximum is {max}", txn.calls.len());
+        }
+    }
+    for (i, call) in txn.calls.iter().enumerate() {
+        if policy.deny_targets.contains(&call.target) {
+            reject!("Rejected by policy: call {i} targets denied contract {}", call.target);
+        }
+        if !policy.allow_targets.is_empty() && !policy.allow_targets.contains(&call.target) {
+            reject!("Rejected by policy: call {i} targets contract {} which is not allowed", call.target);
+        }
+        let denied = call.selector.is_some_and(|s|policy.deny_selectors.contains(&s));
+        let allowed = policy.allow_selectors.is_empty() ||
+            call.selector.is_some_and(|s|policy.allow_selectors.contains(&s));
+        if denied || !allowed {
+            match call.selector {
+                Some(s) => reject!("Rejected by policy: call {i} uses function {s} which is not allowed"),
+                None => reject!("Rejected by policy: call {i} has no function selector"),
+            }
+        }
+    }
+    if let Some(token) = txn.fee_token {
+        if !policy.fee_tokens.contains(&token) {
+            reject!("Rejected by policy: fees are paid in token {token} which is not accepted");
+        }
+    }
+    if let Some(max) = policy.max_wait_seconds {
+        if txn.when_valid() > now + max {
+            reject!("Rejected by policy: transaction is not valid for {} seconds, maximum wait is {max}",
+                txn.when_valid() - now);
+        }
+    }
+    Ok(())
+}
+
+pub fn check_gas(policy: &PolicyConfig, gas: u64) -> Result<()> {
+    if let Some(max) = policy.max_gas {
+        if gas > max {
+            reject!("Rejected by policy: transaction uses {gas} gas, maximum is {max}");
+        }
+    }
+    Ok(())
+}
+
+pub fn check_wait(policy: &PolicyConfig, time_to_run: u64, now: u64) -> Result<()> {
+    if let Some(max) = policy.max_wait_seconds {
+        if time_to_run > now + max {
+            reject!("Rejected by policy: transaction does not pay enough for {} seconds, maximum wait is {max}",
+                time_to_run - now);
+        }
+    }
+    Ok(())
+}
+
+/// Count a new submission against the signer's rate limit.
+/// Only new submissions count, re-discovery of waiting transactions does not.
+pub async fn check_rate(srv: &Arc<Server>, txn: &Transaction) -> Result<()> {
+    let mut m = srv.m.lock().await;
+    if !m.signer_limiter.check(txn.signer, now_sec()) {
+        bail!("Rejected by policy: too many transactions from {}, limit is {} per {} seconds",
+            txn.signer,
+            srv.cfg.policy.signer_rate_limit.unwrap_or_default(),
+            srv.cfg.policy.signer_rate_window_seconds);
+    }
+    Ok(())
+}
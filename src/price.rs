@@ -0,0 +1,135 @@
+use std::sync::Arc;
+
+use alloy::primitives::{utils::parse_ether, Address, U256};
+use eyre::{bail, eyre, Context, Result};
+use serde::Serialize;
+
+use crate::{
+    abi::{IUniswapV2Pair, IERC20},
+    config::PriceSource,
+    general::Server,
+    util::now_sec,
+};
+
+/// How long to trust an exchange rate before reading it again
+const RATE_CACHE_SECONDS: u64 = 60;
+
+/// An exchange rate, `token` units of the token are worth `base` units of the base coin
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct Rate {
+    pub base: U256,
+    pub token: U256,
+}
+impl Rate {
+    /// Value of an amount of the token in the base coin
+    pub fn to_base(self, amount: U256) -> U256 {
+        if self.token == U256::ZERO {
+            return U256::ZERO;
+        }
+        amount.saturating_mul(self.base) / self.token
+    }
+
+    /// Amount of the token which is worth `base` units of the base coin
+    pub fn to_token(self, base: U256) -> U256 {
+        if self.base == U256::ZERO {
+            return U256::MAX;
+        }
+        base.saturating_mul(self.token).div_ceil(self.base)
+    }
+}
+
+/// A fixed price of one whole token, for a token with `decimals` decimals
+pub fn fixed_rate(price: &str, decimals: u8) -> Result<Rate> {
+    Ok(Rate {
+        base: parse_ether(price).with_context(|| format!("Invalid price {price}"))?,
+        token: U256::from(10).pow(U256::from(decimals)),
+    })
+}
+
+/// Selling into a UniswapV2 pair returns 997/1000 of the spot price, before slippage
+pub fn uniswap_v2_rate(reserve_token: U256, reserve_base: U256) -> Rate {
+    Rate {
+        base: reserve_base * U256::from(997),
+        token: reserve_token * U256::from(1000),
+    }
+}
+
+async fn read_rate(srv: &Arc<Server>, token: Address) -> Result<Rate> {
+    let Some(source) = srv.cfg.token_prices.get(&token) else {
+        bail!("No price source for token {token}");
+    };
+    match source {
+        PriceSource::Fixed { price } => {
+            let erc20 = IERC20::new(token, srv.prov.clone());
+            let decimals = erc20.decimals().call().await.context("decimals()")?._0;
+            fixed_rate(price, decimals)
+        }
+        PriceSource::UniswapV2 { pair } => {
+            let pair = IUniswapV2Pair::new(*pair, srv.prov.clone());
+            let res = pair.getReserves().call().await.context("getReserves()")?;
+            let (r0, r1) = (U256::from(res.reserve0), U256::from(res.reserve1));
+            if pair.token0().call().await.context("token0()")?._0 == token {
+                Ok(uniswap_v2_rate(r0, r1))
+            } else if pair.token1().call().await.context("token1()")?._0 == token {
+                Ok(uniswap_v2_rate(r1, r0))
+            } else {
+                Err(eyre!("Pair {} does not contain token {token}", pair.address()))
+            }
+        }
+    }
+}
+
+/// The exchange rate of a token which nectar is paid in, cached like the gas price
+pub async fn token_rate(srv: &Arc<Server>, token: Address) -> Result<Rate> {
+    let now = now_sec();
+    if let Some((checked, rate)) = srv.m.lock().await.token_rates.get(&token) {
+        if checked + RATE_CACHE_SECONDS > now {
+            return Ok(*rate);
+        }
+    }
+    let rate = read_rate(srv, token).await?;
+    srv.m.lock().await.token_rates.insert(token, (now, rate));
+    Ok(rate)
+}
+
+/// Convert an amount of the base coin into nectar in `token`, zero meaning the base coin
+pub async fn base_to_nectar(srv: &Arc<Server>, token: Address, base: U256) -> Result<U256> {
+    if token == Address::ZERO {
+        return Ok(base);
+    }
+    Ok(token_rate(srv, token).await?.to_token(base))
+}
+
+/// Value of an amount of nectar in `token` in the base coin
+pub async fn nectar_to_base(srv: &Arc<Server>, token: Address, nectar: U256) -> Result<U256> {
+    if token == Address::ZERO {
+        return Ok(nectar);
+    }
+    Ok(token_rate(srv, token).await?.to_base(nectar))
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::primitives::{utils::parse_ether, U256};
+
+    use super::{fixed_rate, uniswap_v2_rate};
+
+    #[test]
+    fn test_rates() {
+        // 1 token = 0.002 base coin
+        let r = fixed_rate("0.002", 18).unwrap();
+        assert_eq!(r.to_base(parse_ether("500").unwrap()), parse_ether("1").unwrap());
+        assert_eq!(r.to_token(parse_ether("1").unwrap()), parse_ether("500").unwrap());
+
+        // 6 decimal token worth 2 base coins
+        let r = fixed_rate("2", 6).unwrap();
+        assert_eq!(r.to_base(U256::from(1_500_000)), parse_ether("3").unwrap());
+
+        // Rounds up so that the nectar needed is never undervalued
+        let r = fixed_rate("3", 0).unwrap();
+        assert_eq!(r.to_token(parse_ether("1").unwrap()), U256::from(1));
+
+        let r = uniswap_v2_rate(parse_ether("1000").unwrap(), parse_ether("10").unwrap());
+        assert_eq!(r.to_base(parse_ether("100").unwrap()), parse_ether("0.997").unwrap());
+    }
+}
@@ -0,0 +1,62 @@
+use std::{collections::{HashMap, VecDeque}, hash::Hash};
+
+/// How many checks between sweeps of keys which have no recent hits
+const PRUNE_INTERVAL: u32 = 1024;
+
+/// Sliding window rate limiter, allows `limit` hits per key in any `window_sec` seconds.
+pub struct RateLimiter<K> {
+    limit: Option<u32>,
+    window_sec: u64,
+    hits: HashMap<K, VecDeque<u64>>,
+    checks: u32,
+}
+
+impl<K: Hash + Eq> RateLimiter<K> {
+    /// A limit of None means unlimited
+    pub fn new(limit: Option<u32>, window_sec: u64) -> Self {
+        Self { limit, window_sec, hits: HashMap::new(), checks: 0 }
+    }
+
+    /// Record a hit for `key`, returns false (and does not record) if the key is over the limit
+    pub fn check(&mut self, key: K, now: u64) -> bool {
+        let Some(limit) = self.limit else {
+            return true;
+        };
+        let window_sec = self.window_sec;
+        self.checks += 1;
+        if self.checks >= PRUNE_INTERVAL {
+            self.checks = 0;
+            self.hits.retain(|_, h|h.back().is_some_and(|t|t + window_sec > now));
+        }
+        let hits = self.hits.entry(key).or_default();
+        while hits.front().is_some_and(|t|t + window_sec <= now) {
+            hits.pop_front();
+        }
+        if hits.len() >= limit as usize {
+            return false;
+        }
+        hits.push_back(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RateLimiter;
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut rl = RateLimiter::new(Some(2), 10);
+        assert!(rl.check("a", 100));
+        assert!(rl.check("a", 101));
+        assert!(!rl.check("a", 102), "Third hit in the window is refused");
+        assert!(rl.check("b", 102), "Keys are independent");
+        assert!(rl.check("a", 110), "First hit has left the window");
+        assert!(!rl.check("a", 110));
+
+        let mut rl = RateLimiter::new(None, 10);
+        for _ in 0..100 {
+            assert!(rl.check("a", 100));
+        }
+    }
+}
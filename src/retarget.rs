@@ -0,0 +1,162 @@
+use alloy::primitives::U256;
+use eyre::{bail, OptionExt, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::general::PeriodicParams;
+
+const SCALE: u64 = 1_000_000_000_000_000_000;
+
+/// Mirror of BasePeriodic.retargetParams(), factors are scaled by 1e18
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RetargetParams {
+    pub min_factor: U256,
+    pub max_factor: U256,
+    pub smoothing: U256,
+    pub min_pay: U256,
+    pub max_pay: U256,
+}
+impl Default for RetargetParams {
+    /// The original algorithm, clamped to between 0.5x and 2x
+    fn default() -> Self {
+        Self {
+            min_factor: U256::from(SCALE / 2),
+            max_factor: U256::from(SCALE * 2),
+            smoothing: U256::from(SCALE),
+            min_pay: U256::ZERO,
+            max_pay: U256::MAX,
+        }
+    }
+}
+impl RetargetParams {
+    /// Exactly what BasePeriodic.retarget() computes, fails where it reverts
+    pub fn retarget(&self, pay: U256, real_time: u64, expected_time: u64) -> Result<U256> {
+        let scale = U256::from(SCALE);
+        if expected_time == 0 {
+            bail!("Retarget with an expected time of 0 divides by zero");
+        }
+        let mut factor = U256::from(real_time) * scale / U256::from(expected_time);
+        // maxFactor is checked first, so it wins if minFactor > maxFactor
+        if factor > self.max_factor {
+            factor = self.max_factor;
+        } else if factor < self.min_factor {
+            factor = self.min_factor;
+        }
+        let overflow = "Retarget overflows";
+        let mut target = pay.checked_mul(factor).ok_or_eyre(overflow)? / scale;
+        if self.smoothing < scale {
+            target = pay.checked_mul(scale - self.smoothing)
+                .zip(target.checked_mul(self.smoothing))
+                .and_then(|(a, b)|a.checked_add(b))
+                .ok_or_eyre(overflow)? / scale;
+        }
+        Ok(if target > self.max_pay {
+            self.max_pay
+        } else if target < self.min_pay {
+            self.min_pay
+        } else {
+            target
+        })
+    }
+}
+
+/// Like BasePeriodic.postPeriodic(), update the parameters for a cycle at `time`.
+/// Fails if the retarget would revert, leaving the parameters unchanged.
+pub fn post_periodic(p: &mut PeriodicParams, time: u64) -> Result<()> {
+    if p.cycles_per_retarget > 0 && p.cycles % p.cycles_per_retarget == 0 {
+        p.current_pay_per_period = p.retarget.retarget(
+            p.current_pay_per_period,
+            time.saturating_sub(p.last_retarget_time),
+            p.target_seconds_per_cycle * p.cycles_per_retarget,
+        )?;
+        p.last_retarget_time = time;
+    }
+    p.cycles += 1;
+    p.last_cycle_time = time;
+    Ok(())
+}
+
+/// Predict the nectar paid by each of the next `n` cycles, if the contract is cycled every
+/// `cycle_every` seconds starting from its last cycle. Stops early at a cycle which would
+/// revert, since the contract can never be cycled past it.
+pub fn predict_payouts(params: &PeriodicParams, cycle_every: u64, n: usize) -> Vec<U256> {
+    let mut p = params.clone();
+    let mut out = Vec::with_capacity(n);
+    for _ in 0..n {
+        let time = p.last_cycle_time + cycle_every;
+        let nectar = p.nectar_at(time);
+        if post_periodic(&mut p, time).is_err() {
+            break;
+        }
+        out.push(nectar);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::primitives::{Address, U256};
+
+    use crate::general::PeriodicParams;
+
+    use super::{predict_payouts, RetargetParams, SCALE};
+
+    #[test]
+    fn test_retarget() {
+        let rp = RetargetParams::default();
+        let pay = U256::from(1000);
+        assert_eq!(rp.retarget(pay, 100, 100).unwrap(), pay);
+        assert_eq!(rp.retarget(pay, 150, 100).unwrap(), U256::from(1500));
+        assert_eq!(rp.retarget(pay, 1000, 100).unwrap(), U256::from(2000), "Clamped to 2x");
+        assert_eq!(rp.retarget(pay, 10, 100).unwrap(), U256::from(500), "Clamped to 0.5x");
+
+        // Moving a quarter of the way towards the target
+        let ema = RetargetParams { smoothing: U256::from(SCALE / 4), ..Default::default() };
+        assert_eq!(ema.retarget(pay, 200, 100).unwrap(), U256::from(1250));
+
+        let bounded = RetargetParams {
+            min_pay: U256::from(800),
+            max_pay: U256::from(1200),
+            ..Default::default()
+        };
+        assert_eq!(bounded.retarget(pay, 200, 100).unwrap(), U256::from(1200));
+        assert_eq!(bounded.retarget(pay, 50, 100).unwrap(), U256::from(800));
+    }
+
+    #[test]
+    fn test_retarget_inverted_factors() {
+        // Like the contract, a factor above maxFactor becomes maxFactor even though it is
+        // below minFactor, and one below both becomes minFactor
+        let rp = RetargetParams {
+            min_factor: U256::from(SCALE * 2),
+            max_factor: U256::from(SCALE / 2),
+            ..Default::default()
+        };
+        let pay = U256::from(1000);
+        assert_eq!(rp.retarget(pay, 100, 100).unwrap(), U256::from(500));
+        assert_eq!(rp.retarget(pay, 10, 100).unwrap(), U256::from(2000));
+    }
+
+    #[test]
+    fn test_retarget_reverts() {
+        let rp = RetargetParams::default();
+        assert!(rp.retarget(U256::from(1000), 100, 0).is_err(), "Division by zero");
+        assert!(rp.retarget(U256::MAX, 100, 100).is_err(), "Overflow");
+    }
+
+    #[test]
+    fn test_predict_payouts() {
+        let params = PeriodicParams {
+            current_pay_per_period: U256::from(1000),
+            last_cycle_time: 1000,
+            last_retarget_time: 0,
+            cycles: 1,
+            cycles_per_retarget: 2,
+            target_seconds_per_cycle: 100,
+            retarget: RetargetParams::default(),
+            nectar_token: Address::ZERO,
+        };
+        // Cycling twice as slowly as the target, the retarget on the 2nd cycle doubles the pay
+        let payouts = predict_payouts(&params, 200, 3);
+        assert_eq!(payouts, vec![U256::from(2000), U256::from(2000), U256::from(4000)]);
+    }
+}
@@ -1,4 +1,7 @@
-use std::{convert::Infallible, path::PathBuf, sync::Arc};
+use std::{
+    collections::HashMap, convert::Infallible, net::SocketAddr, path::PathBuf, sync::Arc,
+    time::Duration,
+};
 
 use alloy::{
     hex,
@@ -7,6 +10,7 @@ use alloy::{
         utils::{format_ether, parse_ether},
         Address,
         B256,
+        Bytes,
     },
     providers::{
         Provider,
@@ -17,32 +21,47 @@ use alloy::{
             coins_bip39::{English, Entropy, Mnemonic},
             MnemonicBuilder
         }
-    }, transports::http::reqwest::Url
+    }, transports::http::reqwest::{Client, Url}
 };
-use eyre::Result;
+use eyre::{bail, Result};
 use serde::{Deserialize, Serialize};
 use tokio::sync::{mpsc, Mutex};
 use warp::Filter;
 
 use crate::{
+    antispam::{self, RejectedCache},
     config::Config,
+    decode::DecodedCall,
+    discover::discover_periodics_thread,
     err_is_400,
     general::{
+        PayAfterTxn,
         PayAfterTxnStatus,
         Server,
         ServerMut,
         State
     },
     payafter::{
+        cancel_txn,
         check_payafter_thread,
         discover_txn,
         parse_transaction,
-        DiscoverTxnRes
+        simulate_txn,
+        CancelOutcome,
+        DiscoverTxnRes,
+        SimulateOpts,
     },
-    periodic::check_periodics_thread,
-    util::{reply_with, vstr_from_error},
+    periodic::{check_periodics_thread, FundingEntry},
+    ratelimit::RateLimiter,
+    util::{now_sec, reply_with, vstr_from_error},
 };
 
+const CANCEL_PEER_TIMEOUT_SECONDS: u64 = 10;
+
+/// Sent with every cancel response, so that nobody mistakes it for a binding cancellation
+const CANCEL_NOTE: &str = "Cancellation is best-effort, the transaction remains valid on-chain \
+    and other pollinators may still run it. Only PayAfterDispatcher.killTransaction() is binding.";
+
 #[derive(Serialize, Deserialize)]
 struct PayAfterPost {
     txn: String,
@@ -53,14 +72,53 @@ struct PayAfterRes {
     create_time: Option<u64>,
     txid: Option<B256>, // fully succeeded
     wait_until: Option<u64>, // accepted, will post later
+    waiting_for: Option<B256>, // held until this transaction has run
     data_hash: Option<B256>,
     error: Option<Vec<String>>,
+    calls: Option<Vec<DecodedCall>>, // what the transaction will do
+    cancelled: Option<bool>, // the signer asked us not to run it
+}
+
+fn payafter_res(data_hash: B256, pa: &PayAfterTxn) -> PayAfterRes {
+    PayAfterRes{
+        create_time: Some(pa.create_time),
+        txid: if let PayAfterTxnStatus::Success(txid) = &pa.status {
+            Some(*txid)
+        } else {
+            None
+        },
+        wait_until: if let PayAfterTxnStatus::Waiting(wait) = &pa.status {
+            Some(wait.time_to_run)
+        } else {
+            None
+        },
+        waiting_for: if let PayAfterTxnStatus::Waiting(wait) = &pa.status {
+            wait.requires
+        } else {
+            None
+        },
+        data_hash: Some(data_hash),
+        error: if let PayAfterTxnStatus::Error(e) = &pa.status {
+            Some(e.clone())
+        } else {
+            None
+        },
+        calls: Some(pa.calls.clone()),
+        cancelled: matches!(pa.status, PayAfterTxnStatus::Cancelled).then_some(true),
+    }
 }
 
 async fn api_payafter(
     q: PayAfterPost,
+    remote: Option<SocketAddr>,
     srv: Arc<Server>,
 ) -> Result<Box<dyn warp::Reply>, Infallible> {
+    if let Err(e) = antispam::check_ip(&srv, remote).await {
+        return reply_with(&PayAfterRes{
+            error: Some(vstr_from_error(e)),
+            ..Default::default()
+        });
+    }
     let txn = err_is_400!(hex::decode(&q.txn));
     let txn = match parse_transaction(&srv.cfg, txn.into()) {
         Ok(txn) => txn,
@@ -73,11 +131,32 @@ async fn api_payafter(
     };
     let data_hash = txn.data_hash.clone();
     let create_time = Some(txn.create_time);
-    reply_with(&match discover_txn(&srv, txn).await {
+    let calls = Some(txn.calls.clone());
+    {
+        // Resubmissions of transactions we already know about, or recently rejected,
+        // are answered without touching the chain.
+        let m = srv.m.lock().await;
+        if let Some(pa) = m.state.payafter.get(&data_hash) {
+            if !matches!(pa.status, PayAfterTxnStatus::Error(_)) {
+                return reply_with(&payafter_res(data_hash, pa));
+            }
+        }
+        if let Some(err) = m.rejected.get(&data_hash, now_sec()) {
+            return reply_with(&PayAfterRes{
+                data_hash: Some(data_hash),
+                create_time,
+                error: Some(err.clone()),
+                calls,
+                ..Default::default()
+            });
+        }
+    }
+    reply_with(&match discover_txn(&srv, txn, true).await {
         Ok(x) => {
             let mut par = PayAfterRes{
                 data_hash: Some(data_hash),
                 create_time,
+                calls,
                 ..Default::default()
             };
             match x {
@@ -89,19 +168,140 @@ async fn api_payafter(
                     par.wait_until = Some(time);
                     par
                 }
+                DiscoverTxnRes::WaitFor(requires, time) => {
+                    par.waiting_for = Some(requires);
+                    par.wait_until = Some(time);
+                    par
+                }
             }
         },
         Err(e) => {
+            let rejected = antispam::is_rejection(&e);
+            let error = vstr_from_error(e);
+            if rejected {
+                srv.m.lock().await.rejected.insert(data_hash, now_sec(), error.clone());
+            }
             PayAfterRes{
                 data_hash: Some(data_hash),
                 create_time,
-                error: Some(vstr_from_error(e)),
+                error: Some(error),
+                calls,
                 ..Default::default()
             }
         }
     })
 }
 
+#[derive(Serialize, Deserialize)]
+struct CancelPost {
+    signer: Address,
+    data_hash: B256,
+    signature: Bytes,
+    /// Set when passed on by another pollinator, so it is not passed on again
+    #[serde(default)]
+    propagated: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CancelRes {
+    data_hash: Option<B256>,
+    cancelled: bool,
+    error: Option<Vec<String>>,
+    note: String,
+}
+
+impl Default for CancelRes {
+    fn default() -> Self {
+        Self {
+            data_hash: None,
+            cancelled: false,
+            error: None,
+            note: CANCEL_NOTE.into(),
+        }
+    }
+}
+
+/// Best-effort: we stop running the transaction, but only killTransaction() binds every
+/// pollinator.
+async fn api_cancel(
+    q: CancelPost,
+    remote: Option<SocketAddr>,
+    srv: Arc<Server>,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    if let Err(e) = antispam::check_ip(&srv, remote).await {
+        return reply_with(&CancelRes{
+            error: Some(vstr_from_error(e)),
+            ..Default::default()
+        });
+    }
+    let data_hash = Some(q.data_hash);
+    let outcome = cancel_txn(&srv, q.signer, q.data_hash, q.signature.clone()).await;
+    reply_with(&match outcome {
+        Ok(outcome) => {
+            // Only cancellations whose signature we checked are passed on, so that this cannot
+            // be used to spam our peers
+            if !q.propagated && matches!(outcome, CancelOutcome::Cancelled) {
+                tokio::task::spawn(propagate_cancel(Arc::clone(&srv), q));
+            }
+            match outcome {
+                CancelOutcome::Unknown => CancelRes{
+                    data_hash,
+                    error: Some(vec!["Unknown transaction".into()]),
+                    ..Default::default()
+                },
+                CancelOutcome::Cancelled | CancelOutcome::AlreadyCancelled => CancelRes{
+                    data_hash,
+                    cancelled: true,
+                    ..Default::default()
+                },
+            }
+        }
+        Err(e) => CancelRes{
+            data_hash,
+            error: Some(vstr_from_error(e)),
+            ..Default::default()
+        },
+    })
+}
+
+/// Pass a cancellation on to the pollinators in cancel_peers, once
+async fn propagate_cancel(srv: Arc<Server>, q: CancelPost) {
+    let data_hash = q.data_hash;
+    let body = serde_json::to_string(&CancelPost{ propagated: true, ..q }).unwrap();
+    let client = Client::new();
+    for peer in &srv.cfg.cancel_peers {
+        let url = format!("{}/api/v1/cancel", peer.trim_end_matches('/'));
+        let r = client.post(&url)
+            .header("Content-Type", "application/json")
+            .body(body.clone())
+            .timeout(Duration::from_secs(CANCEL_PEER_TIMEOUT_SECONDS))
+            .send()
+            .await
+            .and_then(|r|r.error_for_status());
+        if let Err(e) = r {
+            println!("Error passing cancellation of {data_hash} to {peer}: {e}");
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SimulatePost {
+    txn: String,
+    #[serde(flatten)]
+    opts: SimulateOpts,
+}
+
+async fn api_simulate(
+    q: SimulatePost,
+    remote: Option<SocketAddr>,
+    srv: Arc<Server>,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    err_is_400!(antispam::check_ip(&srv, remote).await);
+    let txn = err_is_400!(hex::decode(&q.txn));
+    let txn = err_is_400!(parse_transaction(&srv.cfg, txn.into()));
+    reply_with(&err_is_400!(simulate_txn(&srv, &txn, &q.opts).await))
+}
+
 async fn api_address_payafters(
     addr: Address,
     srv: Arc<Server>,
@@ -109,25 +309,63 @@ async fn api_address_payafters(
     let m = srv.m.lock().await;
     let v = m.state.payafter.iter()
         .filter(|(_, pa)|pa.signer == addr)
-        .map(|(id, pa)|PayAfterRes{
-            create_time: Some(pa.create_time),
-            txid: if let PayAfterTxnStatus::Success(txid) = &pa.status {
-                Some(*txid)
-            } else {
-                None
-            },
-            wait_until: if let PayAfterTxnStatus::Waiting(wait) = &pa.status {
-                Some(wait.time_to_run)
-            } else {
-                None
-            },
-            data_hash: Some(*id),
-            error: if let PayAfterTxnStatus::Error(e) = &pa.status {
-                Some(e.clone())
-            } else {
-                None
-            }
-        })
+        .map(|(id, pa)|payafter_res(*id, pa))
+        .collect::<Vec<_>>();
+    reply_with(&v)
+}
+
+async fn api_get_payafter(
+    data_hash: B256,
+    srv: Arc<Server>,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    let m = srv.m.lock().await;
+    let Some(pa) = m.state.payafter.get(&data_hash) else {
+        return Ok(Box::new(warp::reply::with_status(
+            format!("Error: PayAfter {data_hash} not found"),
+            warp::http::StatusCode::NOT_FOUND,
+        )));
+    };
+    reply_with(&payafter_res(data_hash, pa))
+}
+
+/// The funding last read by the scheduler, the API never reads it from the chain itself
+fn cached_funding(m: &ServerMut, addr: Address) -> FundingEntry {
+    m.funding.get(&addr).cloned().unwrap_or_else(||FundingEntry::Error {
+        address: addr,
+        error: "Funding has not been read yet".into(),
+    })
+}
+
+async fn api_periodic(
+    addr: Address,
+    remote: Option<SocketAddr>,
+    srv: Arc<Server>,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    err_is_400!(antispam::check_ip(&srv, remote).await);
+    let m = srv.m.lock().await;
+    if !m.state.periodic_contracts.contains_key(&addr) {
+        return Ok(Box::new(warp::reply::with_status(
+            format!("Error: Periodic {addr} is not watched"),
+            warp::http::StatusCode::NOT_FOUND,
+        )));
+    }
+    match cached_funding(&m, addr) {
+        FundingEntry::Funding(f) => reply_with(&f),
+        FundingEntry::Error { error, .. } => Ok(Box::new(warp::reply::with_status(
+            format!("Error: {error}"),
+            warp::http::StatusCode::BAD_REQUEST,
+        ))),
+    }
+}
+
+async fn api_periodics(
+    remote: Option<SocketAddr>,
+    srv: Arc<Server>,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    err_is_400!(antispam::check_ip(&srv, remote).await);
+    let m = srv.m.lock().await;
+    let v = m.state.periodic_contracts.keys()
+        .map(|addr|cached_funding(&m, *addr))
         .collect::<Vec<_>>();
     reply_with(&v)
 }
@@ -135,6 +373,31 @@ async fn api_address_payafters(
 pub async fn serve(config_path: PathBuf) -> Result<()> {
     let cfg = tokio::fs::read_to_string(config_path).await?;
     let cfg: Config = serde_yaml::from_str(&cfg)?;
+    if cfg.tls_cert_path.is_some() != cfg.tls_key_path.is_some() {
+        bail!("tls_cert_path and tls_key_path must be set together");
+    }
+    if cfg.bind_address.is_empty() {
+        bail!("bind_address must have at least one address");
+    }
+    // warp panics on an origin it cannot parse
+    for origin in cfg.cors_origins.iter().filter(|o|*o != "*") {
+        let url = Url::parse(origin).ok().filter(|u|
+            u.has_host() && u.path() == "/" && !origin.ends_with('/') &&
+            u.username().is_empty() && u.query().is_none() && u.fragment().is_none());
+        if url.is_none() {
+            bail!("cors_origins has {origin:?} which is not an origin like https://example.com");
+        }
+    }
+    // A zero window forgets every hit at once, so the limit would never apply
+    if cfg.policy.signer_rate_limit.is_some() && cfg.policy.signer_rate_window_seconds == 0 {
+        bail!("policy.signer_rate_window_seconds must not be 0 when signer_rate_limit is set");
+    }
+    if cfg.antispam.ip_rate_limit.is_some() && cfg.antispam.ip_rate_window_seconds == 0 {
+        bail!("antispam.ip_rate_window_seconds must not be 0 when ip_rate_limit is set");
+    }
+    if let Some(t) = cfg.policy.fee_tokens.iter().find(|t|!cfg.token_prices.contains_key(t)) {
+        bail!("policy.fee_tokens has {t} which has no entry in token_prices");
+    }
     let mut state = if tokio::fs::try_exists(&cfg.state_file).await? {
         let state = tokio::fs::read_to_string(&cfg.state_file).await?;
         serde_json::from_str::<State>(&state)?
@@ -185,7 +448,21 @@ pub async fn serve(config_path: PathBuf) -> Result<()> {
             state,
             gas_price: 0,
             gas_price_last_checked: 0,
+            token_rates: HashMap::new(),
             send_wakeup,
+            signer_limiter: RateLimiter::new(
+                cfg.policy.signer_rate_limit,
+                cfg.policy.signer_rate_window_seconds,
+            ),
+            ip_limiter: RateLimiter::new(
+                cfg.antispam.ip_rate_limit,
+                cfg.antispam.ip_rate_window_seconds,
+            ),
+            rejected: RejectedCache::new(
+                cfg.antispam.rejected_cache_size,
+                cfg.antispam.rejected_cache_seconds,
+            ),
+            funding: HashMap::new(),
         }),
         minimum_profit,
         cfg,
@@ -198,18 +475,39 @@ pub async fn serve(config_path: PathBuf) -> Result<()> {
 
     tokio::task::spawn(check_payafter_thread(Arc::clone(&srv), recv_wakeup));
 
+    if srv.cfg.discovery.enabled {
+        tokio::task::spawn(discover_periodics_thread(Arc::clone(&srv)));
+    }
+
     let api = {
         let server = Arc::clone(&srv);
         warp::path!("api" / "v1" / "payafter")
             .and(warp::post())
+            .and(warp::body::content_length_limit(srv.cfg.antispam.max_body_bytes))
             .and(warp::body::json())
+            .and(warp::addr::remote())
             .and(warp::any().map(move || Arc::clone(&server)))
             .and_then(api_payafter)
     };
     let api = api.or({
-        warp::path!("api" / "v1" / "payafter")
-            .and(warp::options())
-            .and_then(||async { reply_with(&serde_json::Value::Null) })
+        let server = Arc::clone(&srv);
+        warp::path!("api" / "v1" / "simulate")
+            .and(warp::post())
+            .and(warp::body::content_length_limit(srv.cfg.antispam.max_body_bytes))
+            .and(warp::body::json())
+            .and(warp::addr::remote())
+            .and(warp::any().map(move || Arc::clone(&server)))
+            .and_then(api_simulate)
+    });
+    let api = api.or({
+        let server = Arc::clone(&srv);
+        warp::path!("api" / "v1" / "cancel")
+            .and(warp::post())
+            .and(warp::body::content_length_limit(srv.cfg.antispam.max_body_bytes))
+            .and(warp::body::json())
+            .and(warp::addr::remote())
+            .and(warp::any().map(move || Arc::clone(&server)))
+            .and_then(api_cancel)
     });
 
     let api = api.or({
@@ -220,7 +518,60 @@ pub async fn serve(config_path: PathBuf) -> Result<()> {
             .and_then(api_address_payafters)
     });
 
-    warp::serve(api).bind(([127, 0, 0, 1], srv.cfg.bind_port)).await;
+    let api = api.or({
+        let server = Arc::clone(&srv);
+        warp::path!("api" / "v1" / "payafter" / B256)
+            .and(warp::get())
+            .and(warp::any().map(move || Arc::clone(&server)))
+            .and_then(api_get_payafter)
+    });
+
+    let api = api.or({
+        let server = Arc::clone(&srv);
+        warp::path!("api" / "v1" / "periodic" / Address)
+            .and(warp::get())
+            .and(warp::addr::remote())
+            .and(warp::any().map(move || Arc::clone(&server)))
+            .and_then(api_periodic)
+    });
+
+    let api = api.or({
+        let server = Arc::clone(&srv);
+        warp::path!("api" / "v1" / "periodics")
+            .and(warp::get())
+            .and(warp::addr::remote())
+            .and(warp::any().map(move || Arc::clone(&server)))
+            .and_then(api_periodics)
+    });
+
+    // Preflight (OPTIONS) requests are answered by the CORS filter
+    let cors = if srv.cfg.cors_origins.iter().any(|o|o == "*") {
+        warp::cors().allow_any_origin()
+    } else {
+        warp::cors().allow_origins(srv.cfg.cors_origins.iter().map(|o|o.as_str()))
+    };
+    let api = api.with(cors
+        .allow_methods(vec!["GET", "POST", "OPTIONS"])
+        .allow_headers(vec!["Content-Type"]));
+
+    let mut listeners = Vec::new();
+    for ip in &srv.cfg.bind_address {
+        let addr = SocketAddr::new(*ip, srv.cfg.bind_port);
+        let server = warp::serve(api.clone());
+        listeners.push(match (&srv.cfg.tls_cert_path, &srv.cfg.tls_key_path) {
+            (Some(cert), Some(key)) => {
+                println!("API listening on https://{addr}");
+                tokio::task::spawn(server.tls().cert_path(cert).key_path(key).bind(addr))
+            }
+            _ => {
+                println!("API listening on http://{addr}");
+                tokio::task::spawn(server.bind(addr))
+            }
+        });
+    }
+    for l in listeners {
+        l.await?;
+    }
 
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,696 @@
+use std::path::PathBuf;
+
+use alloy::{
+    primitives::{eip191_hash_message, keccak256, Address, Bytes, PrimitiveSignature, B256, U256},
+    signers::{local::PrivateKeySigner, SignerSync},
+};
+use alloy_sol_types::{eip712_domain, Eip712Domain, SolStruct, SolValue};
+use eyre::{bail, Context, OptionExt, Result};
+use serde::Deserialize;
+
+use crate::{
+    decode::{encode_call, split_calls, RawCall, CALL_FLAG_ALLOW_FAILURE},
+    fee::{
+        get_fees, pack_fee, Header, FLAG_FEE_TOKEN, FLAG_MIN_GAS, FLAG_REQUIRES, FORMAT_V1,
+        FORMAT_V2,
+    },
+    generate::PAYAFTER_DISPATCHER_ADDR,
+    util::now_sec,
+};
+
+pub const SIG_LEN: usize = 65;
+
+/// The last byte of the signature (v) also carries the signature version:
+/// v = 27 + (version << 1) + recovery id
+/// personal_sign over keccak256(abi.encode(keccak256(data), chainid))
+pub const SIG_VERSION_PERSONAL: u8 = 0;
+/// EIP-712 typed data
+pub const SIG_VERSION_EIP712: u8 = 1;
+/// EIP-712 typed data of WalletPayAfter (or WalletPayAfterV2), which wraps the PayAfter together
+/// with the wallet address so that a wallet's signature cannot be replayed as an EOA signature or
+/// by another wallet. It is validated by the signer contract's isValidSignature(). The signature
+/// field holds the contract address and the length of its signature, which follows the payload.
+pub const SIG_VERSION_ERC1271: u8 = 2;
+
+/// How a transaction is signed
+pub enum TxnSignature {
+    /// An EOA signature, with v = 27 or 28
+    Ecdsa(PrimitiveSignature),
+    /// A smart contract wallet, which can only be checked on-chain
+    Erc1271 { signer: Address, signature: Bytes },
+}
+
+alloy::sol! {
+    /// A fee entry as shown to the user, a kill fee has amount type(uint).max
+    #[derive(Debug)]
+    struct Fee {
+        uint256 amount;
+        uint64 afterSeconds;
+    }
+    #[derive(Debug)]
+    struct Call {
+        address target;
+        bytes data;
+    }
+    /// Flags are the call entry's flags, value is 0 unless it has CALL_FLAG_VALUE
+    #[derive(Debug)]
+    struct CallV2 {
+        address target;
+        uint8 flags;
+        uint256 value;
+        bytes data;
+    }
+    #[derive(Debug)]
+    struct PayAfter {
+        uint64 creationTime;
+        Fee[] fees;
+        Call[] calls;
+    }
+    /// Format version 2, options are the fields selected by the flags
+    #[derive(Debug)]
+    struct PayAfterV2 {
+        uint64 creationTime;
+        uint32 flags;
+        bytes options;
+        Fee[] fees;
+        CallV2[] calls;
+    }
+    /// What a smart contract wallet is asked to accept, naming the wallet so that the
+    /// signature is not also valid for its owner's own PayAfter or for another wallet
+    #[derive(Debug)]
+    struct WalletPayAfter {
+        address wallet;
+        PayAfter payAfter;
+    }
+    #[derive(Debug)]
+    struct WalletPayAfterV2 {
+        address wallet;
+        PayAfterV2 payAfter;
+    }
+    /// Asks pollinators to stop serving a PayAfter, only killTransaction() is binding
+    #[derive(Debug)]
+    struct PayAfterCancel {
+        address signer;
+        bytes32 dataHash;
+    }
+}
+
+fn dispatcher_domain(chain_id: u64, dispatcher: Address) -> Eip712Domain {
+    eip712_domain! {
+        name: "PayAfterDispatcher",
+        version: "1",
+        chain_id: chain_id,
+        verifying_contract: dispatcher,
+    }
+}
+
+fn sig_version(bin: &[u8]) -> Result<u8> {
+    let v = *bin.get(SIG_LEN - 1).ok_or_eyre("Buffer overflow")?;
+    if v < 27 {
+        bail!("Invalid signature version");
+    }
+    Ok((v - 27) >> 1)
+}
+
+/// The transaction without any ERC-1271 signature which follows the payload
+pub fn signed_data(bin: &[u8]) -> Result<&[u8]> {
+    if sig_version(bin)? != SIG_VERSION_ERC1271 {
+        return Ok(bin);
+    }
+    let sig_len = u16::from_be_bytes([bin[20], bin[21]]) as usize;
+    if bin.len() < SIG_LEN + 3 + 4 + 4 + sig_len {
+        bail!("Invalid signature length");
+    }
+    Ok(&bin[..bin.len() - sig_len])
+}
+
+/// The EIP-712 signing hash of a PayAfter, the struct is PayAfterDispatcher.payAfterStructHash(),
+/// wrapped in WalletPayAfter for a smart contract wallet
+fn typed_data_hash(bin: &[u8], domain: &Eip712Domain, wallet: Option<Address>) -> Result<B256> {
+    let mut payload = signed_data(bin)?;
+    let (header, fees) = get_fees(&mut payload)?;
+    let fees = fees.into_iter()
+        .map(|(amount, time)|Fee { amount, afterSeconds: time - header.create_time })
+        .collect();
+    let calls = split_calls(payload, header.format)?;
+    Ok(match header.format {
+        FORMAT_V1 => {
+            let pay_after = PayAfter {
+                creationTime: header.create_time,
+                fees,
+                calls: calls.into_iter()
+                    .map(|c|Call { target: c.target, data: Bytes::copy_from_slice(c.data) })
+                    .collect(),
+            };
+            match wallet {
+                Some(wallet) =>
+                    WalletPayAfter { wallet, payAfter: pay_after }.eip712_signing_hash(domain),
+                None => pay_after.eip712_signing_hash(domain),
+            }
+        }
+        _ => {
+            let pay_after = PayAfterV2 {
+                creationTime: header.create_time,
+                flags: header.flags,
+                options: header.options.into(),
+                fees,
+                calls: calls.into_iter()
+                    .map(|c|CallV2 {
+                        target: c.target,
+                        flags: c.flags,
+                        value: c.value,
+                        data: Bytes::copy_from_slice(c.data),
+                    })
+                    .collect(),
+            };
+            match wallet {
+                Some(wallet) =>
+                    WalletPayAfterV2 { wallet, payAfter: pay_after }.eip712_signing_hash(domain),
+                None => pay_after.eip712_signing_hash(domain),
+            }
+        }
+    })
+}
+
+/// The hash which the signer signs, like PayAfterDispatcher.signedHash().
+/// The signature at the start of `bin` is ignored.
+pub fn signed_hash(version: u8, bin: &[u8], chain_id: u64, dispatcher: Address) -> Result<B256> {
+    match version {
+        SIG_VERSION_PERSONAL => {
+            let data_hash = keccak256(bin.get(SIG_LEN..).ok_or_eyre("Buffer overflow")?);
+            let data_hash = keccak256((data_hash, U256::from(chain_id)).abi_encode());
+            Ok(eip191_hash_message(data_hash))
+        }
+        SIG_VERSION_EIP712 => typed_data_hash(bin, &dispatcher_domain(chain_id, dispatcher), None),
+        SIG_VERSION_ERC1271 => {
+            let wallet = Address::from_slice(bin.get(..20).ok_or_eyre("Buffer overflow")?);
+            typed_data_hash(bin, &dispatcher_domain(chain_id, dispatcher), Some(wallet))
+        }
+        _ => bail!("Invalid signature version {version}"),
+    }
+}
+
+/// The EIP-712 hash which the signer of a PayAfter signs to cancel it off-chain
+pub fn cancel_hash(chain_id: u64, dispatcher: Address, signer: Address, data_hash: B256) -> B256 {
+    PayAfterCancel { signer, dataHash: data_hash }
+        .eip712_signing_hash(&dispatcher_domain(chain_id, dispatcher))
+}
+
+/// Sign an off-chain cancellation of the signer's PayAfter with this data hash
+pub fn sign_cancel(
+    signer: &PrivateKeySigner,
+    chain_id: u64,
+    dispatcher: Address,
+    data_hash: B256,
+) -> Result<Bytes> {
+    let hash = cancel_hash(chain_id, dispatcher, signer.address(), data_hash);
+    Ok(signer.sign_hash_sync(&hash)?.as_bytes().into())
+}
+
+/// Get the signature version and the signature
+pub fn parse_signature(bin: &[u8]) -> Result<(u8, TxnSignature)> {
+    let version = sig_version(bin)?;
+    if version == SIG_VERSION_ERC1271 {
+        let signed = signed_data(bin)?;
+        return Ok((version, TxnSignature::Erc1271 {
+            signer: Address::from_slice(&bin[..20]),
+            signature: Bytes::copy_from_slice(&bin[signed.len()..]),
+        }));
+    }
+    let mut sig: [u8; SIG_LEN] = bin[..SIG_LEN].try_into()?;
+    sig[SIG_LEN - 1] = 27 + ((sig[SIG_LEN - 1] - 27) & 1);
+    Ok((version, TxnSignature::Ecdsa(PrimitiveSignature::try_from(&sig[..])?)))
+}
+
+/// Build a PayAfter with an empty signature field
+fn build_unsigned(
+    signer: Address,
+    header: &Header,
+    fees: &[(U256, u64)],
+    calls: &[SpecCall],
+) -> Result<Vec<u8>> {
+    if fees.is_empty() {
+        bail!("At least one fee entry is required");
+    }
+    let mut bin = vec![0u8; SIG_LEN];
+    bin.extend_from_slice(&signer[17..]);
+    header.encode(&mut bin)?;
+    let mut last_time = None;
+    for (i, (amount, after_seconds)) in fees.iter().enumerate() {
+        if last_time.is_some_and(|t|t >= *after_seconds) {
+            bail!("Each fee entry time must be further in the future than the last");
+        }
+        last_time = Some(*after_seconds);
+        let mut packed = pack_fee(*amount, *after_seconds)?;
+        if i == fees.len() - 1 {
+            packed |= 1 << 31;
+        }
+        bin.extend_from_slice(&packed.to_be_bytes());
+    }
+    for c in calls {
+        let flags = if c.allow_failure { CALL_FLAG_ALLOW_FAILURE } else { 0 };
+        let raw = RawCall { target: c.target, flags, value: c.value, data: &c.data };
+        encode_call(&mut bin, header.format, &raw)?;
+    }
+    Ok(bin)
+}
+
+/// Build and sign a PayAfter. Each fee is (amount, seconds after the creation time), U256::MAX
+/// being a kill fee, amounts are rounded down to what can be represented.
+pub fn sign_payafter(
+    signer: &PrivateKeySigner,
+    version: u8,
+    chain_id: u64,
+    dispatcher: Address,
+    header: &Header,
+    fees: &[(U256, u64)],
+    calls: &[SpecCall],
+) -> Result<Bytes> {
+    if version == SIG_VERSION_ERC1271 {
+        bail!("Use sign_payafter_erc1271() for a smart contract wallet");
+    }
+    let mut bin = build_unsigned(signer.address(), header, fees, calls)?;
+    bin[SIG_LEN - 1] = 27 + (version << 1);
+    let hash = signed_hash(version, &bin, chain_id, dispatcher)?;
+    let mut sig = signer.sign_hash_sync(&hash)?.as_bytes();
+    sig[SIG_LEN - 1] += version << 1;
+    bin[..SIG_LEN].copy_from_slice(&sig);
+    Ok(bin.into())
+}
+
+/// Build a PayAfter for a smart contract wallet, `sign` is given the EIP-712 hash and must
+/// return a signature which the wallet's isValidSignature() accepts.
+#[allow(clippy::too_many_arguments)]
+pub fn sign_payafter_erc1271(
+    wallet: Address,
+    chain_id: u64,
+    dispatcher: Address,
+    header: &Header,
+    fees: &[(U256, u64)],
+    calls: &[SpecCall],
+    sign: impl FnOnce(B256) -> Result<Bytes>,
+) -> Result<Bytes> {
+    let mut bin = build_unsigned(wallet, header, fees, calls)?;
+    bin[SIG_LEN - 1] = 27 + (SIG_VERSION_ERC1271 << 1);
+    bin[..20].copy_from_slice(wallet.as_slice());
+    let hash = signed_hash(SIG_VERSION_ERC1271, &bin, chain_id, dispatcher)?;
+    let sig = sign(hash)?;
+    let sig_len = u16::try_from(sig.len()).context("Signature is too long")?;
+    bin[20..22].copy_from_slice(&sig_len.to_be_bytes());
+    bin.extend_from_slice(&sig);
+    Ok(bin.into())
+}
+
+#[derive(Deserialize)]
+pub struct SpecFee {
+    /// In wei, or "kill" for the fee which invalidates the transaction
+    pub amount: String,
+    #[serde(default)]
+    pub after_seconds: u64,
+}
+
+#[derive(Deserialize, Clone, Default)]
+pub struct SpecCall {
+    pub target: Address,
+    /// In wei, sending value requires format version 2
+    #[serde(default)]
+    pub value: U256,
+    /// Carry on with the transaction if this call reverts, requires format version 2
+    #[serde(default)]
+    pub allow_failure: bool,
+    pub data: Bytes,
+}
+
+/// Only `pollinator` may dispatch during the first `seconds` after the creation time
+#[derive(Deserialize)]
+pub struct SpecExclusive {
+    pub pollinator: Address,
+    pub seconds: u32,
+}
+
+fn default_eip712() -> bool { true }
+fn default_format() -> u8 { FORMAT_V1 }
+
+/// What to sign with the sign-payafter command
+#[derive(Deserialize)]
+pub struct SignSpec {
+    pub chain_id: u64,
+    /// Defaults to the deterministic PayAfterDispatcher address
+    #[serde(default)]
+    pub dispatcher: Option<Address>,
+    /// Sign as EIP-712 typed data, otherwise use personal_sign
+    #[serde(default = "default_eip712")]
+    pub eip712: bool,
+    /// Format version 2 has a 64 bit timestamp, dispatchers from before it only accept 1
+    #[serde(default = "default_format")]
+    pub format: u8,
+    /// Defaults to now
+    #[serde(default)]
+    pub create_time: Option<u64>,
+    /// Gas which the dispatch must start with, so that a pollinator cannot starve the calls.
+    /// Requires format version 2.
+    #[serde(default)]
+    pub min_gas: Option<u32>,
+    /// Data hash of another transaction from the same signer which must run first. Requires
+    /// format version 2.
+    #[serde(default)]
+    pub requires: Option<B256>,
+    /// Keep others from front-running the pollinator it is sent to. Requires format version 2.
+    #[serde(default)]
+    pub exclusive: Option<SpecExclusive>,
+    /// Pay the fees in this ERC20 token rather than the base coin, the amounts are then in its
+    /// smallest unit. Requires format version 2.
+    #[serde(default)]
+    pub fee_token: Option<Address>,
+    pub fees: Vec<SpecFee>,
+    pub calls: Vec<SpecCall>,
+}
+
+pub fn sign_payafter_cmd(spec_path: PathBuf) -> Result<()> {
+    let spec = std::fs::read_to_string(&spec_path)
+        .with_context(|| format!("Reading {}", spec_path.display()))?;
+    let spec: SignSpec = serde_yaml::from_str(&spec)?;
+    let fees = spec.fees.iter()
+        .map(|f| {
+            let amount = if f.amount == "kill" {
+                U256::MAX
+            } else {
+                f.amount.parse().with_context(|| format!("Invalid fee amount {}", f.amount))?
+            };
+            Ok((amount, f.after_seconds))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let key = rpassword::prompt_password("Enter signer private key: ")?;
+    let signer: PrivateKeySigner = key.trim().parse().context("Invalid private key")?;
+    let create_time = spec.create_time.unwrap_or_else(now_sec);
+    let mut header = match spec.format {
+        FORMAT_V1 => Header::v1(create_time.try_into().context("create_time needs format 2")?),
+        FORMAT_V2 => Header::v2(create_time, 0, Vec::new()),
+        f => bail!("Unsupported format version {f}"),
+    };
+    if let Some(gas) = spec.min_gas {
+        header.set_option(FLAG_MIN_GAS, &gas.to_be_bytes())?;
+    }
+    if let Some(requires) = spec.requires {
+        header.set_option(FLAG_REQUIRES, requires.as_slice())?;
+    }
+    if let Some(ex) = &spec.exclusive {
+        header.set_exclusive(ex.pollinator, ex.seconds)?;
+    }
+    if let Some(token) = spec.fee_token {
+        header.set_option(FLAG_FEE_TOKEN, token.as_slice())?;
+    }
+    let version = if spec.eip712 { SIG_VERSION_EIP712 } else { SIG_VERSION_PERSONAL };
+    let dispatcher = spec.dispatcher.unwrap_or(PAYAFTER_DISPATCHER_ADDR);
+    let bin = sign_payafter(
+        &signer,
+        version,
+        spec.chain_id,
+        dispatcher,
+        &header,
+        &fees,
+        &spec.calls,
+    )?;
+    // On stderr so that the output is still just the transaction, this is what another
+    // transaction's `requires` refers to
+    eprintln!("Data hash: {}", signed_hash(version, &bin, spec.chain_id, dispatcher)?);
+    println!("{bin}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloy::{
+        primitives::{Address, Bytes, PrimitiveSignature, B256, U256},
+        signers::{local::PrivateKeySigner, SignerSync},
+    };
+    use alloy_sol_types::SolStruct;
+
+    use crate::{
+        config::Config,
+        fee::{Header, FLAG_FEE_TOKEN, FLAG_MIN_GAS, FLAG_REQUIRES, FORMAT_V1, FORMAT_V2},
+        generate::PAYAFTER_DISPATCHER_ADDR,
+        payafter::parse_transaction,
+    };
+
+    use super::{
+        cancel_hash, parse_signature, sign_cancel, sign_payafter, sign_payafter_erc1271,
+        split_calls, PayAfter, PayAfterV2, SpecCall, SpecExclusive, TxnSignature,
+        CALL_FLAG_ALLOW_FAILURE, SIG_VERSION_EIP712, SIG_VERSION_PERSONAL,
+    };
+
+    #[test]
+    fn test_sign_and_parse() {
+        // Hardhat account #1
+        let signer: PrivateKeySigner =
+            "0x59c6995e998f97a5a0044966f0945389dc9e86dae88c7a8412f4603b6b78690d".parse().unwrap();
+        let fees = [(U256::ZERO, 0), (U256::from(1000), 3600), (U256::MAX, 86400)];
+        let calls = [SpecCall {
+            target: Address::repeat_byte(0x11),
+            data: Bytes::from(vec![0xa9, 0x05, 0x9c, 0xbb, 1, 2]),
+            ..Default::default()
+        }];
+        let headers = [Header::v1(1_700_000_000), Header::v2(1_700_000_000, 0, Vec::new())];
+        for (version, header) in [SIG_VERSION_PERSONAL, SIG_VERSION_EIP712].iter()
+            .flat_map(|v|headers.iter().map(move |h|(*v, h)))
+        {
+            let bin = sign_payafter(
+                &signer, version, 31337, PAYAFTER_DISPATCHER_ADDR, header, &fees, &calls,
+            ).unwrap();
+            let txn = parse_transaction(&Config{ chain_id: 31337, ..Default::default() }, bin).unwrap();
+            assert_eq!(txn.signer, signer.address());
+            assert_eq!(txn.format, header.format);
+            assert_eq!(txn.create_time, 1_700_000_000);
+            assert_eq!(txn.when_expires(), 1_700_000_000 + 86400);
+
+            // Signed for a different chain, a different signer is recovered
+            let bin = sign_payafter(
+                &signer, version, 1, PAYAFTER_DISPATCHER_ADDR, header, &fees, &calls,
+            ).unwrap();
+            assert!(parse_transaction(&Config{ chain_id: 31337, ..Default::default() }, bin).is_err());
+        }
+        assert_eq!(
+            PayAfter::eip712_encode_type(),
+            "PayAfter(uint64 creationTime,Fee[] fees,Call[] calls)Call(address target,bytes data)Fee(uint256 amount,uint64 afterSeconds)",
+        );
+        assert_eq!(
+            PayAfterV2::eip712_encode_type(),
+            "PayAfterV2(uint64 creationTime,uint32 flags,bytes options,Fee[] fees,CallV2[] calls)CallV2(address target,uint8 flags,uint256 value,bytes data)Fee(uint256 amount,uint64 afterSeconds)",
+        );
+        assert!(
+            Header::v1(1 << 20).encode(&mut Vec::new()).is_err(),
+            "A zero first byte would be read as a versioned header",
+        );
+
+        // A plain transfer of value to an EOA
+        let send = [calls[0].clone(), SpecCall {
+            target: Address::repeat_byte(0x33),
+            value: U256::from(12345),
+            ..Default::default()
+        }];
+        assert!(sign_payafter(
+            &signer, SIG_VERSION_EIP712, 31337, PAYAFTER_DISPATCHER_ADDR, &headers[0], &fees, &send,
+        ).is_err(), "Format version 1 cannot send value");
+        let bin = sign_payafter(
+            &signer, SIG_VERSION_EIP712, 31337, PAYAFTER_DISPATCHER_ADDR, &headers[1], &fees, &send,
+        ).unwrap();
+        let txn = parse_transaction(&Config{ chain_id: 31337, ..Default::default() }, bin).unwrap();
+        assert_eq!(txn.signer, signer.address());
+        let values = txn.calls.iter().map(|c|c.value).collect::<Vec<_>>();
+        assert_eq!(values, vec![U256::ZERO, U256::from(12345)]);
+        assert_eq!(txn.calls[1].selector, None);
+
+        // An optional call, with and without a value
+        let optional = [send[0].clone(), send[1].clone()]
+            .map(|c|SpecCall { allow_failure: true, ..c });
+        assert!(sign_payafter(
+            &signer, SIG_VERSION_EIP712, 31337, PAYAFTER_DISPATCHER_ADDR, &headers[0], &fees,
+            &optional[..1],
+        ).is_err(), "Format version 1 has no call flags");
+        let bin = sign_payafter(
+            &signer, SIG_VERSION_EIP712, 31337, PAYAFTER_DISPATCHER_ADDR, &headers[1], &fees,
+            &optional,
+        ).unwrap();
+        let txn = parse_transaction(&Config{ chain_id: 31337, ..Default::default() }, bin).unwrap();
+        assert!(txn.calls.iter().all(|c|c.allow_failure));
+        assert_eq!(txn.calls[1].value, U256::from(12345));
+    }
+
+    #[test]
+    fn test_erc1271() {
+        let owner: PrivateKeySigner =
+            "0x59c6995e998f97a5a0044966f0945389dc9e86dae88c7a8412f4603b6b78690d".parse().unwrap();
+        let wallet = Address::repeat_byte(0x22);
+        let fees = [(U256::from(1000), 0), (U256::MAX, 86400)];
+        let calls = [SpecCall {
+            target: Address::repeat_byte(0x11),
+            data: Bytes::from(vec![0xa9, 0x05, 0x9c, 0xbb]),
+            ..Default::default()
+        }];
+        let mut signed = Default::default();
+        let bin = sign_payafter_erc1271(
+            wallet, 31337, PAYAFTER_DISPATCHER_ADDR, &Header::v1(1_700_000_000), &fees, &calls,
+            |hash| {
+                signed = hash;
+                Ok(Bytes::from(owner.sign_hash_sync(&hash)?.as_bytes().to_vec()))
+            },
+        ).unwrap();
+        let txn = parse_transaction(&Config{ chain_id: 31337, ..Default::default() }, bin).unwrap();
+        assert_eq!(txn.signer, wallet, "The wallet is the signer, not its owner");
+        assert_eq!(txn.data_hash, signed);
+        assert_eq!(txn.erc1271_signature.as_ref().map(|s|s.len()), Some(65));
+        assert_eq!(txn.calls.len(), 1, "The wallet signature is not mistaken for a call");
+        assert_eq!(txn.when_expires(), 1_700_000_000 + 86400);
+
+        // The owner's signature for the wallet is not valid for the same PayAfter from the
+        // owner's own address, nor from another wallet
+        let bin = sign_payafter(
+            &owner, SIG_VERSION_EIP712, 31337, PAYAFTER_DISPATCHER_ADDR, &Header::v1(1_700_000_000),
+            &fees, &calls,
+        ).unwrap();
+        let eoa = parse_transaction(&Config{ chain_id: 31337, ..Default::default() }, bin.clone())
+            .unwrap();
+        assert_eq!(eoa.signer, owner.address());
+        assert_ne!(eoa.data_hash, signed);
+        // So neither signature passes as the other: the wallet is asked about `signed`, which
+        // the owner's EOA signature is not for, and the reverse
+        let TxnSignature::Ecdsa(eoa_sig) = parse_signature(&bin).unwrap().1 else { panic!() };
+        assert_ne!(eoa_sig.recover_address_from_prehash(&signed).ok(), Some(owner.address()));
+        let wallet_sig = txn.erc1271_signature.as_ref().unwrap();
+        let wallet_sig = PrimitiveSignature::try_from(&wallet_sig[..]).unwrap();
+        assert_ne!(wallet_sig.recover_address_from_prehash(&eoa.data_hash).ok(),
+            Some(owner.address()));
+        let other = sign_payafter_erc1271(
+            Address::repeat_byte(0x33), 31337, PAYAFTER_DISPATCHER_ADDR, &Header::v1(1_700_000_000),
+            &fees, &calls, |hash| Ok(Bytes::from(owner.sign_hash_sync(&hash)?.as_bytes().to_vec())),
+        ).unwrap();
+        let other = parse_transaction(&Config{ chain_id: 31337, ..Default::default() }, other)
+            .unwrap();
+        assert_ne!(other.data_hash, signed);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let signer: PrivateKeySigner =
+            "0x59c6995e998f97a5a0044966f0945389dc9e86dae88c7a8412f4603b6b78690d".parse().unwrap();
+        let data_hash = B256::repeat_byte(0x42);
+        let sig = sign_cancel(&signer, 31337, PAYAFTER_DISPATCHER_ADDR, data_hash).unwrap();
+        let sig = PrimitiveSignature::try_from(&sig[..]).unwrap();
+        let hash = cancel_hash(31337, PAYAFTER_DISPATCHER_ADDR, signer.address(), data_hash);
+        assert_eq!(sig.recover_address_from_prehash(&hash).unwrap(), signer.address());
+        // Bound to the chain and the transaction
+        assert_ne!(hash, cancel_hash(1, PAYAFTER_DISPATCHER_ADDR, signer.address(), data_hash));
+        assert_ne!(hash, cancel_hash(31337, PAYAFTER_DISPATCHER_ADDR, signer.address(), B256::ZERO));
+    }
+
+    #[derive(serde::Deserialize)]
+    struct VectorFee {
+        amount: String,
+        after: u64,
+        unit: String,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Vector {
+        name: String,
+        format: u8,
+        mode: String,
+        creation_time: u64,
+        #[serde(default)]
+        min_gas: Option<u32>,
+        #[serde(default)]
+        requires: Option<B256>,
+        #[serde(default)]
+        exclusive: Option<SpecExclusive>,
+        #[serde(default)]
+        fee_token: Option<Address>,
+        fees: Vec<VectorFee>,
+        calls: Vec<Bytes>,
+        data_hash: B256,
+        expires: u64,
+        bin: Bytes,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Vectors {
+        chain_id: u64,
+        /// Must be the deployed one, parse_transaction() only knows that
+        dispatcher: Address,
+        private_key: String,
+        vectors: Vec<Vector>,
+    }
+
+    /// The same vectors are checked against the TypeScript library and the dispatcher in
+    /// test/payafter.js
+    #[test]
+    fn test_vectors() {
+        let vs: Vectors =
+            serde_json::from_str(include_str!("../test/vectors/payafter_format.json")).unwrap();
+        let signer: PrivateKeySigner = vs.private_key.parse().unwrap();
+        for v in vs.vectors {
+            let fees = v.fees.iter()
+                .map(|f| {
+                    let unit = match f.unit.as_str() {
+                        "seconds" => 1,
+                        "minutes" => 60,
+                        "hours" => 60 * 60,
+                        "days" => 60 * 60 * 24,
+                        u => panic!("{}: unknown unit {u}", v.name),
+                    };
+                    let amount =
+                        if f.amount == "kill" { U256::MAX } else { f.amount.parse().unwrap() };
+                    (amount, f.after * unit)
+                })
+                .collect::<Vec<_>>();
+            // Vector calls are encoded by prepareCall(), which uses format version 2
+            let calls = v.calls.iter()
+                .flat_map(|c|split_calls(c, FORMAT_V2).unwrap())
+                .map(|c|SpecCall {
+                    target: c.target,
+                    value: c.value,
+                    allow_failure: c.flags & CALL_FLAG_ALLOW_FAILURE != 0,
+                    data: Bytes::copy_from_slice(c.data),
+                })
+                .collect::<Vec<_>>();
+            let mut header = match v.format {
+                FORMAT_V1 => Header::v1(v.creation_time.try_into().unwrap()),
+                FORMAT_V2 => Header::v2(v.creation_time, 0, Vec::new()),
+                f => panic!("{}: unknown format {f}", v.name),
+            };
+            if let Some(gas) = v.min_gas {
+                header.set_option(FLAG_MIN_GAS, &gas.to_be_bytes()).unwrap();
+            }
+            if let Some(requires) = v.requires {
+                header.set_option(FLAG_REQUIRES, requires.as_slice()).unwrap();
+            }
+            if let Some(ex) = &v.exclusive {
+                header.set_exclusive(ex.pollinator, ex.seconds).unwrap();
+            }
+            if let Some(token) = v.fee_token {
+                header.set_option(FLAG_FEE_TOKEN, token.as_slice()).unwrap();
+            }
+            let version = if v.mode == "eip712" { SIG_VERSION_EIP712 } else { SIG_VERSION_PERSONAL };
+            let bin = sign_payafter(
+                &signer, version, vs.chain_id, vs.dispatcher, &header, &fees, &calls,
+            ).unwrap();
+            assert_eq!(bin, v.bin, "{}", v.name);
+
+            let txn = parse_transaction(&Config{ chain_id: vs.chain_id as _, ..Default::default() }, bin)
+                .unwrap();
+            assert_eq!(txn.signer, signer.address(), "{}", v.name);
+            assert_eq!(txn.data_hash, v.data_hash, "{}", v.name);
+            assert_eq!(txn.format, v.format, "{}", v.name);
+            assert_eq!(txn.create_time, v.creation_time, "{}", v.name);
+            assert_eq!(txn.when_expires(), v.expires, "{}", v.name);
+            assert_eq!(txn.min_gas, v.min_gas.map(|g|g as u64), "{}", v.name);
+            assert_eq!(txn.requires, v.requires, "{}", v.name);
+            assert_eq!(txn.fee_token, v.fee_token, "{}", v.name);
+            assert_eq!(
+                txn.exclusive,
+                v.exclusive.map(|ex|(ex.pollinator, v.creation_time + ex.seconds as u64)),
+                "{}", v.name,
+            );
+        }
+    }
+}
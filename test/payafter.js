@@ -2,10 +2,11 @@ const {
   time,
   loadFixture,
 } = require("@nomicfoundation/hardhat-toolbox/network-helpers");
-// const { anyValue } = require("@nomicfoundation/hardhat-chai-matchers/withArgs");
+const { anyValue } = require("@nomicfoundation/hardhat-chai-matchers/withArgs");
 const { expect } = require("chai");
-const { prepareCall, signCalls, estimateGasCustom, makeFee, makeInvalid } = require('../dist/pollinate.js').PayAfter;
+const { prepareCall, prepareRawCall, signCalls, signCallsWithHash, signCancel, estimateGasCustom, makeFee, makeInvalid } = require('../dist/pollinate.js').PayAfter;
 const { deployTokenAndLp } = require('./general.js');
+const VECTORS = require('./vectors/payafter_format.json');
 
 async function deploy() {
   // Contracts are deployed using the first signer/account by default
@@ -109,6 +110,396 @@ describe("PayAfterDispatcher", function () {
       await expect(pad.dispatch(signed, '0x')).to.be.revertedWith("Already executed or killed");
     });
 
+    it("Can dispatch with an EIP-712 signature", async function () {
+      const { pad, mockCallable, owner, otherAccount } = await loadFixture(deploy);
+      const padAddr = await pad.getAddress();
+      const { chainId } = await ethers.provider.getNetwork();
+      expect(await pad.domainSeparator()).to.equal(ethers.TypedDataEncoder.hashDomain({
+        name: 'PayAfterDispatcher',
+        version: '1',
+        chainId,
+        verifyingContract: padAddr,
+      }));
+
+      const calls = [
+        await prepareCall(mockCallable, "callMeMaybe", [123]),
+        await prepareCall(mockCallable, "callMeMaybe", [456]),
+      ];
+      const fees = [
+        makeFee(0n),
+        makeFee(10n).after(25).seconds,
+        makeInvalid().after(10).minutes,
+      ];
+      const opts = { mode: 'eip712', dispatcher: padAddr };
+      console.log('Gas: ', await estimateGasCustom(otherAccount, calls, fees, pad, 'eip712'));
+      const signed = await signCalls(otherAccount, calls, fees, opts);
+
+      await pad.dispatch(signed, '0x');
+      expect(await mockCallable.total()).to.equal(579);
+      expect(await mockCallable.lastCaller()).to.equal(await otherAccount.getAddress());
+      await expect(pad.dispatch(signed, '0x')).to.be.revertedWith("Already executed or killed");
+
+      // Signed for another dispatcher, a different address is recovered
+      const other = await signCalls(otherAccount, calls, fees,
+        { mode: 'eip712', dispatcher: await mockCallable.getAddress() });
+      await expect(pad.dispatch(other, '0x')).to.be.revertedWith("Corrupt signature");
+    });
+
+    it("Can dispatch for an ERC-1271 wallet", async function () {
+      const { pad, mockCallable, owner, otherAccount } = await loadFixture(deploy);
+      const MockERC1271Wallet = await ethers.getContractFactory("MockERC1271Wallet");
+      const wallet = await MockERC1271Wallet.deploy(await otherAccount.getAddress());
+      const walletAddr = await wallet.getAddress();
+
+      const calls = [
+        await prepareCall(mockCallable, "callMeMaybe", [123]),
+      ];
+      const fees = [
+        makeFee(0n),
+        makeInvalid().after(10).minutes,
+      ];
+      const opts = { mode: 'erc1271', dispatcher: await pad.getAddress(), wallet: walletAddr };
+
+      // Signed by someone other than the wallet's owner
+      const bad = await signCalls(owner, calls, fees, opts);
+      await expect(pad.dispatch(bad, '0x')).to.be.revertedWith("Invalid ERC-1271 signature");
+
+      const signed = await signCalls(otherAccount, calls, fees, opts);
+      await pad.dispatch(signed, '0x');
+      expect(await mockCallable.total()).to.equal(123);
+      expect(await mockCallable.lastCaller()).to.equal(walletAddr);
+      await expect(pad.dispatch(signed, '0x')).to.be.revertedWith("Already executed or killed");
+    });
+
+    it("Does not accept a wallet owner's signature as another signer's", async function () {
+      const { pad, mockCallable, otherAccount } = await loadFixture(deploy);
+      const MockERC1271Wallet = await ethers.getContractFactory("MockERC1271Wallet");
+      const wallet = await MockERC1271Wallet.deploy(await otherAccount.getAddress());
+      const walletAddr = await wallet.getAddress();
+      const calls = [
+        await prepareCall(mockCallable, "callMeMaybe", [123]),
+      ];
+      const fees = [
+        makeFee(0n),
+        makeInvalid().after(10).minutes,
+      ];
+      const opts = {
+        dispatcher: await pad.getAddress(),
+        creationTime: (await ethers.provider.getBlock('latest')).timestamp,
+      };
+      const forWallet = await signCalls(otherAccount, calls, fees,
+        { ...opts, mode: 'erc1271', wallet: walletAddr });
+      const forOwner = await signCalls(otherAccount, calls, fees, { ...opts, mode: 'eip712' });
+
+      // The owner's own signature given to the wallet, with v back to 27 / 28
+      const sigLen = 65 * 2;
+      const ownerV = parseInt(forOwner.slice(130, 132), 16) - 2;
+      const toWallet = forWallet.slice(0, -sigLen) + forOwner.slice(2, 130) + ownerV.toString(16);
+      await expect(pad.dispatch(toWallet, '0x')).to.be.revertedWith("Invalid ERC-1271 signature");
+
+      // The signature made for the wallet used as the owner's
+      const walletSig = forWallet.slice(-sigLen);
+      const walletV = parseInt(walletSig.slice(128), 16) + 2;
+      const toOwner = '0x' + walletSig.slice(0, 128) + walletV.toString(16) + forOwner.slice(132);
+      await expect(pad.dispatch(toOwner, '0x')).to.be.revertedWith("Corrupt signature");
+
+      await pad.dispatch(forWallet, '0x');
+      await pad.dispatch(forOwner, '0x');
+      expect(await mockCallable.total()).to.equal(246);
+    });
+
+    it("Can dispatch format version 2", async function () {
+      const { pad, mockCallable, owner, otherAccount } = await loadFixture(deploy);
+      const calls = [
+        await prepareCall(mockCallable, "callMeMaybe", [123]),
+      ];
+      const fees = [
+        makeFee(0n),
+        makeInvalid().after(10).minutes,
+      ];
+      for (const mode of ['personal', 'eip712']) {
+        const signed = await signCalls(otherAccount, calls, fees,
+          { mode, format: 2, dispatcher: await pad.getAddress() });
+        await pad.dispatch(signed, '0x');
+        expect(await mockCallable.lastCaller()).to.equal(await otherAccount.getAddress());
+      }
+      expect(await mockCallable.total()).to.equal(246);
+    });
+
+    it("Can send value from a call", async function () {
+      const { pad, mockCallable, owner, otherAccount } = await loadFixture(deploy);
+      await owner.sendTransaction({ to: await mockCallable.getAddress(), value: ethers.parseEther('1') });
+      const recipient = ethers.Wallet.createRandom().address;
+      const fees = [
+        makeFee(0n),
+        makeInvalid().after(10).minutes,
+      ];
+      const opts = { format: 2, dispatcher: await pad.getAddress() };
+
+      // Nothing has been paid to the dispatcher yet
+      const send = prepareRawCall(recipient, '0x', { value: ethers.parseEther('0.4') });
+      const unfunded = await signCalls(otherAccount, [send], fees, opts);
+      await expect(pad.dispatch(unfunded, '0x')).to.be.revertedWithCustomError(pad, "ValueNotCovered");
+
+      // Format 1 has no room for a value
+      let err;
+      try { await signCalls(otherAccount, [send], fees); } catch (e) { err = e; }
+      expect(err?.message).to.contain("requires format 2");
+
+      const calls = [
+        await prepareCall(mockCallable, "payOut", [ethers.parseEther('1')]),
+        send,
+      ];
+      const signed = await signCalls(otherAccount, calls, fees, opts);
+      const before = await ethers.provider.getBalance(otherAccount.address);
+      await pad.dispatch(signed, '0x');
+      expect(await ethers.provider.getBalance(recipient)).to.equal(ethers.parseEther('0.4'));
+      // The rest is refunded to the signer
+      expect(await ethers.provider.getBalance(otherAccount.address) - before)
+        .to.equal(ethers.parseEther('0.6'));
+    });
+
+    it("Can skip a failing optional call", async function () {
+      const { pad, mockCallable, owner, otherAccount } = await loadFixture(deploy);
+      const fees = [
+        makeFee(0n),
+        makeInvalid().after(10).minutes,
+      ];
+      const opts = { format: 2, dispatcher: await pad.getAddress() };
+
+      const required = [
+        await prepareCall(mockCallable, "callMeMaybe", [1]),
+        await prepareCall(mockCallable, "failMe", []),
+      ];
+      const failing = await signCalls(otherAccount, required, fees, opts);
+      await expect(pad.dispatch(failing, '0x')).to.be.revertedWith("Not today");
+
+      const calls = [
+        await prepareCall(mockCallable, "callMeMaybe", [1]),
+        await prepareCall(mockCallable, "failMe", [], { allowFailure: true }),
+        await prepareCall(mockCallable, "callMeMaybe", [2]),
+      ];
+      const signed = await signCalls(otherAccount, calls, fees, opts);
+      await expect(pad.dispatch(signed, '0x')).to.emit(pad, "PayAfterCallFailed");
+      expect(await mockCallable.total()).to.equal(3);
+    });
+
+    it("Refuses to dispatch with less than the signed minimum gas", async function () {
+      const { pad, mockCallable, owner, otherAccount } = await loadFixture(deploy);
+      const calls = [
+        await prepareCall(mockCallable, "callMeMaybe", [123]),
+      ];
+      const fees = [
+        makeFee(0n),
+        makeInvalid().after(10).minutes,
+      ];
+      const signed = await signCalls(otherAccount, calls, fees,
+        { format: 2, minGas: 500000, dispatcher: await pad.getAddress() });
+      await expect(pad.dispatch(signed, '0x', { gasLimit: 300000 })).to.be.revertedWith("Not enough gas");
+      await pad.dispatch(signed, '0x', { gasLimit: 600000 });
+      expect(await mockCallable.total()).to.equal(123);
+
+      let err;
+      try { await signCalls(otherAccount, calls, fees, { minGas: 500000 }); } catch (e) { err = e; }
+      expect(err?.message).to.contain("requires format 2");
+    });
+
+    it("Waits for a required transaction to run", async function () {
+      const { pad, mockCallable, otherAccount } = await loadFixture(deploy);
+      const fees = [
+        makeFee(0n),
+        makeInvalid().after(10).minutes,
+      ];
+      const opts = { format: 2, dispatcher: await pad.getAddress() };
+      const a = await signCallsWithHash(otherAccount,
+        [ await prepareCall(mockCallable, "callMeMaybe", [100]) ], fees, opts);
+      const b = await signCalls(otherAccount,
+        [ await prepareCall(mockCallable, "callMeMaybe", [20]) ], fees,
+        { ...opts, requires: a.dataHash });
+
+      await expect(pad.dispatch(b, '0x')).to.be.revertedWith("Required transaction has not run");
+      await expect(pad.dispatch(a.signed, '0x')).to.emit(pad, "PayAfter")
+        .withArgs(a.dataHash, otherAccount.address, anyValue);
+      await pad.dispatch(b, '0x');
+      expect(await mockCallable.total()).to.equal(120);
+
+      let err;
+      try { await signCalls(otherAccount, [], fees, { requires: a.dataHash }); } catch (e) { err = e; }
+      expect(err?.message).to.contain("requires needs format 2");
+    });
+
+    it("Only lets the exclusive pollinator dispatch during its window", async function () {
+      const { pad, mockCallable, otherAccount } = await loadFixture(deploy);
+      const [, , pollinator, frontRunner] = await ethers.getSigners();
+      const calls = [
+        await prepareCall(mockCallable, "callMeMaybe", [123]),
+      ];
+      const fees = [
+        makeFee(0n),
+        makeInvalid().after(20).minutes,
+      ];
+      const opts = {
+        format: 2,
+        dispatcher: await pad.getAddress(),
+        exclusive: { pollinator: pollinator.address, seconds: 600 },
+      };
+      const first = await signCalls(otherAccount, calls, fees, opts);
+      await expect(pad.connect(frontRunner).dispatch(first, '0x'))
+        .to.be.revertedWith("Exclusive to another pollinator");
+      await pad.connect(pollinator).dispatch(first, '0x');
+      expect(await mockCallable.total()).to.equal(123);
+
+      const second = await signCalls(otherAccount, calls, fees, opts);
+      await time.increase(600);
+      await pad.connect(frontRunner).dispatch(second, '0x');
+      expect(await mockCallable.total()).to.equal(246);
+    });
+
+    it("Can pay the fee in a token", async function () {
+      const { pad, mockCallable, owner, otherAccount } = await loadFixture(deploy);
+      const MockWETH = await ethers.getContractFactory("MockWETH");
+      const feeToken = await MockWETH.deploy();
+      await feeToken.connect(otherAccount).deposit({ value: 10000n });
+
+      const calls = [
+        await prepareCall(mockCallable, "callMeMaybe", [123]),
+      ];
+      const fees = [
+        makeFee(1000n),
+        makeInvalid().after(10).minutes,
+      ];
+      const opts = {
+        format: 2,
+        dispatcher: await pad.getAddress(),
+        feeToken: await feeToken.getAddress(),
+      };
+      const signed = await signCalls(otherAccount, calls, fees, opts);
+      await expect(pad.dispatch(signed, '0x'))
+        .to.be.revertedWithCustomError(pad, "FeeTokenNotCovered");
+
+      const collector = await pad.feeCollector();
+      await feeToken.connect(otherAccount).approve(collector, 1000n);
+      await pad.dispatch(signed, '0x');
+      expect(await mockCallable.total()).to.equal(123);
+      expect(await feeToken.balanceOf(owner.address)).to.equal(1000n);
+      expect(await feeToken.balanceOf(otherAccount.address)).to.equal(9000n);
+
+      // A token which supports PayAfter takes the approval the same way
+      const MockToken = await ethers.getContractFactory("MockToken");
+      const payAfterToken = await MockToken.deploy(await pad.getAddress());
+      await payAfterToken.transfer(otherAccount.address, 5000n);
+      await payAfterToken.connect(otherAccount).approve(collector, 1000n);
+      const signed2 = await signCalls(otherAccount, calls, fees,
+        { ...opts, feeToken: await payAfterToken.getAddress() });
+      await pad.dispatch(signed2, '0x');
+      expect(await mockCallable.total()).to.equal(246);
+      expect(await payAfterToken.balanceOf(otherAccount.address)).to.equal(4000n);
+    });
+
+    it("Does not let a transaction take another signer's fee approval", async function () {
+      const { pad, mockCallable, owner, otherAccount } = await loadFixture(deploy);
+      const MockWETH = await ethers.getContractFactory("MockWETH");
+      const feeToken = await MockWETH.deploy();
+      await feeToken.deposit({ value: 10000n });
+      const collector = await ethers.getContractAt("PayAfterFeeCollector", await pad.feeCollector());
+      await feeToken.approve(await collector.getAddress(), 10000n);
+
+      await expect(collector.connect(otherAccount).collect(
+        await feeToken.getAddress(), owner.address, otherAccount.address, 10000n))
+        .to.be.revertedWith("Only the dispatcher");
+
+      // The call comes from the dispatcher, but otherAccount is the signer
+      const calls = [
+        await prepareCall(collector, "collect",
+          [await feeToken.getAddress(), owner.address, otherAccount.address, 10000n]),
+      ];
+      const signed = await signCalls(otherAccount, calls, [
+        makeFee(0n),
+        makeInvalid().after(10).minutes,
+      ], { format: 2, dispatcher: await pad.getAddress() });
+      await expect(pad.dispatch(signed, '0x')).to.be.reverted;
+      expect(await feeToken.balanceOf(owner.address)).to.equal(10000n);
+    });
+
+    it("Signs a cancellation of a transaction", async function () {
+      const { pad, mockCallable, otherAccount } = await loadFixture(deploy);
+      const dispatcher = await pad.getAddress();
+      const { dataHash } = await signCallsWithHash(otherAccount,
+        [ await prepareCall(mockCallable, "callMeMaybe", [100]) ],
+        [ makeFee(0n), makeInvalid().after(10).minutes ],
+        { format: 2, dispatcher });
+      const cancel = await signCancel(otherAccount, dataHash, { dispatcher });
+      expect(cancel.signer).to.equal(otherAccount.address);
+      expect(cancel.data_hash).to.equal(dataHash);
+
+      const { chainId } = await ethers.provider.getNetwork();
+      const domain = { name: 'PayAfterDispatcher', version: '1', chainId, verifyingContract: dispatcher };
+      const types = {
+        PayAfterCancel: [
+          { name: 'signer', type: 'address' },
+          { name: 'dataHash', type: 'bytes32' },
+        ],
+      };
+      expect(ethers.verifyTypedData(domain, types,
+        { signer: otherAccount.address, dataHash }, cancel.signature)).to.equal(otherAccount.address);
+
+      let err;
+      try { await signCancel(otherAccount, '0x1234'); } catch (e) { err = e; }
+      expect(err?.message).to.contain("32 byte data hash");
+    });
+
+    it("Matches the test vectors", async function () {
+      const { pad } = await loadFixture(deploy);
+      const signer = new ethers.Wallet(VECTORS.private_key, ethers.provider);
+      for (const v of VECTORS.vectors) {
+        const fees = v.fees.map((f) => {
+          const fee = (f.amount === 'kill') ? makeInvalid() : makeFee(BigInt(f.amount));
+          return fee.after(f.after)[f.unit];
+        });
+        const signed = await signCalls(signer, v.calls, fees, {
+          mode: v.mode,
+          format: v.format,
+          dispatcher: VECTORS.dispatcher,
+          creationTime: v.creation_time,
+          minGas: v.min_gas ?? undefined,
+          requires: v.requires ?? undefined,
+          exclusive: v.exclusive ?? undefined,
+          feeToken: v.fee_token ?? undefined,
+        });
+        expect(signed.toLowerCase(), v.name).to.equal(v.bin);
+
+        const pfr = await pad.parseFee(v.bin, v.creation_time);
+        expect(pfr.creationTime, v.name).to.equal(BigInt(v.creation_time));
+        expect(pfr.expiration, v.name).to.equal(BigInt(v.expires));
+        expect(pfr.format, v.name).to.equal(BigInt(v.format));
+        expect(pfr.minGas, v.name).to.equal(BigInt(v.min_gas ?? 0));
+        expect(pfr.requires, v.name).to.equal(v.requires ?? ethers.ZeroHash);
+        expect(pfr.exclusive, v.name).to.equal(v.exclusive?.pollinator ?? ethers.ZeroAddress);
+        expect(pfr.feeToken, v.name)
+          .to.equal(v.fee_token ? ethers.getAddress(v.fee_token) : ethers.ZeroAddress);
+        expect(pfr.exclusiveUntil, v.name)
+          .to.equal(v.exclusive ? BigInt(v.creation_time + v.exclusive.seconds) : 0n);
+      }
+    });
+
+    it("Can dispatch a batch, skipping failures", async function () {
+      const { pad, mockCallable, owner, otherAccount } = await loadFixture(deploy);
+
+      const fees = [
+        makeFee(0n),
+        makeInvalid().after(10).minutes,
+      ];
+      const signed1 = await signCalls(otherAccount,
+        [await prepareCall(mockCallable, "callMeMaybe", [1])], fees);
+      const signed2 = await signCalls(otherAccount,
+        [await prepareCall(mockCallable, "callMeMaybe", [2])], fees);
+
+      // The repeat of signed1 is rejected as already executed, but the others still run
+      const tx = pad.dispatchBatch([signed1, signed1, signed2], '0x');
+      await expect(tx).to.emit(pad, "PayAfterFailed");
+      expect(await mockCallable.total()).to.equal(3);
+    });
+
     it("Unprofitable transaction", async function () {
       const profit = await payafterTest(0);
       await expect(profit).to.be.lessThan(0n);
@@ -57,5 +57,159 @@ describe("PeriodicDispatcher", function () {
       // console.log(tx);
       await tx.wait();
     });
+
+    it("Can dispatch many, skipping failures", async function () {
+      const { owner, otherAccount, pd, mockPeriodic, sneezeMine } = await loadFixture(deploy);
+
+      await ethers.provider.send("evm_increaseTime", [600]);
+      await ethers.provider.send("evm_mine", []);
+
+      // mockPeriodic has no nectar deposited so it cannot pay, sneezeMine sells tokens to pay
+      const tx = pd.dispatchMany(
+        [await mockPeriodic.getAddress(), await sneezeMine.getAddress()],
+        [0, 0],
+      );
+      await expect(tx).to.emit(pd, "PeriodicFailed");
+      await expect(tx).to.emit(pd, "PeriodicPollinated");
+      expect(await mockPeriodic.counter()).to.equal(0);
+    });
+
+    it("Tracks funders and lets the owner withdraw", async function () {
+      const { owner, otherAccount, pd, mockPeriodic } = await loadFixture(deploy);
+      const mp = await mockPeriodic.getAddress();
+
+      await expect(pd.connect(otherAccount).addNectar(mp, { value: 1000n }))
+        .to.emit(pd, "NectarAdded").withArgs(mp, await otherAccount.getAddress(), 1000n);
+      expect(await pd.depositOf(mp, await otherAccount.getAddress())).to.equal(1000n);
+      expect(await pd.fundersOf(mp)).to.deep.equal([await otherAccount.getAddress()]);
+
+      await expect(mockPeriodic.connect(otherAccount).retire(await otherAccount.getAddress(), 400n))
+        .to.be.revertedWith("Only owner");
+      await expect(mockPeriodic.retire(await otherAccount.getAddress(), 400n))
+        .to.emit(pd, "NectarWithdrawn").withArgs(mp, await otherAccount.getAddress(), 400n);
+      expect(await pd.balanceOf(mp)).to.equal(600n);
+      expect(await pd.depositOf(mp, await otherAccount.getAddress())).to.equal(600n);
+      await expect(mockPeriodic.retire(await owner.getAddress(), 601n))
+        .to.be.revertedWith("Not enough balance");
+
+      // Refunding the whole deposit drops the funder from the list
+      await mockPeriodic.retire(await otherAccount.getAddress(), 600n);
+      expect(await pd.depositOf(mp, await otherAccount.getAddress())).to.equal(0n);
+      expect(await pd.fundersOf(mp)).to.deep.equal([]);
+    });
+
+    it("Pays nectar in a token", async function () {
+      const { owner, otherAccount, pd } = await loadFixture(deploy);
+      const PayAfterDispatcher = await ethers.getContractFactory("PayAfterDispatcher");
+      const pad = await PayAfterDispatcher.deploy();
+      const MockToken = await ethers.getContractFactory("MockToken");
+      const token = await MockToken.deploy(await pad.getAddress());
+      const MockTokenPeriodic = await ethers.getContractFactory("MockTokenPeriodic");
+      const mtp = await MockTokenPeriodic.deploy(await pd.getAddress(), await token.getAddress());
+      const mtpAddr = await mtp.getAddress();
+
+      expect(await pd.nectarTokenOf(mtpAddr)).to.equal(await token.getAddress());
+      await expect(pd.addNectar(mtpAddr, { value: 1000n }))
+        .to.be.revertedWith("Nectar is paid in a token");
+
+      const deposit = ethers.parseEther('10');
+      await token.approve(await pd.getAddress(), deposit);
+      await expect(pd.addTokenNectar(mtpAddr, deposit))
+        .to.emit(pd, "NectarAdded").withArgs(mtpAddr, await owner.getAddress(), deposit);
+
+      await ethers.provider.send("evm_increaseTime", [600]);
+      await ethers.provider.send("evm_mine", []);
+
+      await pd.connect(otherAccount).dispatch(mtpAddr, 0);
+      expect(await mtp.counter()).to.equal(1);
+      const earned = await token.balanceOf(await otherAccount.getAddress());
+      expect(earned).to.be.greaterThan(0n);
+      expect(await pd.balanceOf(mtpAddr)).to.equal(deposit - earned);
+    });
+
+    it("Keeps the nectar token a contract was funded in", async function () {
+      const { owner, otherAccount, pd, mockPeriodic } = await loadFixture(deploy);
+      const PayAfterDispatcher = await ethers.getContractFactory("PayAfterDispatcher");
+      const pad = await PayAfterDispatcher.deploy();
+      const MockToken = await ethers.getContractFactory("MockToken");
+      const token = await MockToken.deploy(await pad.getAddress());
+      const MockTokenPeriodic = await ethers.getContractFactory("MockTokenPeriodic");
+      const mtp = await MockTokenPeriodic.deploy(await pd.getAddress(), await token.getAddress());
+      const mtpAddr = await mtp.getAddress();
+
+      // Another contract's base coin is held by the dispatcher
+      await pd.addNectar(await mockPeriodic.getAddress(), { value: 1000n });
+      await token.approve(await pd.getAddress(), 1000n);
+      await pd.addTokenNectar(mtpAddr, 1000n);
+
+      await mtp.setNectarToken(ethers.ZeroAddress);
+      expect(await pd.nectarTokenOf(mtpAddr)).to.equal(await token.getAddress());
+
+      const to = await otherAccount.getAddress();
+      const coinBefore = await ethers.provider.getBalance(to);
+      await mtp.retire(to, 1000n);
+      expect(await ethers.provider.getBalance(to)).to.equal(coinBefore);
+      expect(await token.balanceOf(to)).to.equal(1000n);
+      expect(await ethers.provider.getBalance(await pd.getAddress())).to.equal(1000n);
+    });
+
+    it("Does not credit nectar added from inside periodic() twice", async function () {
+      const { owner, otherAccount, pd, mockPeriodic } = await loadFixture(deploy);
+      const MockReentrantPeriodic = await ethers.getContractFactory("MockReentrantPeriodic");
+      const mrp = await MockReentrantPeriodic.deploy(await pd.getAddress(), ethers.ZeroAddress);
+      const mrpAddr = await mrp.getAddress();
+
+      // Another contract's base coin is held by the dispatcher
+      await pd.addNectar(await mockPeriodic.getAddress(), { value: 10000n });
+      await pd.addNectar(mrpAddr, { value: 1000n });
+      await owner.sendTransaction({ to: mrpAddr, value: 5000n });
+
+      await ethers.provider.send("evm_increaseTime", [600]);
+      await ethers.provider.send("evm_mine", []);
+      await expect(pd.connect(otherAccount).dispatch(mrpAddr, 0))
+        .to.be.revertedWith("Dispatch in progress");
+      await expect(pd.connect(otherAccount).dispatchMany([mrpAddr], [0]))
+        .to.emit(pd, "PeriodicFailed");
+      expect(await pd.balanceOf(mrpAddr)).to.equal(1000n);
+
+      await expect(mrp.retire(await otherAccount.getAddress(), 1001n))
+        .to.be.revertedWith("Not enough balance");
+      expect(await ethers.provider.getBalance(await pd.getAddress())).to.equal(11000n);
+    });
+
+    it("Does not credit token nectar added from inside periodic() twice", async function () {
+      const { owner, otherAccount, pd } = await loadFixture(deploy);
+      const PayAfterDispatcher = await ethers.getContractFactory("PayAfterDispatcher");
+      const pad = await PayAfterDispatcher.deploy();
+      const MockToken = await ethers.getContractFactory("MockToken");
+      const token = await MockToken.deploy(await pad.getAddress());
+      const MockTokenPeriodic = await ethers.getContractFactory("MockTokenPeriodic");
+      const mtp = await MockTokenPeriodic.deploy(await pd.getAddress(), await token.getAddress());
+      const MockReentrantPeriodic = await ethers.getContractFactory("MockReentrantPeriodic");
+      const mrp = await MockReentrantPeriodic.deploy(await pd.getAddress(), await token.getAddress());
+      const mrpAddr = await mrp.getAddress();
+
+      // Another contract's tokens are held by the dispatcher
+      await token.approve(await pd.getAddress(), 11000n);
+      await pd.addTokenNectar(await mtp.getAddress(), 10000n);
+      await pd.addTokenNectar(mrpAddr, 1000n);
+      await token.transfer(mrpAddr, 5000n);
+
+      await ethers.provider.send("evm_increaseTime", [600]);
+      await ethers.provider.send("evm_mine", []);
+      await expect(pd.connect(otherAccount).dispatch(mrpAddr, 0))
+        .to.be.revertedWith("Dispatch in progress");
+      expect(await pd.balanceOf(mrpAddr)).to.equal(1000n);
+
+      await expect(mrp.retire(await otherAccount.getAddress(), 1001n))
+        .to.be.revertedWith("Not enough balance");
+      expect(await token.balanceOf(await pd.getAddress())).to.equal(11000n);
+    });
+
+    it("Rejects mismatched dispatchMany arguments", async function () {
+      const { pd, mockPeriodic } = await loadFixture(deploy);
+      await expect(pd.dispatchMany([await mockPeriodic.getAddress()], []))
+        .to.be.revertedWith("Length mismatch");
+    });
   });
 });